@@ -1,6 +1,11 @@
 #![feature(let_chains)]
 use core::time::Duration;
-use std::{collections::HashMap, fmt::Write, thread::sleep, time::SystemTime};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    thread::sleep,
+    time::{Instant, SystemTime},
+};
 
 use embedded_sdmmc::SdMmcSpi;
 use embedded_svc::{
@@ -8,22 +13,30 @@ use embedded_svc::{
     wifi::{self, AccessPointConfiguration},
 };
 use esp_idf_hal::{
-    gpio::{AnyIOPin, PinDriver},
+    gpio::{AnyIOPin, PinDriver, Pull},
+    i2c::{I2cConfig, I2cDriver},
     peripherals::Peripherals,
     prelude::*,
     spi::{config::DriverConfig, SpiConfig, SpiDeviceDriver},
 };
 use firmware::{
     definitions::set_message_device_id,
-    utilities::{init::init, sd::SD},
+    sensors::ds3231::{self, Ds3231},
+    utilities::init::init,
 };
 use messages::Frame;
 use std::thread;
 use utilities::{
-    constants::{BROADCAST_PING_INTERVAL, SD_RETRY_INTERVAL, SSID, STACK_SIZE},
+    constants::{
+        BROADCAST_PING_INTERVAL, CAPTIVE_PORTAL_URLS, CLOCK_FALLBACK_INTERVAL, HTTPS_PORT,
+        HTTP_PORT, LIVE_STREAM_QUEUE_LEN, SD_RETRY_INTERVAL, SD_STATUS_INTERVAL, SSID, STACK_SIZE,
+        TELEMETRY_INTERVAL,
+    },
     espnow::espnow_recv_cb,
     global_state::GlobalState,
     http_server::request_handler_thread,
+    sd_card::CardDetect,
+    storage::Storage,
     tcp_client,
 };
 
@@ -31,6 +44,7 @@ use esp_idf_hal::sys::wifi_second_chan_t_WIFI_SECOND_CHAN_NONE;
 use esp_idf_svc::{
     espnow::{EspNow, BROADCAST},
     http::server::EspHttpServer,
+    tls::X509,
 };
 
 use esp_idf_svc::eventloop::*;
@@ -52,6 +66,15 @@ fn main() {
     let nvs_default_partition = EspDefaultNvsPartition::take().unwrap();
     GlobalState::init(nvs_default_partition.clone());
     let gs = GlobalState::get();
+    utilities::clock::init();
+
+    // Factory reset button (BOOT button)
+    let mut reset_button = PinDriver::input(peripherals.pins.gpio0.downgrade_input()).unwrap();
+    reset_button.set_pull(Pull::Up).unwrap();
+    let _ = thread::Builder::new()
+        .stack_size(4 * 1024)
+        .name("Factory reset button".to_string())
+        .spawn(move || utilities::factory_reset::button_task(reset_button));
 
     // Init status LEDs
     // Wi-Fi status LED
@@ -61,6 +84,26 @@ fn main() {
     let mut green_led2 = PinDriver::output(peripherals.pins.gpio17).unwrap();
     let mut green_led3 = PinDriver::output(peripherals.pins.gpio18).unwrap();
 
+    // Real-time clock (DS3231), keeping the time while the hub is off
+    let i2c_config = I2cConfig::new().baudrate(100.kHz().into());
+    let i2c = I2cDriver::new(
+        peripherals.i2c0,
+        peripherals.pins.gpio1,
+        peripherals.pins.gpio2,
+        &i2c_config,
+    )
+    .unwrap();
+    let mut rtc = match Ds3231::new(i2c, ds3231::DEFAULT_ADDRESS) {
+        Ok(mut rtc) => {
+            utilities::clock::set_from_rtc(&mut rtc);
+            Some(rtc)
+        }
+        Err(e) => {
+            info!("No RTC found: {:?}", e);
+            None
+        }
+    };
+
     // ----------- //
     // WIFI config //
     // ----------- //
@@ -82,16 +125,37 @@ fn main() {
         info!("Creating AP config");
         info!("To configure the device connect to the Wi-Fi network: {:?}. Go to the IP address below and submit the form", SSID);
 
-        // Access Point configuration
-        wifi::Configuration::AccessPoint(AccessPointConfiguration {
-            ssid: SSID.try_into().unwrap(),
-            ssid_hidden: false,
-            channel: 0,
-            ..Default::default()
-        })
+        // Access Point configuration, the station is enabled (but not
+        // configured) to scan for the nearby networks
+        wifi::Configuration::Mixed(
+            ClientConfiguration::default(),
+            AccessPointConfiguration {
+                ssid: SSID.try_into().unwrap(),
+                ssid_hidden: false,
+                channel: 0,
+                ..Default::default()
+            },
+        )
     } else {
         let config = wifi.get_configuration().unwrap();
-        info!("Found Wi-Fi configuration: {:?}", config);
+        // The credentials are not logged
+        if let Configuration::Mixed(client, _) | Configuration::Client(client) = &config {
+            info!(
+                "Found Wi-Fi configuration, SSID: {}, Auth method: {:?}",
+                client.ssid, client.auth_method
+            );
+        }
+
+        // The WPA2-Enterprise credentials are not kept by the Wi-Fi driver
+        if let Configuration::Mixed(client, _) | Configuration::Client(client) = &config
+            && client.auth_method == AuthMethod::WPA2Enterprise
+        {
+            let enabled = utilities::wifi::load_eap_credentials()
+                .and_then(|eap| utilities::wifi::enable_enterprise(&eap));
+            if let Err(e) = enabled {
+                error!("Failed to restore the WPA2-Enterprise credentials: {:?}", e);
+            }
+        }
 
         config
     };
@@ -101,6 +165,8 @@ fn main() {
 
     // Start WiFi
     wifi.start().unwrap();
+    let ap_ip = wifi.wifi().ap_netif().get_ip_info().unwrap().ip;
+    gs.ap_ip.lock().unwrap().replace(ap_ip);
 
     // Thread to handle Wi-Fi connection and reconnection
     let _ = thread::Builder::new()
@@ -110,15 +176,48 @@ fn main() {
     // Store the wifi in the global state
     gs.wifi.lock().unwrap().replace(wifi);
 
+    // -------------- //
+    // Captive portal //
+    // -------------- //
+    // Until the hub is configured, every DNS query is answered with the
+    // address of the access point
+    if !utilities::wifi::is_provisioned() {
+        utilities::captive_portal::start(ap_ip);
+    }
+
     // ---------------- //
     // Form page SERVER //
     // ---------------- //
     // HTTP server configuration
-    let server_configuration = esp_idf_svc::http::server::Configuration {
+    let mut server_configuration = esp_idf_svc::http::server::Configuration {
+        http_port: HTTP_PORT,
         stack_size: STACK_SIZE,
         ..Default::default()
     };
 
+    // HTTPS, only once configured: the provisioning over the access point
+    // stays in plain HTTP, so that the captive portal works
+    if utilities::wifi::is_provisioned() && utilities::tls::is_enabled() {
+        match utilities::tls::load_or_generate() {
+            Ok(certificate) => {
+                match certificate.fingerprint() {
+                    Ok(fingerprint) => {
+                        info!("HTTPS certificate SHA-256 fingerprint: {}", fingerprint)
+                    }
+                    Err(e) => warn!("Failed to compute the certificate fingerprint: {:?}", e),
+                }
+                // The server keeps the certificate for the whole program
+                let server_certificate = Box::leak(certificate.certificate.into_boxed_c_str());
+                let private_key = Box::leak(certificate.private_key.into_boxed_c_str());
+                server_configuration.https_port = HTTPS_PORT;
+                server_configuration.server_certificate = Some(X509::pem(server_certificate));
+                server_configuration.private_key = Some(X509::pem(private_key));
+                *gs.https_enabled.lock().unwrap() = true;
+            }
+            Err(e) => error!("Failed to load the HTTPS certificate, using HTTP: {:?}", e),
+        }
+    }
+
     let mut server = EspHttpServer::new(&server_configuration).unwrap();
     info!("Server created");
 
@@ -141,6 +240,73 @@ fn main() {
         })
         .unwrap();
 
+    // Provisioning API
+    server
+        .fn_handler(
+            "/api/wifi/scan",
+            Method::Get,
+            utilities::http_server::wifi_scan_handler,
+        )
+        .unwrap();
+    server
+        .fn_handler(
+            "/api/provision/status",
+            Method::Get,
+            utilities::http_server::provision_status_handler,
+        )
+        .unwrap();
+
+    server
+        .fn_handler(
+            "/api/uplink/status",
+            Method::Get,
+            utilities::http_server::uplink_status_handler,
+        )
+        .unwrap();
+    server
+        .fn_handler(
+            "/api/clock/status",
+            Method::Get,
+            utilities::http_server::clock_status_handler,
+        )
+        .unwrap();
+
+    // Prometheus scrape endpoint
+    server
+        .fn_handler("/metrics", Method::Get, utilities::metrics::metrics_handler)
+        .unwrap();
+
+    // WebSocket live stream of the frames
+    server
+        .ws_handler("/api/live", utilities::live_stream::ws_handler)
+        .unwrap();
+
+    // Configuration backup
+    server
+        .fn_handler(
+            "/api/config/export",
+            Method::Post,
+            utilities::backup::export_handler,
+        )
+        .unwrap();
+    let import_config_sender = connection_config_sender.clone();
+    server
+        .fn_handler::<anyhow::Error, _>("/api/config/import", Method::Post, move |req| {
+            utilities::backup::import_handler(req, &import_config_sender)
+        })
+        .unwrap();
+
+    // Captive portal redirects
+    let portal_url = format!("http://{}/", ap_ip);
+    for url in CAPTIVE_PORTAL_URLS {
+        let portal_url = portal_url.clone();
+        server
+            .fn_handler::<anyhow::Error, _>(url, Method::Get, move |req| {
+                utilities::captive_portal::redirect_handler(req, &portal_url)
+            })
+            .unwrap();
+    }
+
     // ----------------- //
     // TCP client config //
     // ----------------- //
@@ -179,7 +345,29 @@ fn main() {
     // Build an SDHandle Card interface out of an SPI device
     let mut spi_device = SdMmcSpi::new(spi, sdmmc_cs);
 
-    let mut sd = SD::new(&mut spi_device).ok();
+    // Storage of the frames that could not be sent, the SD card or the
+    // internal flash
+    let storage = Storage::load();
+    let (mut store, mut sd_mounted) = match utilities::storage::open(storage, &mut spi_device) {
+        Some((store, opened)) => (Some(store), opened == Storage::SdCard),
+        None => (None, false),
+    };
+    // The SD card is mounted and unmounted when it is inserted and removed
+    let sd_detect_pin = utilities::sd_card::has_card_detect().then(|| {
+        let mut pin = PinDriver::input(peripherals.pins.gpio8.downgrade_input()).unwrap();
+        pin.set_pull(Pull::Up).unwrap();
+        pin
+    });
+    let mut card_detect = CardDetect::new(sd_detect_pin);
+
+    // Frames kept in memory while neither the server nor the storage are
+    // available
+    let mut frames_queue = utilities::frame_queue::new();
+    // Number of dropped frames last reported to the server
+    let mut reported_drops = 0;
+    if storage != Storage::Flash {
+        frames_queue.push(utilities::sd_card::status_frame(sd_mounted));
+    }
 
     // -------------- //
     // ESP-NOW config //
@@ -203,9 +391,9 @@ fn main() {
         .unwrap()
         .get_configuration()
         .unwrap()
+        && let Some(channel) = client.channel
     {
         // Set the channel
-        let channel = client.channel.expect("Channel not set");
         let second = wifi_second_chan_t_WIFI_SECOND_CHAN_NONE;
         unsafe {
             esp_idf_hal::sys::esp_wifi_set_channel(channel, second);
@@ -221,6 +409,9 @@ fn main() {
             ..Default::default()
         };
         espnow.add_peer(broadcast).unwrap();
+
+        // Remember the channel, to announce it to the slaves
+        gs.espnow_channel.lock().unwrap().replace(channel);
     }
 
     // Add espnow to the global state
@@ -238,15 +429,32 @@ fn main() {
         .name("Configuration changes handler".to_string())
         .spawn(move || request_handler_thread(connection_config_receiver));
 
+    // Thread sending the frames to the clients of the live stream, so that
+    // the slow clients do not hold the main loop
+    let (live_sender, live_receiver) = std::sync::mpsc::sync_channel(LIVE_STREAM_QUEUE_LEN);
+    let _ = thread::Builder::new()
+        .stack_size(8 * 1024)
+        .name("Live stream".to_string())
+        .spawn(move || utilities::live_stream::sender_task(live_receiver));
+
     // --------- //
     // MAIN LOOP //
     // --------- //
     let mut last_broadcast_ts: Option<SystemTime> = None;
     let mut last_sd_retry: Option<SystemTime> = None;
+    let mut last_sd_report: Option<SystemTime> = None;
+    let mut last_telemetry: Option<Instant> = None;
+    // SNTP is given some time before asking the time to the server
+    let mut last_clock_fallback = Instant::now();
     loop {
         // Sleep for a FreeRTOS tick, this allow the scheduler to run another task
         sleep(Duration::from_millis(10));
 
+        // Keep the RTC in sync with SNTP
+        if let Some(rtc) = rtc.as_mut() {
+            utilities::clock::update_rtc(rtc);
+        }
+
         // Brodcast ping message for slaves
         if last_broadcast_ts.is_none()
             || last_broadcast_ts
@@ -257,7 +465,8 @@ fn main() {
         {
             if let Some(esp_now) = gs.esp_now.lock().unwrap().as_mut() {
                 info!("Broadcasting ping message");
-                let message = firmware::PingMessage::new();
+                let channel = gs.espnow_channel.lock().unwrap().unwrap_or(0);
+                let message = firmware::PingMessage::new().with_channel(channel);
                 let frame: Frame = message.into();
                 if let Err(e) = esp_now.send(BROADCAST, &frame.serialize()) {
                     warn!("Failed to send broadcast ping message: {:?}", e);
@@ -269,6 +478,7 @@ fn main() {
         // Receive data from the ESP-NOW
         let mut frames_hash = HashMap::new();
         while let Ok((mac_addr, raw_frames)) = rx.try_recv() {
+            utilities::espnow::mark_taken();
             let vec = hash.entry(mac_addr.clone()).or_insert_with(Vec::new);
 
             vec.extend_from_slice(raw_frames.as_slice());
@@ -332,94 +542,169 @@ fn main() {
                 .collect();
         }
 
-        // Frames from slaves are timestamped with the time of the reception
+        // Frames from slaves are timestamped with the time of the reception,
+        // relative to the boot until the clock is set
         let mut frames_with_id = frames_with_id
             .iter()
-            .map(|&x| {
-                x.set_timestamp(
-                    SystemTime::now()
-                        .duration_since(SystemTime::UNIX_EPOCH)
-                        .unwrap()
-                        .as_millis() as u64,
-                )
-            })
+            .map(|&x| x.set_timestamp(utilities::clock::now()))
             .collect::<Vec<_>>();
+        utilities::metrics::record(&frames_with_id);
+        utilities::live_stream::publish(&frames_with_id, &live_sender);
+
+        // SD card hot-plug, the internal flash is never removed
+        if storage != Storage::Flash && (sd_mounted || store.is_none()) {
+            let mounted = if sd_mounted {
+                store.as_deref_mut()
+            } else {
+                None
+            };
+            match card_detect.is_inserted(mounted) {
+                Some(false) if sd_mounted => {
+                    warn!("SD card removed");
+                    // Keep in memory the frames that could not be written
+                    for frame in store.take().unwrap().unmount() {
+                        frames_queue.push(frame);
+                    }
+                    sd_mounted = false;
+                    frames_with_id.push(utilities::sd_card::status_frame(false));
+                }
+                Some(true)
+                    if !sd_mounted
+                        && (last_sd_retry.is_none()
+                            || last_sd_retry.unwrap().elapsed().unwrap() > SD_RETRY_INTERVAL) =>
+                {
+                    warn!("SD card not mounted. Trying to mount it...");
+                    drop(store);
+                    store = utilities::storage::open_sd(&mut spi_device);
+                    last_sd_retry = Some(SystemTime::now());
+                    if store.is_some() {
+                        info!("SD card mounted");
+                        sd_mounted = true;
+                        last_sd_report = None;
+                        frames_with_id.push(utilities::sd_card::status_frame(true));
+                    }
+                }
+                _ => {}
+            }
+        }
 
-        let mut sent = false;
         if utilities::wifi::is_connected() {
             // If connected to the Wi-Fi, turn on the Wi-Fi status LED
             blue_led.set_high().unwrap();
 
             // If there is a connection to the Wi-Fi, send the data to the server
-
-            // Extend the frames with the data from the SD card (if any)
-            if let Some(mut sd_inner) = sd {
-                let sd_frames = sd_inner.read();
-                if sd_frames.is_err() {
-                    warn!("Failed to read from the SD card");
+            let mut is_uplink_open = gs.uplink.lock().unwrap().is_some();
+            if !is_uplink_open {
+                // The connection was not opened yet or was lost
+                tcp_client::try_connect();
+                is_uplink_open = gs.uplink.lock().unwrap().is_some();
+            }
+            // Without SNTP, ask the time to the server
+            if is_uplink_open
+                && !utilities::clock::is_set()
+                && last_clock_fallback.elapsed() > CLOCK_FALLBACK_INTERVAL
+            {
+                tcp_client::sync_clock();
+                last_clock_fallback = Instant::now();
+            }
+            // The frames are held until the clock is set, to be sent with
+            // the wall-clock time
+            let can_send = is_uplink_open && utilities::clock::is_set();
+
+            // Send the data stored in the SD card or the flash (if any), only
+            // when it can be sent. It is removed from the storage once
+            // delivered.
+            if can_send && let Some(store_inner) = store.as_mut() {
+                match store_inner.read() {
+                    Ok(stored_frames) => {
+                        if stored_frames.is_empty() || tcp_client::send(stored_frames).is_empty() {
+                            if let Err(e) = store_inner.commit() {
+                                warn!("Failed to remove the sent data from the storage: {:?}", e);
+                            }
+                        } else {
+                            // Read again at the next attempt
+                            store_inner.rollback();
+                        }
+                    }
+                    Err(e) => warn!("Failed to read from the storage: {:?}", e),
                 }
-                frames_with_id.extend(sd_frames.unwrap_or_default());
+            }
+
+            // Send the frames kept in memory first, they are older
+            if can_send && !frames_queue.is_empty() {
+                let mut frames = frames_queue.drain();
+                frames.append(&mut frames_with_id);
+                frames_with_id = frames;
+            }
 
-                sd = Some(sd_inner);
-            } else if last_sd_retry.is_none()
-                || last_sd_retry.unwrap().elapsed().unwrap() > SD_RETRY_INTERVAL
+            // Send the data to the server, the frames that could not be sent
+            // are stored in the SD card or the flash
+            frames_with_id = tcp_client::send(frames_with_id);
+
+            // Report the frames dropped by the queue
+            if frames_queue.dropped() != reported_drops
+                && tcp_client::send_point(utilities::frame_queue::to_point(&frames_queue))
             {
-                warn!("SD card not initialized. Trying to recover...");
-                // Try to recover the SD card
-                drop(sd);
-                sd = SD::new(&mut spi_device).ok();
-                last_sd_retry = Some(SystemTime::now());
+                reported_drops = frames_queue.dropped();
             }
 
-            // Send the data to the server
-            let mut tcp_stream = gs.tcp_stream.lock().unwrap();
-            if let Some(stream) = tcp_stream.as_mut() {
-                if !frames_with_id.is_empty() {
-                    info!("Sending {:#?} to the TCP server", frames_with_id.len());
-                }
-                for frame in frames_with_id.clone() {
-                    //info!("Sending data to the TCP server: {:?}", frame);
-                    if let Ok(influx_lp) = frame.to_point() {
-                        if let Err(e) = stream.write_point(&influx_lp) {
-                            warn!("Failed to send data to the TCP server: {:?}", e);
-                        }
-                    } else {
-                        warn!(
-                            "Failed to convert the frame {:?} to InfluxDB line protocol",
-                            frame
-                        );
+            // Report the usage of the SD card
+            if is_uplink_open
+                && sd_mounted
+                && last_sd_report.map_or(true, |last_sd_report| {
+                    last_sd_report.elapsed().unwrap_or_default() > SD_STATUS_INTERVAL
+                })
+                && let Some(store_inner) = store.as_mut()
+            {
+                last_sd_report = Some(SystemTime::now());
+                match store_inner.usage() {
+                    Ok(usage) => {
+                        tcp_client::send_point(utilities::sd_card::usage_point(usage));
                     }
+                    Err(e) => warn!("Failed to read the usage of the SD card: {:?}", e),
                 }
-                sent = true;
-            } else {
-                drop(tcp_stream);
+            }
 
-                // TCP was not initialized yet
-                tcp_client::connect();
+            // Report the health of the hub
+            if is_uplink_open
+                && last_telemetry.map_or(true, |last_telemetry| {
+                    last_telemetry.elapsed() > TELEMETRY_INTERVAL
+                })
+            {
+                last_telemetry = Some(Instant::now());
+                let backlog = store
+                    .as_mut()
+                    .and_then(|store_inner| store_inner.usage().ok())
+                    .map_or(0, |usage| usage.backlog);
+                tcp_client::send_point(utilities::telemetry::health_point(backlog));
             }
         } else {
             // If not connected to the Wi-Fi, turn off the Wi-Fi status LED
             blue_led.set_low().unwrap();
         }
 
-        if !sent && let Some(mut sd_inner) = sd {
-            // There is no connection, store data in the SD card
-            for frame in frames_with_id {
-                if let Err(err) = sd_inner.write(&frame) {
-                    error!("Failed to write to the SD card: {:?}", err);
+        if (!frames_with_id.is_empty() || !frames_queue.is_empty())
+            && let Some(store_inner) = store.as_mut()
+        {
+            // The data could not be sent, store it, with the frames kept in
+            // memory while the storage was missing
+            let mut frames = frames_queue.drain();
+            frames.append(&mut frames_with_id);
+            for frame in frames {
+                if let Err(err) = store_inner.write(&frame) {
+                    error!("Failed to write to the storage: {:?}", err);
                 }
             }
+        }
 
-            sd = Some(sd_inner);
-        } else if sd.is_none()
-            && (last_sd_retry.is_none()
-                || last_sd_retry.unwrap().elapsed().unwrap() > SD_RETRY_INTERVAL)
+        // Without the server and the storage, keep the frames in memory
+        for frame in frames_with_id {
+            frames_queue.push(frame);
+        }
         {
-            warn!("SD card not initialized. Trying to recover...");
-            // Try to recover the SD card
-            drop(sd);
-            sd = SD::new(&mut spi_device).ok();
-            last_sd_retry = Some(SystemTime::now());
+            let mut stats = gs.uplink_stats.lock().unwrap();
+            stats.buffered = frames_queue.len() as u64;
+            stats.dropped = frames_queue.dropped();
         }
     }
 }
@@ -0,0 +1,362 @@
+use core::time::Duration;
+use std::mem::size_of;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Instant;
+
+use anyhow::Error;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use embedded_svc::http::Headers;
+use esp_idf_hal::io::Write;
+use esp_idf_svc::http::server::{EspHttpConnection, Request};
+use esp_idf_svc::sys::{
+    esp_fill_random, httpd_req_t, httpd_req_to_sockfd, lwip_getpeername, lwip_getsockname,
+    sockaddr, sockaddr_in, sockaddr_in6, sockaddr_storage, socklen_t, AF_INET, AF_INET6,
+};
+use log::{info, warn};
+use sha2::Sha256;
+
+use super::{
+    constants::{
+        ADMIN_CLAIM_TIME, ADMIN_PASSWORD_MIN_LEN, ADMIN_USERNAME, LOGIN_LOCKOUT_MAX,
+        LOGIN_LOCKOUT_TIME, MAX_FAILED_LOGINS, MAX_LOGIN_CLIENTS, PASSWORD_HASH_ROUNDS,
+    },
+    global_state::GlobalState,
+};
+
+/// NVS keys of the admin password
+const ADMIN_SALT_KEY: &str = "Admin salt";
+const ADMIN_HASH_KEY: &str = "Admin hash";
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+
+/// Reason why a request was not authorized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// Missing or wrong credentials
+    Unauthorized,
+    /// Too many failed logins, retry after the given time
+    TooManyAttempts(Duration),
+}
+
+/// Limiter of the failed logins of a client.
+/// After [`MAX_FAILED_LOGINS`] failures the logins are refused for a time that
+/// doubles at every further failure.
+#[derive(Debug)]
+struct LoginLimiter {
+    client: IpAddr,
+    failed_logins: u32,
+    locked_until: Option<Instant>,
+    last_login: Instant,
+}
+
+impl LoginLimiter {
+    /// Time to wait before the next login is allowed, if any.
+    fn retry_after(&self) -> Option<Duration> {
+        self.locked_until
+            .and_then(|locked_until| locked_until.checked_duration_since(Instant::now()))
+    }
+
+    fn is_locked(&self) -> bool {
+        self.retry_after().is_some()
+    }
+
+    fn on_success(&mut self) {
+        self.failed_logins = 0;
+        self.locked_until = None;
+    }
+
+    fn on_failure(&mut self) {
+        self.failed_logins = self.failed_logins.saturating_add(1);
+        if self.failed_logins >= MAX_FAILED_LOGINS {
+            let exponent = (self.failed_logins - MAX_FAILED_LOGINS).min(16);
+            let lockout = LOGIN_LOCKOUT_TIME
+                .saturating_mul(1 << exponent)
+                .min(LOGIN_LOCKOUT_MAX);
+            self.locked_until = Some(Instant::now() + lockout);
+        }
+    }
+}
+
+/// Limiters of the failed logins of the last [`MAX_LOGIN_CLIENTS`] clients,
+/// so that a client failing to log in does not lock out the others.
+#[derive(Debug, Default)]
+pub struct LoginLimiters {
+    limiters: Vec<LoginLimiter>,
+}
+
+impl LoginLimiters {
+    /// Time the client must wait before the next login is allowed, if any.
+    fn retry_after(&self, client: IpAddr) -> Option<Duration> {
+        self.limiters
+            .iter()
+            .find(|limiter| limiter.client == client)
+            .and_then(LoginLimiter::retry_after)
+    }
+
+    /// Limiter of the client, replacing the one of the least recent client
+    /// when they are all in use. The locked out clients are replaced last.
+    fn limiter(&mut self, client: IpAddr) -> &mut LoginLimiter {
+        let index = match self
+            .limiters
+            .iter()
+            .position(|limiter| limiter.client == client)
+        {
+            Some(index) => index,
+            None => {
+                if self.limiters.len() >= MAX_LOGIN_CLIENTS {
+                    let oldest = self
+                        .limiters
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, limiter)| (limiter.is_locked(), limiter.last_login))
+                        .map(|(index, _)| index)
+                        .unwrap();
+                    self.limiters.swap_remove(oldest);
+                }
+                self.limiters.push(LoginLimiter {
+                    client,
+                    failed_logins: 0,
+                    locked_until: None,
+                    last_login: Instant::now(),
+                });
+                self.limiters.len() - 1
+            }
+        };
+        let limiter = &mut self.limiters[index];
+        limiter.last_login = Instant::now();
+        limiter
+    }
+}
+
+/// Check if the admin password has been set.
+pub fn is_admin_set() -> bool {
+    let gs = GlobalState::get();
+    let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+    matches!(nvs.contains(ADMIN_HASH_KEY), Ok(true))
+}
+
+/// Check if the request may set the admin password of a hub that has none.
+/// A hub upgraded from a firmware without admin password is already on the
+/// network: the account can only be claimed from its access point, or from
+/// the station interface shortly after a press of its button.
+pub fn may_claim_admin(req: &mut Request<&mut EspHttpConnection>) -> bool {
+    let gs = GlobalState::get();
+    let claim_allowed = gs
+        .admin_claim_until
+        .lock()
+        .unwrap()
+        .is_some_and(|until| Instant::now() < until);
+    if claim_allowed {
+        return true;
+    }
+
+    let Ok(raw_connection) = req.connection().raw_connection() else {
+        return false;
+    };
+    let local = local_address(raw_connection.handle());
+    let ap_ip = *gs.ap_ip.lock().unwrap();
+    ap_ip.is_some_and(|ap_ip| local == IpAddr::V4(ap_ip))
+}
+
+/// Allow claiming the admin account from the station interface for
+/// [`ADMIN_CLAIM_TIME`].
+pub fn allow_admin_claim() {
+    if is_admin_set() {
+        return;
+    }
+    info!("The admin account can be claimed from the network for a while");
+    let gs = GlobalState::get();
+    gs.admin_claim_until
+        .lock()
+        .unwrap()
+        .replace(Instant::now() + ADMIN_CLAIM_TIME);
+}
+
+/// Check that the admin password is long enough.
+pub fn validate_admin_password(password: &str) -> Result<(), &'static str> {
+    if password.chars().count() < ADMIN_PASSWORD_MIN_LEN {
+        return Err("The admin password must be at least 8 characters long");
+    }
+    Ok(())
+}
+
+/// Hash the admin password with a new salt and store it in the NVS.
+pub fn set_admin_password(password: &str) -> Result<(), Error> {
+    let mut salt = [0u8; SALT_LEN];
+    unsafe { esp_fill_random(salt.as_mut_ptr().cast(), salt.len()) };
+    let hash = hash_password(password, &salt);
+
+    let gs = GlobalState::get();
+    let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+    nvs.set_str(ADMIN_SALT_KEY, &to_hex(&salt))?;
+    nvs.set_str(ADMIN_HASH_KEY, &to_hex(&hash))?;
+    Ok(())
+}
+
+/// Check the HTTP basic authentication credentials of the request against
+/// the admin password.
+pub fn authorize(req: &mut Request<&mut EspHttpConnection>) -> Result<(), AuthError> {
+    let client = match req.connection().raw_connection() {
+        Ok(raw_connection) => client_address(raw_connection.handle()),
+        Err(_) => Ipv4Addr::UNSPECIFIED.into(),
+    };
+    authorize_header(req.header("Authorization"), client)
+}
+
+/// Check the value of the `Authorization` header sent by `client` against the
+/// admin password, for the requests that are not a [`Request`] (the WebSocket
+/// handshakes).
+pub fn authorize_header(authorization: Option<&str>, client: IpAddr) -> Result<(), AuthError> {
+    let gs = GlobalState::get();
+    if let Some(retry_after) = gs.login_limiters.lock().unwrap().retry_after(client) {
+        return Err(AuthError::TooManyAttempts(retry_after));
+    }
+
+    // Requests without credentials are not failed logins, the browsers send
+    // them before asking for the password
+    let Some(authorization) = authorization else {
+        return Err(AuthError::Unauthorized);
+    };
+
+    let valid = check_credentials(authorization);
+    let mut limiters = gs.login_limiters.lock().unwrap();
+    let limiter = limiters.limiter(client);
+    if valid {
+        limiter.on_success();
+        Ok(())
+    } else {
+        warn!("Failed admin login from {}", client);
+        limiter.on_failure();
+        Err(AuthError::Unauthorized)
+    }
+}
+
+/// Address of the client that sent the request, unspecified if unknown.
+pub fn client_address(raw_req: *mut httpd_req_t) -> IpAddr {
+    socket_address(raw_req, lwip_getpeername)
+}
+
+/// Address of the hub the request was sent to, unspecified if unknown.
+fn local_address(raw_req: *mut httpd_req_t) -> IpAddr {
+    socket_address(raw_req, lwip_getsockname)
+}
+
+/// Address of an end of the socket of the request, given by `get_name`.
+fn socket_address(
+    raw_req: *mut httpd_req_t,
+    get_name: unsafe extern "C" fn(i32, *mut sockaddr, *mut socklen_t) -> i32,
+) -> IpAddr {
+    let fd = unsafe { httpd_req_to_sockfd(raw_req) };
+    let mut address: sockaddr_storage = unsafe { core::mem::zeroed() };
+    let mut len = size_of::<sockaddr_storage>() as socklen_t;
+    if fd < 0 || unsafe { get_name(fd, &mut address as *mut _ as *mut sockaddr, &mut len) } != 0 {
+        return Ipv4Addr::UNSPECIFIED.into();
+    }
+    match address.ss_family as u32 {
+        AF_INET => {
+            let address = unsafe { &*(&address as *const _ as *const sockaddr_in) };
+            Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr)).into()
+        }
+        AF_INET6 => {
+            let address = unsafe { &*(&address as *const _ as *const sockaddr_in6) };
+            let address = Ipv6Addr::from(unsafe { address.sin6_addr.un.u8_addr });
+            // The server listens on IPv6, the IPv4 clients are mapped
+            address
+                .to_ipv4_mapped()
+                .map_or(IpAddr::V6(address), IpAddr::V4)
+        }
+        _ => Ipv4Addr::UNSPECIFIED.into(),
+    }
+}
+
+/// Write the response of a request that was not authorized.
+pub fn write_auth_error(
+    req: Request<&mut EspHttpConnection>,
+    error: AuthError,
+) -> Result<(), Error> {
+    match error {
+        AuthError::Unauthorized => {
+            req.into_response(
+                401,
+                None,
+                &[
+                    ("Content-Type", "application/json"),
+                    ("WWW-Authenticate", "Basic realm=\"Smart Home Hub\""),
+                ],
+            )?
+            .write_all(br#"{"error":"Unauthorized"}"#)?;
+        }
+        AuthError::TooManyAttempts(retry_after) => {
+            let retry_after = (retry_after.as_secs() + 1).to_string();
+            req.into_response(
+                429,
+                None,
+                &[
+                    ("Content-Type", "application/json"),
+                    ("Retry-After", &retry_after),
+                ],
+            )?
+            .write_all(br#"{"error":"Too many failed logins, retry later"}"#)?;
+        }
+    }
+    Ok(())
+}
+
+/// Check the value of the `Authorization` header.
+fn check_credentials(authorization: &str) -> bool {
+    let Some(encoded) = authorization.strip_prefix("Basic ") else {
+        return false;
+    };
+    let Ok(decoded) = BASE64.decode(encoded.trim()) else {
+        return false;
+    };
+    let Ok(decoded) = core::str::from_utf8(&decoded) else {
+        return false;
+    };
+    let Some((username, password)) = decoded.split_once(':') else {
+        return false;
+    };
+
+    let gs = GlobalState::get();
+    let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+    let mut salt_buffer = [0u8; 2 * SALT_LEN + 1];
+    let mut hash_buffer = [0u8; 2 * HASH_LEN + 1];
+    let (Ok(Some(salt)), Ok(Some(stored_hash))) = (
+        nvs.get_str(ADMIN_SALT_KEY, &mut salt_buffer),
+        nvs.get_str(ADMIN_HASH_KEY, &mut hash_buffer),
+    ) else {
+        return false;
+    };
+    let (Some(salt), Some(stored_hash)) = (from_hex(salt), from_hex(stored_hash)) else {
+        return false;
+    };
+
+    let hash = hash_password(password, &salt);
+    // Constant time comparison
+    let difference = hash
+        .iter()
+        .zip(stored_hash.iter())
+        .fold(0, |difference, (a, b)| difference | (a ^ b));
+    username == ADMIN_USERNAME && stored_hash.len() == HASH_LEN && difference == 0
+}
+
+/// Hash the password with PBKDF2-HMAC-SHA256.
+fn hash_password(password: &str, salt: &[u8]) -> [u8; HASH_LEN] {
+    let mut hash = [0u8; HASH_LEN];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, PASSWORD_HASH_ROUNDS, &mut hash);
+    hash
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
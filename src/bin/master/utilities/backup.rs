@@ -0,0 +1,441 @@
+use core::ffi::CStr;
+use std::sync::mpsc::SyncSender;
+
+use anyhow::{bail, Context, Error};
+use embedded_svc::http::Headers;
+use esp_idf_hal::io::{Read, Write};
+use esp_idf_svc::http::server::{EspHttpConnection, Request};
+use esp_idf_svc::sys::{
+    esp_fill_random, nvs_entry_find, nvs_entry_info, nvs_entry_info_t, nvs_entry_next,
+    nvs_iterator_t, nvs_release_iterator, nvs_type_t_NVS_TYPE_U8, ESP_OK,
+};
+use esp_idf_svc::wifi::Configuration;
+use hmac::{Hmac, Mac};
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use sha2::Sha256;
+
+use super::{
+    auth,
+    clock::TimeSettings,
+    constants::{BACKUP_PASSPHRASE_MIN_LEN, NVS_NAMESPACE, NVS_PARTITION, PASSWORD_HASH_ROUNDS},
+    global_state::GlobalState,
+    http_server::{self, ConnectionConfig, FormData},
+    netif::NetifSettings,
+    uplink::UplinkSettings,
+};
+
+/// Max length of an imported backup
+const MAX_BACKUP_LEN: usize = 8 * 1024;
+/// Max length of an export request
+const MAX_EXPORT_REQUEST_LEN: usize = 256;
+/// Version of the backup format, bumped at every change of the fields of the
+/// configuration
+const BACKUP_VERSION: u32 = 2;
+/// Oldest version that can be imported, the fields missing from the older
+/// versions take their default values. The backups of version 1 were signed
+/// with a key built into the firmware.
+const MIN_BACKUP_VERSION: u32 = 2;
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+/// Length of the NVS keys of the slaves (MAC address in hexadecimal)
+const SLAVE_KEY_LEN: usize = 12;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Slave known by the hub.
+#[derive(Serialize, Deserialize)]
+struct Slave {
+    /// MAC address in hexadecimal
+    mac: String,
+    id: u8,
+}
+
+/// Configuration of the hub.
+#[derive(Serialize, Deserialize)]
+struct HubConfig {
+    connection: FormData,
+    slaves: Vec<Slave>,
+}
+
+/// Signed backup of the configuration, without the passwords and the token.
+///
+/// The signature is an HMAC-SHA256 of the exact text of the configuration,
+/// keyed with PBKDF2 of a passphrase chosen at the export and given again at
+/// the import. It detects a backup modified since its export by someone who
+/// does not know the passphrase, e.g. to send the data to another server.
+/// It does not hide the configuration: the backups are not encrypted, so the
+/// secrets are left out of them.
+#[derive(Serialize, Deserialize)]
+struct Backup {
+    version: u32,
+    /// Salt of the key derived from the passphrase, in hexadecimal
+    salt: String,
+    /// [`HubConfig`], kept as received so that the signed text does not
+    /// depend on the fields known by the firmware
+    config: Box<RawValue>,
+    /// HMAC-SHA256 of the configuration, in hexadecimal
+    signature: String,
+}
+
+/// Export of the configuration.
+#[derive(Deserialize)]
+struct Export {
+    /// Passphrase signing the backup
+    passphrase: String,
+}
+
+/// Secrets left out of the backups, given again with the import. The ones
+/// left empty are kept from the current configuration when it is for the
+/// same network and server.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Secrets {
+    wifi_pass: String,
+    eap_password: String,
+    influx_token: String,
+}
+
+/// Import of a backup.
+#[derive(Deserialize)]
+struct Import {
+    backup: Backup,
+    /// Passphrase given at the export
+    passphrase: String,
+    #[serde(default)]
+    secrets: Secrets,
+    /// New admin password, required when the hub has none yet
+    #[serde(default)]
+    admin_password: String,
+}
+
+/// Handle the POST request for the export of the configuration, signed with
+/// the passphrase of the request.
+pub fn export_handler(mut req: Request<&mut EspHttpConnection>) -> Result<(), Error> {
+    if let Err(e) = auth::authorize(&mut req) {
+        return auth::write_auth_error(req, e);
+    }
+
+    let len = req.content_len().unwrap_or(0) as usize;
+    if len > MAX_EXPORT_REQUEST_LEN {
+        return http_server::write_error(req, 413, "Request too big");
+    }
+    let mut buf = vec![0; len];
+    req.read_exact(&mut buf)?;
+    let passphrase = match serde_json::from_slice::<Export>(&buf) {
+        Ok(export) => export.passphrase,
+        Err(e) => return http_server::write_error(req, 400, &format!("Invalid request: {}", e)),
+    };
+    if passphrase.chars().count() < BACKUP_PASSPHRASE_MIN_LEN {
+        return http_server::write_error(
+            req,
+            400,
+            "The passphrase must be at least 8 characters long",
+        );
+    }
+
+    let mut connection = match connection_form() {
+        Ok(connection) => connection,
+        Err(e) => return http_server::write_error(req, 409, &format!("{:#}", e)),
+    };
+    // The backups are stored unencrypted, the secrets are given again when
+    // importing them
+    connection.wifi_pass.clear();
+    connection.eap_password.clear();
+    connection.influx_token.clear();
+    let config = serde_json::value::to_raw_value(&HubConfig {
+        connection,
+        slaves: slaves(),
+    })?;
+    let mut salt = [0u8; SALT_LEN];
+    unsafe { esp_fill_random(salt.as_mut_ptr().cast(), salt.len()) };
+    let backup = Backup {
+        version: BACKUP_VERSION,
+        salt: auth::to_hex(&salt),
+        signature: sign(&backup_key(&passphrase, &salt), config.get())?,
+        config,
+    };
+    info!("Exporting the configuration");
+
+    req.into_response(
+        200,
+        None,
+        &[
+            ("Content-Type", "application/json"),
+            (
+                "Content-Disposition",
+                "attachment; filename=\"smarthome-hub.json\"",
+            ),
+        ],
+    )?
+    .write_all(serde_json::to_string(&backup)?.as_bytes())?;
+    Ok(())
+}
+
+/// Handle the POST request for the import of a configuration.
+/// The slaves are restored right away, the connection is applied by the
+/// request handler thread like a submitted form.
+pub fn import_handler(
+    mut req: Request<&mut EspHttpConnection>,
+    connection_config_sender: &SyncSender<ConnectionConfig>,
+) -> Result<(), Error> {
+    // Like the provisioning form, a hub without admin password accepts the
+    // import from its access point or after a press of its button, the
+    // import must then set one
+    let admin_set = auth::is_admin_set();
+    if admin_set && let Err(e) = auth::authorize(&mut req) {
+        return auth::write_auth_error(req, e);
+    }
+    if !admin_set && !auth::may_claim_admin(&mut req) {
+        return http_server::write_error(req, 403, http_server::ADMIN_CLAIM_REFUSED);
+    }
+
+    let len = req.content_len().unwrap_or(0) as usize;
+    if len > MAX_BACKUP_LEN {
+        return http_server::write_error(req, 413, "Backup too big");
+    }
+
+    let mut buf = vec![0; len];
+    req.read_exact(&mut buf)?;
+
+    let Import {
+        backup,
+        passphrase,
+        secrets,
+        admin_password,
+    } = match serde_json::from_slice::<Import>(&buf) {
+        Ok(import) => import,
+        Err(e) => return http_server::write_error(req, 400, &format!("Invalid backup: {}", e)),
+    };
+    if !(MIN_BACKUP_VERSION..=BACKUP_VERSION).contains(&backup.version) {
+        return http_server::write_error(req, 400, "Unsupported backup version");
+    }
+    let Some(salt) = auth::from_hex(&backup.salt) else {
+        return http_server::write_error(req, 400, "Invalid backup salt");
+    };
+    // The signature is checked before parsing the configuration
+    let key = backup_key(&passphrase, &salt);
+    if verify(&key, backup.config.get(), &backup.signature).is_err() {
+        return http_server::write_error(req, 400, "Invalid backup signature, or wrong passphrase");
+    }
+    let hub_config = match serde_json::from_str::<HubConfig>(backup.config.get()) {
+        Ok(hub_config) => hub_config,
+        Err(e) => return http_server::write_error(req, 400, &format!("Invalid backup: {}", e)),
+    };
+    // The admin password is not part of the backup
+    let mut connection = hub_config.connection;
+    connection.admin_password.clear();
+    restore_secrets(&mut connection, secrets);
+    // The uplink settings of the backup replace the current ones, even empty
+    connection.clear_uplink = true;
+    let config = match connection.validate() {
+        Ok(config) => config,
+        Err(e) => return http_server::write_error(req, 400, e),
+    };
+    if (!admin_set || !admin_password.is_empty())
+        && let Err(e) = auth::validate_admin_password(&admin_password)
+    {
+        return http_server::write_error(req, 400, e);
+    }
+    if let Err(e) = restore_slaves(&hub_config.slaves) {
+        return http_server::write_error(req, 400, &format!("{:#}", e));
+    }
+    if !admin_password.is_empty() {
+        auth::set_admin_password(&admin_password)?;
+        info!("Admin password changed");
+    }
+    info!("Imported configuration for Wi-Fi SSID: {}", config.ssid);
+
+    http_server::submit_config(req, config, connection_config_sender)
+}
+
+/// Current connection configuration, in the format of the provisioning form.
+fn connection_form() -> Result<FormData, Error> {
+    let gs = GlobalState::get();
+
+    let client = match gs
+        .wifi
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|wifi| wifi.get_configuration())
+    {
+        Some(Ok(Configuration::Mixed(client, _))) | Some(Ok(Configuration::Client(client)))
+            if !client.ssid.is_empty() =>
+        {
+            client
+        }
+        _ => bail!("The hub is not configured"),
+    };
+    let eap = super::wifi::load_eap_credentials()?;
+    let netif = NetifSettings::load()?;
+    let uplink = UplinkSettings::load()?;
+    let time_settings = TimeSettings::load()?;
+
+    let mut buffer = [0u8; 64];
+    let server_addr = gs
+        .nvs_connect_configs_ns
+        .lock()
+        .unwrap()
+        .get_str("Server IP", &mut buffer)?
+        .unwrap_or_default()
+        .to_string();
+
+    Ok(FormData {
+        wifi_ssid: client.ssid.to_string(),
+        wifi_pass: client.password.to_string(),
+        auth_method: format!("{:?}", client.auth_method),
+        eap_identity: eap.identity.to_string(),
+        eap_username: eap.username.to_string(),
+        eap_password: eap.password.to_string(),
+        ip_addr: server_addr,
+        influx_org: uplink.influx_org,
+        influx_bucket: uplink.influx_bucket,
+        influx_token: uplink.influx_token,
+        server_cert: uplink.server_cert,
+        clear_uplink: false,
+        hostname: netif.hostname.to_string(),
+        static_ip: netif
+            .static_ip
+            .as_ref()
+            .map(|static_ip| static_ip.ip.to_string())
+            .unwrap_or_default(),
+        gateway: netif
+            .static_ip
+            .as_ref()
+            .map(|static_ip| static_ip.gateway.to_string())
+            .unwrap_or_default(),
+        netmask: netif
+            .static_ip
+            .as_ref()
+            .map(|static_ip| static_ip.prefix.to_string())
+            .unwrap_or_default(),
+        dns: netif
+            .static_ip
+            .as_ref()
+            .map(|static_ip| static_ip.dns.to_string())
+            .unwrap_or_default(),
+        https: super::tls::is_enabled(),
+        queue_capacity: super::frame_queue::stored_capacity(),
+        storage: super::storage::Storage::load().name().to_string(),
+        sd_card_detect: super::sd_card::has_card_detect(),
+        ntp_servers: time_settings.ntp_servers,
+        timezone: time_settings.timezone,
+        time: 0,
+        admin_password: String::new(),
+    })
+}
+
+/// Put the secrets given with the import in the configuration, the missing
+/// ones are kept from the current configuration when it is for the same
+/// network and server.
+fn restore_secrets(connection: &mut FormData, secrets: Secrets) {
+    connection.wifi_pass = secrets.wifi_pass;
+    connection.eap_password = secrets.eap_password;
+    connection.influx_token = secrets.influx_token;
+
+    let Ok(current) = connection_form() else {
+        return;
+    };
+    if connection.wifi_pass.is_empty() && connection.wifi_ssid == current.wifi_ssid {
+        connection.wifi_pass = current.wifi_pass;
+    }
+    if connection.eap_password.is_empty()
+        && connection.wifi_ssid == current.wifi_ssid
+        && connection.eap_username == current.eap_username
+    {
+        connection.eap_password = current.eap_password;
+    }
+    if connection.influx_token.is_empty() && connection.ip_addr == current.ip_addr {
+        connection.influx_token = current.influx_token;
+    }
+}
+
+/// Slaves registered in the NVS, the keys of the `u8` entries that are MAC
+/// addresses.
+fn slaves() -> Vec<Slave> {
+    let gs = GlobalState::get();
+    // Keep the namespace locked while iterating over it
+    let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+
+    let mut slaves = Vec::new();
+    let mut iterator: nvs_iterator_t = core::ptr::null_mut();
+    let mut result = unsafe {
+        nvs_entry_find(
+            NVS_PARTITION.as_ptr(),
+            NVS_NAMESPACE.as_ptr(),
+            nvs_type_t_NVS_TYPE_U8,
+            &mut iterator,
+        )
+    };
+    while result == ESP_OK {
+        let mut info = nvs_entry_info_t::default();
+        unsafe { nvs_entry_info(iterator, &mut info) };
+        let key = unsafe { CStr::from_ptr(info.key.as_ptr()) }.to_str();
+        if let Ok(key) = key
+            && is_slave_key(key)
+            && let Ok(Some(id)) = nvs.get_u8(key)
+        {
+            slaves.push(Slave {
+                mac: key.to_string(),
+                id,
+            });
+        }
+        result = unsafe { nvs_entry_next(&mut iterator) };
+    }
+    unsafe { nvs_release_iterator(iterator) };
+
+    slaves
+}
+
+/// Register the slaves of the backup in the NVS.
+fn restore_slaves(slaves: &[Slave]) -> Result<(), Error> {
+    if let Some(slave) = slaves.iter().find(|slave| !is_slave_key(&slave.mac)) {
+        bail!("Invalid slave MAC address: {}", slave.mac);
+    }
+
+    let gs = GlobalState::get();
+    let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+    let mut num_slaves = nvs.get_u8("Num of slaves")?.unwrap_or(0);
+    for slave in slaves {
+        nvs.set_u8(&slave.mac, slave.id)?;
+        // New slaves must not reuse the restored IDs
+        num_slaves = num_slaves.max(slave.id.saturating_add(1));
+    }
+    nvs.set_u8("Num of slaves", num_slaves)?;
+    info!("Restored {} slaves", slaves.len());
+
+    Ok(())
+}
+
+/// Check if the NVS key is the MAC address of a slave.
+fn is_slave_key(key: &str) -> bool {
+    key.len() == SLAVE_KEY_LEN
+        && key
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('A'..='F').contains(&c))
+}
+
+/// Key signing the backups, derived from the passphrase with PBKDF2.
+fn backup_key(passphrase: &str, salt: &[u8]) -> [u8; KEY_LEN] {
+    let mut key = [0u8; KEY_LEN];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, PASSWORD_HASH_ROUNDS, &mut key);
+    key
+}
+
+/// Compute the signature of the text of the configuration.
+fn sign(key: &[u8], config: &str) -> Result<String, Error> {
+    let mut mac = HmacSha256::new_from_slice(key)?;
+    mac.update(config.as_bytes());
+    Ok(auth::to_hex(&mac.finalize().into_bytes()))
+}
+
+/// Verify the signature of the text of the configuration.
+fn verify(key: &[u8], config: &str, signature: &str) -> Result<(), Error> {
+    let signature = auth::from_hex(signature).context("Invalid signature")?;
+    let mut mac = HmacSha256::new_from_slice(key)?;
+    mac.update(config.as_bytes());
+    mac.verify_slice(&signature)?;
+    Ok(())
+}
@@ -0,0 +1,37 @@
+use std::net::Ipv4Addr;
+use std::thread;
+
+use anyhow::Error;
+use esp_idf_svc::http::server::{EspHttpConnection, Request};
+use firmware::utilities::captive_dns::{CaptiveDns, DNS_PORT};
+use log::{info, warn};
+
+/// Start the DNS server of the captive portal, answering every query with the
+/// address of the access point.
+pub fn start(ap_ip: Ipv4Addr) {
+    let _ = thread::Builder::new()
+        .stack_size(4 * 1024)
+        .name("Captive portal DNS".to_string())
+        .spawn(move || {
+            let dns = match CaptiveDns::bind((Ipv4Addr::UNSPECIFIED, DNS_PORT), ap_ip) {
+                Ok(dns) => dns,
+                Err(e) => {
+                    warn!("Failed to start the captive portal DNS: {:?}", e);
+                    return;
+                }
+            };
+            info!("Captive portal DNS started, answering with {}", ap_ip);
+            let e = dns.run();
+            warn!("Captive portal DNS stopped: {:?}", e);
+        });
+}
+
+/// Handle the connectivity checks of the operating systems, redirecting them
+/// to the provisioning page so that it is opened automatically.
+pub fn redirect_handler(
+    req: Request<&mut EspHttpConnection>,
+    portal_url: &str,
+) -> Result<(), Error> {
+    req.into_response(302, Some("Found"), &[("Location", portal_url)])?;
+    Ok(())
+}
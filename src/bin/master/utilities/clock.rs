@@ -0,0 +1,260 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+
+use anyhow::Error;
+use esp_idf_hal::i2c::I2cDriver;
+use esp_idf_svc::{
+    sntp::{EspSntp, SntpConf},
+    sys::{esp_timer_get_time, localtime_r, time_t, tm, tzset},
+};
+use firmware::{
+    sensors::ds3231::Ds3231,
+    utilities::{sd::CurrentTime, timestamp},
+};
+use log::{info, warn};
+use messages::Frame;
+use serde::Serialize;
+
+use super::global_state::GlobalState;
+
+/// NVS keys of the ID of the last boot and of the time settings
+const BOOT_ID_KEY: &str = "Boot ID";
+const NTP_SERVERS_KEY: &str = "NTP servers";
+const TIMEZONE_KEY: &str = "Timezone";
+/// Max length of the NTP servers, comma separated
+pub const NTP_SERVERS_MAX_LEN: usize = 127;
+/// Max number of NTP servers
+pub const NTP_SERVERS_MAX: usize = 3;
+/// Max length of the POSIX TZ string
+pub const TIMEZONE_MAX_LEN: usize = 63;
+
+/// ID of the current boot, in the timestamps recorded before the clock is set
+static BOOT_ID: AtomicU32 = AtomicU32::new(0);
+/// Source that set the clock, 0 while it is not set
+static SOURCE: AtomicU8 = AtomicU8::new(0);
+/// SNTP synchronized the clock since the RTC was last updated
+static SNTP_SYNCED: AtomicBool = AtomicBool::new(false);
+
+/// Real-time clock keeping the time while the hub is off.
+pub type Rtc = Ds3231<I2cDriver<'static>>;
+
+/// Source that set the clock.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClockSource {
+    Sntp = 1,
+    /// `Date` header of the InfluxDB server, when NTP is blocked
+    Http = 2,
+    /// Browser used for the provisioning, when NTP is blocked
+    Browser = 3,
+    /// Real-time clock, at boot
+    Rtc = 4,
+}
+
+/// Time settings, stored in the NVS.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TimeSettings {
+    /// NTP servers, comma separated, empty for the default ones
+    pub ntp_servers: String,
+    /// POSIX TZ string (e.g. `CET-1CEST,M3.5.0,M10.5.0/3`), empty for UTC
+    pub timezone: String,
+}
+
+impl TimeSettings {
+    /// Load the settings from the NVS.
+    pub fn load() -> Result<Self, Error> {
+        let gs = GlobalState::get();
+        let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+        let mut buffer = [0u8; NTP_SERVERS_MAX_LEN + 1];
+        let mut get = |key: &str| -> Result<String, Error> {
+            Ok(nvs
+                .get_str(key, &mut buffer)?
+                .unwrap_or_default()
+                .to_string())
+        };
+
+        Ok(Self {
+            ntp_servers: get(NTP_SERVERS_KEY)?,
+            timezone: get(TIMEZONE_KEY)?,
+        })
+    }
+
+    /// Store the settings in the NVS.
+    pub fn store(&self) -> Result<(), Error> {
+        let gs = GlobalState::get();
+        let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+        nvs.set_str(NTP_SERVERS_KEY, &self.ntp_servers)?;
+        nvs.set_str(TIMEZONE_KEY, &self.timezone)?;
+        Ok(())
+    }
+
+    /// NTP servers, empty for the default ones.
+    pub fn servers(&self) -> impl Iterator<Item = &str> {
+        self.ntp_servers
+            .split(',')
+            .map(str::trim)
+            .filter(|server| !server.is_empty())
+    }
+}
+
+/// Give a new ID to the boot and apply the stored timezone.
+pub fn init() {
+    let gs = GlobalState::get();
+    let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+    let boot_id = match nvs.get_u32(BOOT_ID_KEY) {
+        Ok(Some(last_boot_id)) => (last_boot_id + 1) % (1 << timestamp::BOOT_ID_BITS),
+        _ => 0,
+    };
+    if let Err(e) = nvs.set_u32(BOOT_ID_KEY, boot_id) {
+        warn!("Failed to store the boot ID: {:?}", e);
+    }
+    BOOT_ID.store(boot_id, Ordering::Relaxed);
+    drop(nvs);
+
+    match TimeSettings::load() {
+        Ok(settings) => apply_timezone(&settings.timezone),
+        Err(e) => warn!("Failed to load the time settings: {:?}", e),
+    }
+}
+
+/// Check if the clock has been set, by SNTP or another source.
+pub fn is_set() -> bool {
+    CurrentTime::new().is_set()
+}
+
+/// Source that set the clock, `None` while it is not set.
+pub fn source() -> Option<ClockSource> {
+    match SOURCE.load(Ordering::Relaxed) {
+        1 => Some(ClockSource::Sntp),
+        2 => Some(ClockSource::Http),
+        3 => Some(ClockSource::Browser),
+        4 => Some(ClockSource::Rtc),
+        _ => None,
+    }
+}
+
+/// Set the clock from a fallback source, in ms since the UNIX epoch.
+/// Ignored once the clock is set, SNTP is more precise.
+pub fn set_fallback_time(time: u64, source: ClockSource) {
+    if is_set() {
+        return;
+    }
+    info!("Clock set from {:?}", source);
+    unsafe { CurrentTime::new().update_time(time) };
+    SOURCE.store(source as u8, Ordering::Relaxed);
+}
+
+/// Apply the POSIX TZ string to the local time, UTC when empty.
+/// Only at boot, before the other threads are started: changing `TZ` while
+/// another thread reads the environment or the local time is a data race.
+fn apply_timezone(timezone: &str) {
+    let timezone = if timezone.is_empty() {
+        "UTC0"
+    } else {
+        timezone
+    };
+    std::env::set_var("TZ", timezone);
+    unsafe { tzset() };
+}
+
+/// Local time, formatted as `YYYY-MM-DD hh:mm:ss`. `None` while the clock is
+/// not set.
+pub fn local_time() -> Option<String> {
+    let now = CurrentTime::new().as_millis()?;
+    let seconds = (now / 1000) as time_t;
+    let mut local: tm = unsafe { core::mem::zeroed() };
+    if unsafe { localtime_r(&seconds, &mut local) }.is_null() {
+        return None;
+    }
+    Some(format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        local.tm_year + 1900,
+        local.tm_mon + 1,
+        local.tm_mday,
+        local.tm_hour,
+        local.tm_min,
+        local.tm_sec,
+    ))
+}
+
+/// Milliseconds since the boot.
+fn uptime_ms() -> u64 {
+    (unsafe { esp_timer_get_time() } / 1000) as u64
+}
+
+/// Timestamp of a frame recorded now: the wall-clock time, or the time since
+/// the boot while the clock is not set.
+pub fn now() -> u64 {
+    match CurrentTime::new().as_millis() {
+        Some(now) => now,
+        None => timestamp::relative(BOOT_ID.load(Ordering::Relaxed), uptime_ms()),
+    }
+}
+
+/// Rewrite the timestamps of the frames recorded before the clock was set to
+/// the wall-clock time. The frames recorded during a previous boot, while
+/// the clock was never set, are dropped.
+/// The clock must be set.
+pub fn to_wall_clock(frames: Vec<Frame>) -> Vec<Frame> {
+    let now = CurrentTime::new().as_millis_raw();
+    let boot_time = now.saturating_sub(uptime_ms());
+    let boot_id = BOOT_ID.load(Ordering::Relaxed);
+
+    let len = frames.len();
+    let frames: Vec<Frame> = frames
+        .into_iter()
+        .filter_map(|frame| {
+            timestamp::to_wall_clock(frame.timestamp(), boot_id, boot_time)
+                .map(|timestamp| frame.set_timestamp(timestamp))
+        })
+        .collect();
+    if frames.len() < len {
+        warn!(
+            "Dropped {} frames recorded before the clock was set in a previous boot",
+            len - frames.len()
+        );
+    }
+    frames
+}
+
+/// Start SNTP with the stored servers, the clock is marked as set once
+/// synchronized. The slots not filled by the stored servers keep the default
+/// ones.
+pub fn start_sntp() -> EspSntp<'static> {
+    let settings = TimeSettings::load().unwrap_or_else(|e| {
+        warn!("Failed to load the time settings: {:?}", e);
+        TimeSettings::default()
+    });
+    let mut conf = SntpConf::default();
+    for (slot, server) in conf.servers.iter_mut().zip(settings.servers()) {
+        *slot = server;
+    }
+    info!("Starting SNTP with the servers {:?}", conf.servers);
+
+    EspSntp::new_with_callback(&conf, |_| {
+        info!("Clock synchronized with SNTP");
+        CurrentTime::new().mark_set();
+        SOURCE.store(ClockSource::Sntp as u8, Ordering::Relaxed);
+        SNTP_SYNCED.store(true, Ordering::Relaxed);
+    })
+    .expect("Failed to initialize SNTP")
+}
+
+/// Set the clock from the RTC, at boot.
+pub fn set_from_rtc(rtc: &mut Rtc) {
+    match rtc.time() {
+        Ok(Some(time)) => set_fallback_time(time, ClockSource::Rtc),
+        Ok(None) => warn!("The RTC stopped, its time is lost until the next SNTP sync"),
+        Err(e) => warn!("Failed to read the RTC: {:?}", e),
+    }
+}
+
+/// Update the RTC with the time of SNTP, after each synchronization.
+pub fn update_rtc(rtc: &mut Rtc) {
+    if !SNTP_SYNCED.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    match rtc.set_time(CurrentTime::new().as_millis_raw()) {
+        Ok(()) => info!("RTC updated with the time of SNTP"),
+        Err(e) => warn!("Failed to update the RTC: {:?}", e),
+    }
+}
@@ -1,18 +1,106 @@
+use core::ffi::CStr;
 use core::time::Duration;
 
 use esp_idf_sys::ESP_NOW_MAX_DATA_LEN;
 
 pub const MAX_DATA_LEN: usize = ESP_NOW_MAX_DATA_LEN as usize;
+/// Firmware version, advertised with mDNS and reported to the server
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
 // Need lots of stack to parse JSON
 pub const STACK_SIZE: usize = 10240;
 /// AP SSID
 pub const SSID: &str = "Smart Home Hub";
+/// Default hostname of the station interface
+pub const HOSTNAME: &str = "smarthome-hub";
+/// Instance name of the services advertised with mDNS
+pub const MDNS_INSTANCE_NAME: &str = "Smart Home Hub";
+/// Port of the HTTP server
+pub const HTTP_PORT: u16 = 80;
+/// Port of the HTTPS server
+pub const HTTPS_PORT: u16 = 443;
+/// Connectivity check URLs of the operating systems, redirected to the
+/// provisioning page by the captive portal
+pub const CAPTIVE_PORTAL_URLS: [&str; 9] = [
+    // Android
+    "/generate_204",
+    "/gen_204",
+    // Apple
+    "/hotspot-detect.html",
+    "/library/test/success.html",
+    // Windows
+    "/connecttest.txt",
+    "/ncsi.txt",
+    "/redirect",
+    // Firefox
+    "/canonical.html",
+    "/success.txt",
+];
+/// NVS partition of the configuration
+// Safety: NUL terminated, without interior NUL
+pub const NVS_PARTITION: &CStr = unsafe { CStr::from_bytes_with_nul_unchecked(b"nvs\0") };
+/// NVS namespace of the configuration
+// Safety: NUL terminated, without interior NUL
+pub const NVS_NAMESPACE: &CStr =
+    unsafe { CStr::from_bytes_with_nul_unchecked(b"Connect configs\0") };
+/// Min length of the passphrase signing the configuration backups
+pub const BACKUP_PASSPHRASE_MIN_LEN: usize = 8;
+/// Username of the admin interface
+pub const ADMIN_USERNAME: &str = "admin";
+/// Min length of the admin password
+pub const ADMIN_PASSWORD_MIN_LEN: usize = 8;
+/// PBKDF2 rounds of the admin password hash
+pub const PASSWORD_HASH_ROUNDS: u32 = 2048;
+/// Number of failed logins after which the logins are refused for a while
+pub const MAX_FAILED_LOGINS: u32 = 5;
+/// Time the logins are refused after too many failures, doubled at every
+/// further failure
+pub const LOGIN_LOCKOUT_TIME: Duration = Duration::from_secs(30);
+/// Max time the logins are refused
+pub const LOGIN_LOCKOUT_MAX: Duration = Duration::from_secs(15 * 60);
+/// Number of clients whose failed logins are counted separately
+pub const MAX_LOGIN_CLIENTS: usize = 8;
+/// Time the factory reset button must be held down
+pub const FACTORY_RESET_HOLD_TIME: Duration = Duration::from_secs(5);
+/// Time the admin account can be claimed from the station interface after a
+/// short press of the button
+pub const ADMIN_CLAIM_TIME: Duration = Duration::from_secs(5 * 60);
 /// Default TCP server address (telegraf)
 pub const TCP_SERVER_ADDR: &str = "tcp://4.232.184.193:8094";
+/// Max number of frames kept in memory while neither the server nor the SD
+/// card are available
+pub const RAM_QUEUE_CAPACITY: usize = 256;
+/// Max number of frames kept in memory, on the boards with enough PSRAM
+pub const PSRAM_QUEUE_CAPACITY: usize = 16 * 1024;
+/// Max number of frames sent to the server in a single write
+pub const UPLINK_BATCH_SIZE: usize = 50;
+/// Delay before reconnecting to the server after a failure, doubled at every
+/// further failure
+pub const UPLINK_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// Max delay before reconnecting to the server
+pub const UPLINK_RETRY_MAX: Duration = Duration::from_secs(5 * 60);
 /// Broadcast ping frequency (interval)
 pub const BROADCAST_PING_INTERVAL: Duration = Duration::from_secs(2);
+/// Number of times the channel change announcement is broadcast
+pub const CHANNEL_ANNOUNCE_REPEAT: usize = 3;
+/// Interval between two channel change announcements
+pub const CHANNEL_ANNOUNCE_INTERVAL: Duration = Duration::from_millis(50);
 /// Sd retry frequency (interval)
 pub const SD_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+/// Interval between two reports of the SD card usage to the server
+pub const SD_STATUS_INTERVAL: Duration = Duration::from_secs(60);
+/// Max number of fields kept for the Prometheus metrics
+pub const METRICS_MAX_SERIES: usize = 256;
+/// Max number of clients of the WebSocket live stream, each one keeps one of
+/// the 4 sockets of the HTTP server open
+pub const LIVE_STREAM_MAX_CLIENTS: usize = 2;
+/// Max number of batches of frames waiting to be sent to the live stream
+/// clients
+pub const LIVE_STREAM_QUEUE_LEN: usize = 8;
+/// Interval between two reports of the health of the hub to the server
+pub const TELEMETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// Interval between two requests of the time to the server, while the clock
+/// is not set by SNTP
+pub const CLOCK_FALLBACK_INTERVAL: Duration = Duration::from_secs(30);
 /// WiFi retry frequency (interval)
 pub const WIFI_RETRY_INTERVAL: Duration = Duration::from_secs(5);
 /// ESP-NOW initialization time limit
@@ -1,13 +1,35 @@
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::mpsc::SyncSender;
+use std::thread;
 
+use esp_idf_svc::espnow::{EspNow, BROADCAST};
 use esp_idf_svc::wifi::{BlockingWifi, Configuration, EspWifi};
 use esp_idf_sys::wifi_second_chan_t_WIFI_SECOND_CHAN_NONE;
-use log::info;
+use firmware::utilities::channel::set_channel;
+use log::{info, warn};
+use messages::Frame;
 
-use super::constants::MAX_DATA_LEN;
+use super::constants::{CHANNEL_ANNOUNCE_INTERVAL, CHANNEL_ANNOUNCE_REPEAT, MAX_DATA_LEN};
+
+/// Data received from the ESP-NOW and passed to the main thread
+static RECEIVED: AtomicU32 = AtomicU32::new(0);
+/// Data dropped because the channel to the main thread was full
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+/// Data taken from the channel by the main thread
+static TAKEN: AtomicU32 = AtomicU32::new(0);
+
+/// Counters of the data received from the ESP-NOW.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EspNowStats {
+    pub received: u32,
+    pub dropped: u32,
+    /// Data waiting in the channel to the main thread
+    pub pending: u32,
+}
 
 /// Callback invoked when a frame is received from the ESP-NOW.
-/// Sends the received data to the main thread with a channel.
+/// Sends the received data to the main thread with a channel, the data is
+/// dropped if the main thread is behind, the Wi-Fi task must not block.
 pub fn espnow_recv_cb(
     mac_addr: &[u8],
     data: &[u8],
@@ -15,7 +37,25 @@ pub fn espnow_recv_cb(
 ) {
     let vec_data = heapless::Vec::<u8, MAX_DATA_LEN>::from_slice(data).unwrap();
 
-    channel.send((mac_addr.to_vec(), vec_data)).unwrap();
+    match channel.try_send((mac_addr.to_vec(), vec_data)) {
+        Ok(()) => RECEIVED.fetch_add(1, Ordering::Relaxed),
+        Err(_) => DROPPED.fetch_add(1, Ordering::Relaxed),
+    };
+}
+
+/// Count the data taken from the channel by the main thread.
+pub fn mark_taken() {
+    TAKEN.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counters of the data received from the ESP-NOW since the boot.
+pub fn stats() -> EspNowStats {
+    let received = RECEIVED.load(Ordering::Relaxed);
+    EspNowStats {
+        received,
+        dropped: DROPPED.load(Ordering::Relaxed),
+        pending: received.wrapping_sub(TAKEN.load(Ordering::Relaxed)),
+    }
 }
 
 /// Reconfigure the broadcast peer to match the WiFI channel.
@@ -31,17 +71,28 @@ pub fn reconfigure_broadcast(wifi: &BlockingWifi<EspWifi<'static>>) {
         .expect("ESP-NOW not initialized");
 
     // Get the new channel
-    let channel = unsafe {
+    let channel = match wifi.get_configuration().unwrap() {
+        Configuration::Mixed(client, _) => client.channel.expect("Channel not set"),
+        _ => panic!("Invalid configuration"),
+    };
+
+    let mut espnow_channel = gs.espnow_channel.lock().unwrap();
+    unsafe {
         esp_idf_hal::sys::esp_wifi_set_promiscuous(true);
-        let channel = match wifi.get_configuration().unwrap() {
-            Configuration::Mixed(client, _) => client.channel.expect("Channel not set"),
-            _ => panic!("Invalid configuration"),
-        };
+    }
+    // Announce the new channel to the slaves on the old one, before leaving it
+    if let Some(old_channel) = *espnow_channel
+        && old_channel != channel
+    {
+        announce_channel_change(espnow, old_channel, channel);
+    }
+    unsafe {
         let second = wifi_second_chan_t_WIFI_SECOND_CHAN_NONE;
         esp_idf_hal::sys::esp_wifi_set_channel(channel, second);
         esp_idf_hal::sys::esp_wifi_set_promiscuous(false);
-        channel
-    };
+    }
+    espnow_channel.replace(channel);
+    drop(espnow_channel);
 
     info!("New espnow channel: {}", channel);
 
@@ -50,7 +101,7 @@ pub fn reconfigure_broadcast(wifi: &BlockingWifi<EspWifi<'static>>) {
         channel,
         ifidx: esp_idf_hal::sys::wifi_interface_t_WIFI_IF_AP,
         encrypt: false,
-        peer_addr: [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF],
+        peer_addr: BROADCAST,
         ..Default::default()
     };
     // Check if the broadcast is already added
@@ -63,3 +114,22 @@ pub fn reconfigure_broadcast(wifi: &BlockingWifi<EspWifi<'static>>) {
     // Drop the lock
     drop(espnow_option_lock);
 }
+
+/// Broadcast the channel change announcement on the old channel, so that the
+/// slaves can follow the master without searching for it.
+fn announce_channel_change(espnow: &EspNow<'static>, old_channel: u8, new_channel: u8) {
+    info!(
+        "Announcing channel change from {} to {}",
+        old_channel, new_channel
+    );
+    set_channel(old_channel);
+
+    let message = firmware::ChannelChangeMessage::new().with_channel(new_channel);
+    let frame: Frame = message.into();
+    for _ in 0..CHANNEL_ANNOUNCE_REPEAT {
+        if let Err(e) = espnow.send(BROADCAST, &frame.serialize()) {
+            warn!("Failed to send channel change announcement: {:?}", e);
+        }
+        thread::sleep(CHANNEL_ANNOUNCE_INTERVAL);
+    }
+}
@@ -0,0 +1,70 @@
+use core::time::Duration;
+use std::thread;
+use std::time::Instant;
+
+use esp_idf_hal::gpio::{AnyInputPin, Input, PinDriver};
+use esp_idf_svc::sys::{
+    esp, esp_wifi_restore, nvs_close, nvs_commit, nvs_erase_all, nvs_handle_t, nvs_open,
+    nvs_open_mode_t_NVS_READWRITE,
+};
+use log::{error, info, warn};
+
+use super::{
+    auth,
+    constants::{FACTORY_RESET_HOLD_TIME, NVS_NAMESPACE},
+};
+
+/// Task that performs a factory reset when the button is held down.
+/// A short press allows claiming the admin account from the station
+/// interface, see [`auth::may_claim_admin`].
+/// The button is active low.
+pub fn button_task(button: PinDriver<'static, AnyInputPin, Input>) {
+    let mut pressed_since: Option<Instant> = None;
+    loop {
+        thread::sleep(Duration::from_millis(100));
+
+        if button.is_high() {
+            // Released before the factory reset
+            if pressed_since.take().is_some() {
+                auth::allow_admin_claim();
+            }
+            continue;
+        }
+        let pressed_since = pressed_since.get_or_insert_with(|| {
+            info!("Factory reset button pressed, hold it to reset");
+            Instant::now()
+        });
+        if pressed_since.elapsed() > FACTORY_RESET_HOLD_TIME {
+            factory_reset();
+        }
+    }
+}
+
+/// Erase the configuration of the hub and restart it.
+pub fn factory_reset() -> ! {
+    warn!("Factory reset");
+
+    // Wi-Fi, server address, interface settings and slaves
+    let erased = unsafe {
+        let mut handle: nvs_handle_t = 0;
+        esp!(nvs_open(
+            NVS_NAMESPACE.as_ptr(),
+            nvs_open_mode_t_NVS_READWRITE,
+            &mut handle
+        ))
+        .and_then(|_| {
+            let result = esp!(nvs_erase_all(handle)).and_then(|_| esp!(nvs_commit(handle)));
+            nvs_close(handle);
+            result
+        })
+    };
+    if let Err(e) = erased {
+        error!("Failed to erase the configuration: {:?}", e);
+    }
+    // Wi-Fi configuration stored by the driver
+    if let Err(e) = unsafe { esp!(esp_wifi_restore()) } {
+        error!("Failed to restore the Wi-Fi configuration: {:?}", e);
+    }
+
+    esp_idf_hal::reset::restart();
+}
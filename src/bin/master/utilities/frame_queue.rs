@@ -0,0 +1,82 @@
+use std::mem::size_of;
+
+use anyhow::Error;
+use esp_idf_svc::sys::{
+    heap_caps_get_largest_free_block, heap_caps_get_total_size, MALLOC_CAP_SPIRAM,
+};
+use firmware::{definitions::is_alarm_frame, utilities::bounded_queue::BoundedQueue};
+use log::info;
+use messages::Frame;
+use telegraf::{Metric, Point};
+
+use super::{
+    constants::{PSRAM_QUEUE_CAPACITY, RAM_QUEUE_CAPACITY},
+    global_state::GlobalState,
+};
+
+/// NVS key of the capacity of the queue, 0 for the max of the board
+const QUEUE_CAPACITY_KEY: &str = "Queue capacity";
+/// The queue takes at most a quarter of the largest free block of PSRAM, the
+/// rest is left to the other allocations
+const PSRAM_SHARE_DIVISOR: usize = 4;
+
+/// State of the queue, reported to the server.
+#[derive(Metric)]
+#[measurement = "hub_queue"]
+struct QueueMetric {
+    queued: u64,
+    dropped: u64,
+    capacity: u64,
+}
+
+/// Max capacity of the queue, larger when the board has PSRAM.
+/// The items are allocated up front in a single block, the capacity is
+/// limited by the free PSRAM: the max number of frames may not fit in a
+/// 2 MB PSRAM.
+pub fn max_capacity() -> usize {
+    let psram_size = unsafe { heap_caps_get_total_size(MALLOC_CAP_SPIRAM) };
+    if psram_size == 0 {
+        return RAM_QUEUE_CAPACITY;
+    }
+    let largest_block = unsafe { heap_caps_get_largest_free_block(MALLOC_CAP_SPIRAM) };
+    let capacity = largest_block / PSRAM_SHARE_DIVISOR / size_of::<Frame>();
+    capacity.clamp(RAM_QUEUE_CAPACITY, PSRAM_QUEUE_CAPACITY)
+}
+
+/// Capacity of the queue stored in the NVS, 0 for the max of the board.
+pub fn stored_capacity() -> u32 {
+    let gs = GlobalState::get();
+    let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+    nvs.get_u32(QUEUE_CAPACITY_KEY).ok().flatten().unwrap_or(0)
+}
+
+/// Store the capacity of the queue, from the next restart.
+pub fn set_capacity(capacity: u32) -> Result<(), Error> {
+    let gs = GlobalState::get();
+    let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+    nvs.set_u32(QUEUE_CAPACITY_KEY, capacity)?;
+    Ok(())
+}
+
+/// Create the queue of the frames waiting for the server or the SD card.
+/// The alarms are never dropped.
+pub fn new() -> BoundedQueue<Frame> {
+    let max_capacity = max_capacity();
+    let capacity = match stored_capacity() as usize {
+        0 => max_capacity,
+        capacity => capacity.min(max_capacity),
+    };
+    info!("Frames queue capacity: {}", capacity);
+
+    BoundedQueue::new(capacity, is_alarm_frame)
+}
+
+/// Point reporting the state of the queue to the server.
+pub fn to_point(queue: &BoundedQueue<Frame>) -> Point {
+    QueueMetric {
+        queued: queue.len() as u64,
+        dropped: queue.dropped(),
+        capacity: queue.capacity() as u64,
+    }
+    .to_point()
+}
@@ -1,16 +1,28 @@
 use std::{
     fmt::Debug,
+    net::Ipv4Addr,
     sync::{Arc, Mutex, OnceLock},
+    time::Instant,
 };
 
 use esp_idf_svc::{
     espnow::EspNow,
+    mdns::EspMdns,
     nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault},
     sntp::EspSntp,
     wifi::{BlockingWifi, EspWifi},
 };
+use firmware::utilities::{backoff::Backoff, metrics::LatestValues};
 use log::info;
-use telegraf::Client;
+
+use super::{
+    auth::LoginLimiters,
+    constants::{NVS_NAMESPACE, UPLINK_RETRY_INTERVAL, UPLINK_RETRY_MAX},
+    http_server::ProvisionStatus,
+    live_stream::LiveClients,
+    metrics,
+    uplink::{Uplink, UplinkStats},
+};
 
 static GLOBAL_STATE: OnceLock<Arc<GlobalState>> = OnceLock::new();
 
@@ -21,8 +33,28 @@ pub struct GlobalState {
     pub(crate) nvs_connect_configs_ns: Mutex<EspNvs<NvsDefault>>,
     pub(crate) wifi: Mutex<Option<BlockingWifi<EspWifi<'static>>>>,
     pub(crate) esp_now: Mutex<Option<EspNow<'static>>>,
-    pub(crate) tcp_stream: Mutex<Option<Client>>,
+    /// Wi-Fi channel of the ESP-NOW broadcast peer, announced to the slaves.
+    pub(crate) espnow_channel: Mutex<Option<u8>>,
+    /// Connection to the server receiving the data.
+    pub(crate) uplink: Mutex<Option<Uplink>>,
+    /// Schedule of the reconnections to the server.
+    pub(crate) uplink_backoff: Mutex<Backoff>,
+    pub(crate) uplink_stats: Mutex<UplinkStats>,
     pub(crate) sntp: Mutex<Option<EspSntp<'static>>>,
+    pub(crate) mdns: Mutex<Option<EspMdns>>,
+    pub(crate) provision_status: Mutex<ProvisionStatus>,
+    pub(crate) login_limiters: Mutex<LoginLimiters>,
+    /// Address of the hub on its access point.
+    pub(crate) ap_ip: Mutex<Option<Ipv4Addr>>,
+    /// End of the time the admin account can be claimed from the station
+    /// interface, opened by a short press of the button.
+    pub(crate) admin_claim_until: Mutex<Option<Instant>>,
+    /// Whether the server has been started with HTTPS.
+    pub(crate) https_enabled: Mutex<bool>,
+    /// Latest values of the fields received from the slaves, for Prometheus.
+    pub(crate) latest_values: Mutex<LatestValues>,
+    /// Clients of the WebSocket live stream of the frames.
+    pub(crate) live_clients: Mutex<LiveClients>,
 }
 
 impl Debug for GlobalState {
@@ -36,7 +68,7 @@ impl GlobalState {
     /// Must be initialized only once.
     pub fn init(nvs_partition: EspDefaultNvsPartition) {
         // NVS config
-        let namespace = "Connect configs";
+        let namespace = NVS_NAMESPACE.to_str().unwrap();
         let nvs = match EspNvs::new(nvs_partition, namespace, true) {
             Ok(nvs) => {
                 info!("Got namespace {:?} from default partition", namespace);
@@ -49,8 +81,19 @@ impl GlobalState {
             nvs_connect_configs_ns: Mutex::new(nvs),
             wifi: Mutex::new(None),
             esp_now: Mutex::new(None),
-            tcp_stream: Mutex::new(None),
+            espnow_channel: Mutex::new(None),
+            uplink: Mutex::new(None),
+            uplink_backoff: Mutex::new(Backoff::new(UPLINK_RETRY_INTERVAL, UPLINK_RETRY_MAX)),
+            uplink_stats: Mutex::new(UplinkStats::default()),
             sntp: Mutex::new(None),
+            mdns: Mutex::new(None),
+            provision_status: Mutex::new(ProvisionStatus::Idle),
+            login_limiters: Mutex::new(LoginLimiters::default()),
+            ap_ip: Mutex::new(None),
+            admin_claim_until: Mutex::new(None),
+            https_enabled: Mutex::new(false),
+            latest_values: Mutex::new(metrics::latest_values()),
+            live_clients: Mutex::new(LiveClients::default()),
         };
         GLOBAL_STATE
             .set(Arc::new(gs))
@@ -1,30 +1,393 @@
 use core::time::Duration;
+use std::net::Ipv4Addr;
 use std::sync::mpsc::SyncSender;
 use std::thread;
 
-use anyhow::Error;
+use anyhow::{Context, Error};
 use embedded_svc::http::Headers;
 use esp_idf_hal::io::{EspIOError, Read, Write};
 use esp_idf_svc::http::server::{EspHttpConnection, Request};
-use esp_idf_svc::sntp;
-use esp_idf_svc::wifi::{self, AccessPointConfiguration, AuthMethod, ClientConfiguration};
-use log::info;
-use serde::Deserialize;
+use esp_idf_svc::sntp::SyncStatus;
+use esp_idf_svc::wifi::{
+    self, AccessPointConfiguration, AuthMethod, BlockingWifi, ClientConfiguration, EspWifi,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
 
 use crate::utilities;
-use crate::utilities::constants::SSID;
+use crate::utilities::auth;
+use crate::utilities::clock::{
+    self, ClockSource, TimeSettings, NTP_SERVERS_MAX, NTP_SERVERS_MAX_LEN, TIMEZONE_MAX_LEN,
+};
+use crate::utilities::constants::{PSRAM_QUEUE_CAPACITY, SSID};
+use crate::utilities::netif::{self, NetifSettings, StaticIp};
+use crate::utilities::storage::Storage;
+use crate::utilities::uplink::{self, UplinkSettings, UplinkStats, SERVER_CERT_MAX_LEN};
 
 /// Max payload length
-const MAX_LEN: usize = 128;
+const MAX_LEN: usize = 4096;
+/// Error of the configuration changes of a hub without admin password, sent
+/// from the network
+pub(crate) const ADMIN_CLAIM_REFUSED: &str = "Set the admin password from the access point \
+     of the hub, or press its button shortly and retry within 5 minutes";
 /// Include the HTML page
 static INDEX_HTML: &str = include_str!("server_page.html");
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 /// Input form data structure.
-pub struct FormData<'a> {
-    wifi_ssid: &'a str,
-    wifi_pass: &'a str,
-    ip_addr: &'a str,
+pub struct FormData {
+    pub(crate) wifi_ssid: String,
+    #[serde(default)]
+    pub(crate) wifi_pass: String,
+    /// Authentication method, detected with a scan when empty
+    #[serde(default)]
+    pub(crate) auth_method: String,
+    /// WPA2-Enterprise outer identity, the username is used when empty
+    #[serde(default)]
+    pub(crate) eap_identity: String,
+    #[serde(default)]
+    pub(crate) eap_username: String,
+    #[serde(default)]
+    pub(crate) eap_password: String,
+    pub(crate) ip_addr: String,
+    /// InfluxDB organization, bucket and API token, for the `http://` and
+    /// `https://` server addresses
+    #[serde(default)]
+    pub(crate) influx_org: String,
+    #[serde(default)]
+    pub(crate) influx_bucket: String,
+    #[serde(default)]
+    pub(crate) influx_token: String,
+    /// PEM encoded server certificate to pin, the CA bundle is used when empty
+    #[serde(default)]
+    pub(crate) server_cert: String,
+    /// Store the uplink settings even when all of them are empty, to remove
+    /// the InfluxDB credentials and the pinned certificate. Without it, empty
+    /// settings keep the current ones.
+    #[serde(default, skip_serializing)]
+    pub(crate) clear_uplink: bool,
+    /// Hostname of the hub, the default one is used when empty
+    #[serde(default)]
+    pub(crate) hostname: String,
+    /// Static IPv4 address, DHCP is used when empty
+    #[serde(default)]
+    pub(crate) static_ip: String,
+    #[serde(default)]
+    pub(crate) gateway: String,
+    #[serde(default)]
+    pub(crate) netmask: String,
+    /// DNS server, the gateway is used when empty
+    #[serde(default)]
+    pub(crate) dns: String,
+    /// Serve the pages over HTTPS, from the next restart
+    #[serde(default)]
+    pub(crate) https: bool,
+    /// Max number of frames kept in memory without the server and the SD
+    /// card, 0 for the max of the board. Applied at the next restart.
+    #[serde(default)]
+    pub(crate) queue_capacity: u32,
+    /// Storage of the frames that could not be sent: auto, sd or flash.
+    /// Applied at the next restart.
+    #[serde(default)]
+    pub(crate) storage: String,
+    /// The SD card slot has a card-detect switch, the card is polled
+    /// otherwise. Applied at the next restart.
+    #[serde(default)]
+    pub(crate) sd_card_detect: bool,
+    /// NTP servers, comma separated, the default ones are used when empty
+    #[serde(default)]
+    pub(crate) ntp_servers: String,
+    /// POSIX TZ string, UTC is used when empty
+    #[serde(default)]
+    pub(crate) timezone: String,
+    /// Time of the browser in ms since the UNIX epoch, 0 when unknown. Sets
+    /// the clock when NTP is blocked, never exported in the backups.
+    #[serde(default, skip_serializing)]
+    pub(crate) time: u64,
+    /// New admin password, required at the first provisioning.
+    /// Never exported in the backups.
+    #[serde(default, skip_serializing)]
+    pub(crate) admin_password: String,
+}
+
+/// Validated connection configuration, sent to the request handler thread.
+pub struct ConnectionConfig {
+    pub ssid: heapless::String<32>,
+    pub password: heapless::String<64>,
+    /// Authentication method, `None` to detect it with a scan
+    pub auth_method: Option<AuthMethod>,
+    pub eap: EapCredentials,
+    /// Server address, empty to keep the current one
+    pub server_addr: heapless::String<63>,
+    /// Settings of the uplink, `None` to keep the current ones
+    pub uplink: Option<UplinkSettings>,
+    pub netif: NetifSettings,
+    pub https: bool,
+    pub queue_capacity: u32,
+    pub storage: Storage,
+    pub sd_card_detect: bool,
+    pub time_settings: TimeSettings,
+    /// Time of the browser in ms since the UNIX epoch
+    pub browser_time: Option<u64>,
+}
+
+/// Credentials of a WPA2-Enterprise (EAP-PEAP) network.
+#[derive(Default, Clone, PartialEq)]
+pub struct EapCredentials {
+    pub identity: heapless::String<64>,
+    pub username: heapless::String<64>,
+    pub password: heapless::String<64>,
+}
+
+impl ConnectionConfig {
+    /// Check that the credentials match the authentication method.
+    pub fn check_credentials(&self, auth_method: AuthMethod) -> Result<(), &'static str> {
+        match auth_method {
+            AuthMethod::None => {
+                if !self.password.is_empty() {
+                    return Err("Open networks have no password");
+                }
+            }
+            AuthMethod::WPA2Enterprise => {
+                if self.eap.username.is_empty() || self.eap.password.is_empty() {
+                    return Err("WPA2-Enterprise networks require a username and a password");
+                }
+            }
+            AuthMethod::WEP | AuthMethod::WAPIPersonal => {
+                return Err("Unsupported authentication method");
+            }
+            _ => {
+                // WPA passphrases are 8 to 63 characters long, or 64 hexadecimal digits
+                let pass_len = self.password.len();
+                if !(8..=64).contains(&pass_len)
+                    || (pass_len == 64 && !self.password.chars().all(|c| c.is_ascii_hexdigit()))
+                {
+                    return Err(
+                        "The Wi-Fi password must be 8 to 63 characters long, or 64 hexadecimal digits",
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse the authentication method of the form. Both the names of the form
+/// and the ones returned by the scan are accepted.
+fn parse_auth_method(name: &str) -> Result<Option<AuthMethod>, &'static str> {
+    let auth_method = match name {
+        "" => return Ok(None),
+        "open" | "None" => AuthMethod::None,
+        "wpa2" | "WPA2Personal" => AuthMethod::WPA2Personal,
+        "WPA" => AuthMethod::WPA,
+        "WPAWPA2Personal" => AuthMethod::WPAWPA2Personal,
+        "wpa3" | "WPA3Personal" => AuthMethod::WPA3Personal,
+        "wpa2wpa3" | "WPA2WPA3Personal" => AuthMethod::WPA2WPA3Personal,
+        "wpa2enterprise" | "WPA2Enterprise" => AuthMethod::WPA2Enterprise,
+        _ => return Err("Unsupported authentication method"),
+    };
+    Ok(Some(auth_method))
+}
+
+impl FormData {
+    /// Validate the form, returning the error to show to the user.
+    pub fn validate(&self) -> Result<ConnectionConfig, &'static str> {
+        if self.wifi_ssid.is_empty() {
+            return Err("The Wi-Fi SSID is required");
+        }
+        let ssid = self
+            .wifi_ssid
+            .as_str()
+            .try_into()
+            .map_err(|_| "The Wi-Fi SSID must be at most 32 bytes long")?;
+
+        let password = self
+            .wifi_pass
+            .as_str()
+            .try_into()
+            .map_err(|_| "The Wi-Fi password must be at most 64 bytes long")?;
+        let auth_method = parse_auth_method(&self.auth_method)?;
+
+        let eap_too_long = "The WPA2-Enterprise credentials must be at most 64 bytes long";
+        let eap = EapCredentials {
+            identity: self
+                .eap_identity
+                .as_str()
+                .try_into()
+                .map_err(|_| eap_too_long)?,
+            username: self
+                .eap_username
+                .as_str()
+                .try_into()
+                .map_err(|_| eap_too_long)?,
+            password: self
+                .eap_password
+                .as_str()
+                .try_into()
+                .map_err(|_| eap_too_long)?,
+        };
+
+        let server_addr = self
+            .ip_addr
+            .as_str()
+            .try_into()
+            .map_err(|_| "The server address must be at most 63 bytes long")?;
+        if !self.ip_addr.is_empty() && !uplink::is_supported_address(&self.ip_addr) {
+            return Err(
+                "The server address must start with tcp://, udp://, tls://, http:// or https://",
+            );
+        }
+
+        if self.queue_capacity as usize > PSRAM_QUEUE_CAPACITY {
+            return Err("The frames queue capacity must be at most 16384");
+        }
+        let storage =
+            Storage::from_name(&self.storage).ok_or("The storage must be auto, sd or flash")?;
+
+        let config = ConnectionConfig {
+            ssid,
+            password,
+            auth_method,
+            eap,
+            server_addr,
+            uplink: self.validate_uplink()?,
+            netif: self.validate_netif()?,
+            https: self.https,
+            queue_capacity: self.queue_capacity,
+            storage,
+            sd_card_detect: self.sd_card_detect,
+            time_settings: self.validate_time()?,
+            browser_time: (self.time > 0).then_some(self.time),
+        };
+        // Without the authentication method, the credentials are checked once
+        // it has been detected
+        if let Some(auth_method) = auth_method {
+            config.check_credentials(auth_method)?;
+        }
+
+        Ok(config)
+    }
+
+    /// Validate the settings of the uplink, `None` to keep the current ones
+    /// when none is given.
+    fn validate_uplink(&self) -> Result<Option<UplinkSettings>, &'static str> {
+        let settings = UplinkSettings {
+            influx_org: self.influx_org.trim().to_string(),
+            influx_bucket: self.influx_bucket.trim().to_string(),
+            influx_token: self.influx_token.trim().to_string(),
+            server_cert: self.server_cert.trim().to_string(),
+        };
+        if settings == UplinkSettings::default() && !self.clear_uplink {
+            return Ok(None);
+        }
+
+        if uplink::is_influx_address(&self.ip_addr) && !settings.has_influx_credentials() {
+            return Err("The InfluxDB organization, bucket and token are required");
+        }
+        if !settings.server_cert.is_empty()
+            && (!settings
+                .server_cert
+                .starts_with("-----BEGIN CERTIFICATE-----")
+                || !settings.server_cert.ends_with("-----END CERTIFICATE-----"))
+        {
+            return Err("The server certificate must be PEM encoded");
+        }
+        if settings.server_cert.len() > SERVER_CERT_MAX_LEN {
+            return Err("The server certificate must be at most 2048 bytes long");
+        }
+
+        Ok(Some(settings))
+    }
+
+    /// Validate the NTP servers and the timezone.
+    fn validate_time(&self) -> Result<TimeSettings, &'static str> {
+        let settings = TimeSettings {
+            ntp_servers: self.ntp_servers.trim().to_string(),
+            timezone: self.timezone.trim().to_string(),
+        };
+        if settings.ntp_servers.len() > NTP_SERVERS_MAX_LEN
+            || settings.servers().count() > NTP_SERVERS_MAX
+        {
+            return Err("At most 3 NTP servers are allowed, 127 bytes long in total");
+        }
+        if settings.servers().any(|server| {
+            !server
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'.' | b'-' | b':'))
+        }) {
+            return Err("The NTP servers must be host names or IP addresses, comma separated");
+        }
+        if settings.timezone.len() > TIMEZONE_MAX_LEN
+            || !settings
+                .timezone
+                .bytes()
+                .all(|byte| byte.is_ascii_graphic())
+        {
+            return Err("The timezone must be a POSIX TZ string of at most 63 characters");
+        }
+
+        Ok(settings)
+    }
+
+    /// Validate the settings of the station interface.
+    fn validate_netif(&self) -> Result<NetifSettings, &'static str> {
+        let mut settings = NetifSettings::default();
+        if !self.hostname.is_empty() {
+            if !netif::is_valid_hostname(&self.hostname) {
+                return Err("The hostname must be at most 32 letters, digits or hyphens, not starting or ending with a hyphen");
+            }
+            settings.hostname = self.hostname.as_str().try_into().unwrap();
+        }
+
+        if self.static_ip.is_empty() {
+            return Ok(settings);
+        }
+        let ip: Ipv4Addr = self
+            .static_ip
+            .parse()
+            .map_err(|_| "Invalid static IP address")?;
+        let gateway: Ipv4Addr = self
+            .gateway
+            .parse()
+            .map_err(|_| "Invalid gateway address")?;
+        let prefix = netif::parse_netmask(&self.netmask).ok_or("Invalid netmask")?;
+        let dns = if self.dns.is_empty() {
+            gateway
+        } else {
+            self.dns.parse().map_err(|_| "Invalid DNS server address")?
+        };
+        settings.static_ip = Some(StaticIp {
+            ip,
+            gateway,
+            prefix,
+            dns,
+        });
+
+        Ok(settings)
+    }
+}
+
+/// Status of the last provisioning, reported to the configuration page.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum ProvisionStatus {
+    /// No configuration has been submitted since boot
+    Idle,
+    /// The new configuration is being applied
+    Connecting,
+    /// Connected with the new configuration
+    Connected { ip: String },
+    /// The new configuration could not be applied
+    Failed { reason: String },
+}
+
+#[derive(Serialize)]
+/// Access point found by a Wi-Fi scan.
+struct ScanResult {
+    ssid: String,
+    rssi: i8,
+    channel: u8,
+    auth_method: String,
 }
 
 /// Handle the GET request for the index page.
@@ -37,50 +400,301 @@ pub fn get_request_handler(req: Request<&mut EspHttpConnection>) -> Result<(), E
 /// Handle the POST request for the form data.
 pub fn post_request_handler(
     mut req: Request<&mut EspHttpConnection>,
-    connection_config_sender: &SyncSender<(
-        heapless::String<32>,
-        heapless::String<64>,
-        heapless::String<63>,
-    )>,
+    connection_config_sender: &SyncSender<ConnectionConfig>,
 ) -> Result<(), Error> {
+    // The admin password is chosen at the first provisioning, then it is
+    // required to change the configuration. Until then, only the clients of
+    // the access point may change it, see `auth::may_claim_admin`
+    let admin_set = auth::is_admin_set();
+    if admin_set && let Err(e) = auth::authorize(&mut req) {
+        return auth::write_auth_error(req, e);
+    }
+    if !admin_set && !auth::may_claim_admin(&mut req) {
+        return write_error(req, 403, ADMIN_CLAIM_REFUSED);
+    }
+
     let len = req.content_len().unwrap_or(0) as usize;
 
     if len > MAX_LEN {
-        req.into_status_response(413)?
-            .write_all("Request too big".as_bytes())?;
-        return Ok(());
+        return write_error(req, 413, "Request too big");
     }
 
     let mut buf = vec![0; len];
     req.read_exact(&mut buf)?;
-    let mut resp = req.into_ok_response()?;
 
-    if let Ok(form) = serde_json::from_slice::<FormData>(&buf) {
-        info!(
-            "Wi-Fi SSID: {}, Password: {}, Ip Address: {}",
-            form.wifi_ssid, form.wifi_pass, form.ip_addr
-        );
+    let form = match serde_json::from_slice::<FormData>(&buf) {
+        Ok(form) => form,
+        Err(e) => return write_error(req, 400, &format!("Invalid JSON: {}", e)),
+    };
+    let config = match form.validate() {
+        Ok(config) => config,
+        Err(e) => return write_error(req, 400, e),
+    };
+
+    if (!admin_set || !form.admin_password.is_empty())
+        && let Err(e) = auth::validate_admin_password(&form.admin_password)
+    {
+        return write_error(req, 400, e);
+    }
+
+    // The credentials are not logged
+    info!(
+        "Wi-Fi SSID: {}, Auth method: {:?}, Ip Address: {}",
+        config.ssid, config.auth_method, config.server_addr
+    );
+
+    if !form.admin_password.is_empty() {
+        auth::set_admin_password(&form.admin_password)?;
+        info!("Admin password changed");
+    }
+
+    submit_config(req, config, connection_config_sender)
+}
+
+/// Send the configuration to the request handler thread and answer with the
+/// provisioning status.
+pub(crate) fn submit_config(
+    req: Request<&mut EspHttpConnection>,
+    config: ConnectionConfig,
+    connection_config_sender: &SyncSender<ConnectionConfig>,
+) -> Result<(), Error> {
+    if connection_config_sender.try_send(config).is_err() {
+        return write_error(req, 503, "A configuration is already being applied");
+    }
+    let status = ProvisionStatus::Connecting;
+    let gs = crate::utilities::global_state::GlobalState::get();
+    *gs.provision_status.lock().unwrap() = status.clone();
+
+    write_json(req, 202, &serde_json::to_string(&status)?)
+}
+
+/// Handle the GET request for the list of the nearby access points.
+pub fn wifi_scan_handler(req: Request<&mut EspHttpConnection>) -> Result<(), Error> {
+    let gs = crate::utilities::global_state::GlobalState::get();
+    let scan = gs.wifi.lock().unwrap().as_mut().map(|wifi| wifi.scan());
+
+    let mut access_points = match scan {
+        Some(Ok(access_points)) => access_points,
+        Some(Err(e)) => return write_error(req, 503, &format!("Wi-Fi scan failed: {}", e)),
+        None => return write_error(req, 503, "Wi-Fi not initialized"),
+    };
 
-        let ssid: heapless::String<32> = form.wifi_ssid.try_into().unwrap();
-        let pwd: heapless::String<64> = form.wifi_pass.try_into().unwrap();
-        let ip: heapless::String<63> = form.ip_addr.try_into().unwrap();
+    // Strongest first, hidden networks and duplicated SSIDs (other BSSIDs of
+    // the same network) are skipped
+    access_points.sort_by(|a, b| b.signal_strength.cmp(&a.signal_strength));
+    let mut results: Vec<ScanResult> = Vec::new();
+    for access_point in access_points {
+        if access_point.ssid.is_empty()
+            || results.iter().any(|r| r.ssid == access_point.ssid.as_str())
+        {
+            continue;
+        }
+        results.push(ScanResult {
+            ssid: access_point.ssid.to_string(),
+            rssi: access_point.signal_strength,
+            channel: access_point.channel,
+            auth_method: access_point
+                .auth_method
+                .map(|auth_method| format!("{:?}", auth_method))
+                .unwrap_or_default(),
+        });
+    }
+
+    write_json(req, 200, &serde_json::to_string(&results)?)
+}
+
+/// Handle the GET request for the status of the last provisioning.
+pub fn provision_status_handler(req: Request<&mut EspHttpConnection>) -> Result<(), Error> {
+    let gs = crate::utilities::global_state::GlobalState::get();
+    let status = gs.provision_status.lock().unwrap().clone();
+
+    write_json(req, 200, &serde_json::to_string(&status)?)
+}
+
+/// Status of the connection to the server, reported to the configuration page.
+#[derive(Serialize)]
+struct UplinkStatus {
+    connected: bool,
+    /// Consecutive failed connections or writes
+    failures: u32,
+    #[serde(flatten)]
+    stats: UplinkStats,
+}
 
-        connection_config_sender.send((ssid, pwd, ip)).unwrap();
+/// Handle the GET request for the status of the connection to the server.
+pub fn uplink_status_handler(req: Request<&mut EspHttpConnection>) -> Result<(), Error> {
+    let gs = crate::utilities::global_state::GlobalState::get();
+    let status = UplinkStatus {
+        connected: gs.uplink.lock().unwrap().is_some(),
+        failures: gs.uplink_backoff.lock().unwrap().failures(),
+        stats: *gs.uplink_stats.lock().unwrap(),
+    };
+
+    write_json(req, 200, &serde_json::to_string(&status)?)
+}
+
+/// Status of the clock, reported to the configuration page.
+#[derive(Serialize)]
+struct ClockStatus {
+    /// The clock has been set, by SNTP or a fallback source
+    synced: bool,
+    source: Option<ClockSource>,
+    /// Status of the SNTP synchronization: reset, in_progress or completed
+    sntp: &'static str,
+    /// Time in ms since the UNIX epoch
+    time: Option<u64>,
+    /// Time in the configured timezone
+    local_time: Option<String>,
+    timezone: String,
+}
+
+/// Handle the GET request for the status of the clock.
+pub fn clock_status_handler(req: Request<&mut EspHttpConnection>) -> Result<(), Error> {
+    let gs = crate::utilities::global_state::GlobalState::get();
+    let sntp = match gs
+        .sntp
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|sntp| sntp.get_sync_status())
+    {
+        Some(SyncStatus::Completed) => "completed",
+        Some(SyncStatus::InProgress) => "in_progress",
+        Some(SyncStatus::Reset) | None => "reset",
+    };
+    let status = ClockStatus {
+        synced: clock::is_set(),
+        source: clock::source(),
+        sntp,
+        time: clock::is_set().then(clock::now),
+        local_time: clock::local_time(),
+        timezone: TimeSettings::load().unwrap_or_default().timezone,
+    };
+
+    write_json(req, 200, &serde_json::to_string(&status)?)
+}
+
+/// Write a JSON response with the given status code.
+pub(crate) fn write_json(
+    req: Request<&mut EspHttpConnection>,
+    status: u16,
+    body: &str,
+) -> Result<(), Error> {
+    req.into_response(status, None, &[("Content-Type", "application/json")])?
+        .write_all(body.as_bytes())?;
+    Ok(())
+}
+
+/// Write a JSON error response with the given status code.
+pub(crate) fn write_error(
+    req: Request<&mut EspHttpConnection>,
+    status: u16,
+    message: &str,
+) -> Result<(), Error> {
+    write_json(
+        req,
+        status,
+        &serde_json::json!({ "error": message }).to_string(),
+    )
+}
+
+/// Detect the authentication method of the network with a scan.
+fn detect_auth_method(
+    wifi: &mut BlockingWifi<EspWifi<'static>>,
+    ssid: &str,
+) -> Result<AuthMethod, Error> {
+    let access_points = wifi.scan().context("Wi-Fi scan failed")?;
+    access_points
+        .iter()
+        .filter(|access_point| access_point.ssid == ssid)
+        .max_by_key(|access_point| access_point.signal_strength)
+        .map(|access_point| access_point.auth_method.unwrap_or(AuthMethod::None))
+        .ok_or_else(|| {
+            anyhow::anyhow!("Network not found, select its authentication method manually")
+        })
+}
+
+/// Set the new Wi-Fi configuration and connect to it.
+fn connect_wifi(
+    wifi: &mut BlockingWifi<EspWifi<'static>>,
+    config: &ConnectionConfig,
+) -> Result<(), Error> {
+    let auth_method = match config.auth_method {
+        Some(auth_method) => auth_method,
+        None => {
+            let auth_method = detect_auth_method(wifi, &config.ssid)?;
+            info!("Detected authentication method: {:?}", auth_method);
+            auth_method
+        }
+    };
+    config
+        .check_credentials(auth_method)
+        .map_err(|e| anyhow::anyhow!(e))?;
+
+    // The credentials of enterprise networks are not stored by the Wi-Fi driver
+    utilities::wifi::store_eap_credentials(&config.eap)
+        .context("Failed to store the WPA2-Enterprise credentials")?;
+    if auth_method == AuthMethod::WPA2Enterprise {
+        utilities::wifi::enable_enterprise(&config.eap)
+            .context("Failed to enable WPA2-Enterprise")?;
     } else {
-        resp.write_all("JSON error".as_bytes())?;
+        utilities::wifi::disable_enterprise();
     }
 
+    let new_config = wifi::Configuration::Mixed(
+        ClientConfiguration {
+            ssid: config.ssid.clone(),
+            bssid: None,
+            auth_method: utilities::wifi::auth_threshold(auth_method),
+            // The password of open and enterprise networks is not used
+            password: config.password.clone(),
+            channel: None,
+        },
+        AccessPointConfiguration {
+            ssid: SSID.try_into().unwrap(),
+            ssid_hidden: false,
+            channel: 0,
+            ..Default::default()
+        },
+    );
+
+    wifi.set_configuration(&new_config)
+        .context("Invalid Wi-Fi configuration")?;
+    netif::apply(wifi, &config.netif)?;
+    config
+        .netif
+        .store()
+        .context("Failed to store the station interface settings")?;
+    wifi.connect()
+        .context("Failed to connect to the Wi-Fi network")?;
+    wifi.wait_netif_up()
+        .context("Failed to get an IP address")?;
+
     Ok(())
 }
 
+/// Check if the Wi-Fi part of the configuration differs from the one in use,
+/// or if the station is not connected.
+fn wifi_changed(wifi: &BlockingWifi<EspWifi<'static>>, config: &ConnectionConfig) -> bool {
+    let client = match wifi.get_configuration() {
+        Ok(wifi::Configuration::Mixed(client, _)) | Ok(wifi::Configuration::Client(client)) => {
+            client
+        }
+        _ => return true,
+    };
+    let auth_changed = config.auth_method.map_or(false, |auth_method| {
+        utilities::wifi::auth_threshold(auth_method) != client.auth_method
+    });
+    client.ssid != config.ssid
+        || client.password != config.password
+        || auth_changed
+        || utilities::wifi::load_eap_credentials().map_or(true, |eap| eap != config.eap)
+        || NetifSettings::load().map_or(true, |netif| netif != config.netif)
+        || !wifi.is_connected().unwrap_or(false)
+}
+
 /// Loop that handles the requests from the HTTP server.
-pub fn request_handler_thread(
-    receiver: std::sync::mpsc::Receiver<(
-        heapless::String<32>,
-        heapless::String<64>,
-        heapless::String<63>,
-    )>,
-) {
+pub fn request_handler_thread(receiver: std::sync::mpsc::Receiver<ConnectionConfig>) {
     let gs = crate::utilities::global_state::GlobalState::get();
 
     info!("HTTP server request handler thread started");
@@ -89,79 +703,168 @@ pub fn request_handler_thread(
         thread::sleep(Duration::from_millis(10));
 
         match receiver.try_recv() {
-            Ok((ssid, password, new_ip)) => {
+            Ok(config) => {
+                // ----- //
+                // Clock //
+                // ----- //
+                // The time of the browser is a fallback when NTP is blocked
+                if let Some(time) = config.browser_time {
+                    clock::set_fallback_time(time, ClockSource::Browser);
+                }
+                // Stored before SNTP is started again with the new servers, the
+                // timezone is applied at boot, the change needs a restart
+                let time_settings = TimeSettings::load().unwrap_or_default();
+                if config.time_settings != time_settings {
+                    match config.time_settings.store() {
+                        Ok(()) if config.time_settings.timezone != time_settings.timezone => {
+                            info!(
+                                "Timezone: {}, restart to apply",
+                                config.time_settings.timezone
+                            )
+                        }
+                        Ok(()) => {}
+                        Err(e) => warn!("Failed to store the time settings: {:?}", e),
+                    }
+                }
+
+                // -------------------------- //
+                // TCP connection reconfigure //
+                // -------------------------- //
+                // Stored before the Wi-Fi is reconfigured, the connection is
+                // opened again once the Wi-Fi is up
+                let new_ip = config.server_addr;
+                let mut buffer: [u8; 64] = [0; 64];
+                let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+                let old_ip = nvs
+                    .get_str("Server IP", &mut buffer)
+                    .unwrap()
+                    .unwrap_or_default()
+                    .to_string();
+                let ip_changed = !new_ip.is_empty() && new_ip != old_ip;
+                if ip_changed {
+                    info!("New IP address: {}", new_ip);
+                    nvs.set_str("Server IP", &new_ip).unwrap();
+                }
+                drop(nvs);
+
+                let mut uplink_changed = false;
+                if let Some(settings) = config.uplink {
+                    match settings.store() {
+                        Ok(()) => uplink_changed = true,
+                        Err(e) => warn!("Failed to store the uplink settings: {:?}", e),
+                    }
+                }
+
+                // ----- //
+                // HTTPS //
+                // ----- //
+                // The server is started at boot, the change needs a restart
+                if config.https != utilities::tls::is_enabled() {
+                    match utilities::tls::set_enabled(config.https) {
+                        Ok(()) => info!("HTTPS enabled: {}, restart to apply", config.https),
+                        Err(e) => warn!("Failed to store the HTTPS setting: {:?}", e),
+                    }
+                }
+
+                // ------------ //
+                // Frames queue //
+                // ------------ //
+                // The queue is allocated at boot, the change needs a restart
+                if config.queue_capacity != utilities::frame_queue::stored_capacity() {
+                    match utilities::frame_queue::set_capacity(config.queue_capacity) {
+                        Ok(()) => info!(
+                            "Frames queue capacity: {}, restart to apply",
+                            config.queue_capacity
+                        ),
+                        Err(e) => warn!("Failed to store the frames queue capacity: {:?}", e),
+                    }
+                }
+
+                // ------- //
+                // Storage //
+                // ------- //
+                // The storage is opened at boot, the change needs a restart
+                if config.storage != Storage::load() {
+                    match config.storage.store() {
+                        Ok(()) => info!(
+                            "Frames storage: {}, restart to apply",
+                            config.storage.name()
+                        ),
+                        Err(e) => warn!("Failed to store the frames storage: {:?}", e),
+                    }
+                }
+                // The card-detect pin is configured at boot
+                if config.sd_card_detect != utilities::sd_card::has_card_detect() {
+                    match utilities::sd_card::set_card_detect(config.sd_card_detect) {
+                        Ok(()) => info!(
+                            "SD card-detect switch: {}, restart to apply",
+                            config.sd_card_detect
+                        ),
+                        Err(e) => warn!("Failed to store the SD card-detect setting: {:?}", e),
+                    }
+                }
+
                 // ---------------- //
                 // WIFI reconfigure //
                 // ---------------- //
-                // Disconnect from the current Wi-Fi
+                *gs.provision_status.lock().unwrap() = ProvisionStatus::Connecting;
+
                 let mut wifi_option_lock = gs.wifi.lock().unwrap();
                 let wifi_lock = wifi_option_lock.as_mut().unwrap();
-                let _ = wifi_lock.disconnect();
-
-                info!("Initilizing Wi-Fi with new configuration");
-
-                let new_config = wifi::Configuration::Mixed(
-                    ClientConfiguration {
-                        ssid,
-                        bssid: None,
-                        auth_method: AuthMethod::WPA2Personal,
-                        password,
-                        channel: None,
-                    },
-                    AccessPointConfiguration {
-                        ssid: SSID.try_into().unwrap(),
-                        ssid_hidden: false,
-                        channel: 0,
-                        ..Default::default()
-                    },
-                );
-
-                wifi_lock.set_configuration(&new_config).unwrap();
-
-                if let Err(e) = wifi_lock.connect() {
-                    info!("Failed to connect to Wi-Fi: {:?}", e);
-                    continue;
+                // The connection is kept when only the other settings changed
+                let wifi_changed = wifi_changed(wifi_lock, &config);
+                if wifi_changed {
+                    // Disconnect from the current Wi-Fi
+                    let _ = wifi_lock.disconnect();
+
+                    info!("Initilizing Wi-Fi with new configuration");
+
+                    if let Err(e) = connect_wifi(wifi_lock, &config) {
+                        warn!("Failed to apply the new Wi-Fi configuration: {:#}", e);
+                        *gs.provision_status.lock().unwrap() = ProvisionStatus::Failed {
+                            reason: format!("{:#}, the other settings were saved", e),
+                        };
+                        continue;
+                    }
+                    info!("Connected to Wi-Fi");
+                } else {
+                    info!("Wi-Fi not changed");
                 }
-                info!("Connected to Wi-Fi");
 
-                wifi_lock.wait_netif_up().unwrap();
+                let ip = wifi_lock
+                    .wifi()
+                    .sta_netif()
+                    .get_ip_info()
+                    .map(|ip_info| ip_info.ip.to_string())
+                    .unwrap_or_default();
+                *gs.provision_status.lock().unwrap() = ProvisionStatus::Connected { ip };
+
+                if wifi_changed {
+                    // Advertise the hub with the new hostname
+                    utilities::mdns::announce();
+                }
 
-                // Start SNTP service
-                let sntp = sntp::EspSntp::new_default().expect("Failed to initialize SNTP");
+                // Start SNTP service, with the new servers
+                let sntp = utilities::clock::start_sntp();
                 info!("SNTP initialized");
                 // Keeping it around or else the SNTP service will stop
                 gs.sntp.lock().unwrap().replace(sntp);
 
-                // -------------------------- //
-                // TCP connection reconfigure //
-                // -------------------------- //
-                let mut buffer: [u8; 63] = [0; 63];
-                gs.nvs_connect_configs_ns
-                    .lock()
-                    .unwrap()
-                    .get_str("Server IP", &mut buffer)
-                    .unwrap();
-                let old_ip = std::str::from_utf8(&buffer).unwrap();
-                if new_ip != old_ip {
-                    info!("New IP address: {}", new_ip);
-                    gs.nvs_connect_configs_ns
-                        .lock()
-                        .unwrap()
-                        .set_str("Server IP", &new_ip)
-                        .unwrap();
-
+                if wifi_changed || ip_changed || uplink_changed {
                     // Shutdown the previous connection
                     crate::utilities::tcp_client::shutdown();
                     // Connect to the new IP address
                     crate::utilities::tcp_client::connect();
                 } else {
-                    info!("IP address not changed, still: {}", new_ip);
+                    info!("Server not changed, still: {}", old_ip);
                 }
 
                 // ------- //
                 // ESP-NOW //
                 // ------- //
-                utilities::espnow::reconfigure_broadcast(wifi_lock);
+                if wifi_changed {
+                    utilities::espnow::reconfigure_broadcast(wifi_lock);
+                }
             }
             Err(err) => {
                 if let std::sync::mpsc::TryRecvError::Empty = err {
@@ -0,0 +1,270 @@
+use core::ffi::{c_char, CStr};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError};
+
+use embedded_svc::ws::FrameType;
+use esp_idf_svc::{
+    http::server::ws::{EspHttpWsConnection, EspHttpWsDetachedSender},
+    sys::{esp, httpd_req_get_hdr_value_len, httpd_req_get_hdr_value_str, EspError, ESP_FAIL},
+};
+use firmware::{
+    definitions::{message_values, Message},
+    utilities::timestamp,
+};
+use log::{info, warn};
+use messages::Frame;
+use serde::{Deserialize, Serialize};
+
+use super::{auth, constants::LIVE_STREAM_MAX_CLIENTS, global_state::GlobalState};
+
+/// Max length of a subscription message
+const MAX_FILTER_LEN: usize = 512;
+
+/// Devices and messages a client subscribed to, all of them when empty.
+///
+/// Sent by the client as a text message, e.g.
+/// `{"devices": [0, 2], "messages": ["Temperature"]}`.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+struct Filter {
+    devices: Vec<u8>,
+    messages: Vec<String>,
+}
+
+impl Filter {
+    fn matches(&self, device_id: u8, message: &str) -> bool {
+        (self.devices.is_empty() || self.devices.contains(&device_id))
+            && (self.messages.is_empty() || self.messages.iter().any(|name| name == message))
+    }
+}
+
+/// Clients of the live stream.
+#[derive(Default)]
+pub struct LiveClients {
+    /// Senders of the clients, taken by [`sender_task`] while it sends: sending
+    /// waits for the HTTP server task, that may be waiting for the lock
+    senders: Vec<EspHttpWsDetachedSender>,
+    /// Subscriptions of the connected clients, by session
+    filters: HashMap<i32, Filter>,
+}
+
+/// Field of a frame pushed to the clients.
+#[derive(Serialize)]
+struct LiveField {
+    name: String,
+    value: f64,
+    unit: Option<String>,
+}
+
+/// Frame pushed to the clients, as JSON.
+#[derive(Serialize)]
+struct LiveFrame {
+    device: u8,
+    message: String,
+    /// Time in ms since the UNIX epoch, `None` while the clock is not set
+    timestamp: Option<u64>,
+    fields: Vec<LiveField>,
+}
+
+/// Handle the WebSocket connections of the live stream: register the new
+/// clients, forget the closed ones and update the subscriptions.
+/// Once the admin password is set, the handshake must give it with HTTP basic
+/// authentication, the browsers send the credentials of the page.
+pub fn ws_handler(ws: &mut EspHttpWsConnection) -> Result<(), EspError> {
+    let gs = GlobalState::get();
+    let session = ws.session();
+
+    if ws.is_new() {
+        if auth::is_admin_set()
+            && let Err(e) =
+                auth::authorize_header(handshake_authorization(ws).as_deref(), handshake_client(ws))
+        {
+            warn!("Live stream session {} refused: {:?}", session, e);
+            ws.send(FrameType::Close, &[])?;
+            return Err(EspError::from_infallible::<ESP_FAIL>());
+        }
+
+        let mut clients = gs.live_clients.lock().unwrap();
+        if clients.filters.len() >= LIVE_STREAM_MAX_CLIENTS {
+            drop(clients);
+            warn!("Too many live stream clients, session {} refused", session);
+            return ws.send(FrameType::Close, &[]);
+        }
+        clients.senders.push(ws.create_detached_sender()?);
+        clients.filters.insert(session, Filter::default());
+        info!("Live stream client connected, session {}", session);
+        return Ok(());
+    }
+    if ws.is_closed() {
+        let mut clients = gs.live_clients.lock().unwrap();
+        clients.senders.retain(|sender| sender.session() != session);
+        clients.filters.remove(&session);
+        info!("Live stream client disconnected, session {}", session);
+        return Ok(());
+    }
+
+    // Length of the message, then the message
+    let (_, len) = ws.recv(&mut [])?;
+    if len > MAX_FILTER_LEN {
+        // The message is not read, the session can not be used anymore
+        warn!("Live stream subscription too long: {} bytes", len);
+        ws.send(FrameType::Close, &[])?;
+        return Err(EspError::from_infallible::<ESP_FAIL>());
+    }
+    let mut buffer = [0u8; MAX_FILTER_LEN];
+    let (frame_type, len) = ws.recv(&mut buffer)?;
+    if !matches!(frame_type, FrameType::Text(_)) {
+        return Ok(());
+    }
+    // The message may be NUL terminated
+    let message = buffer[..len].strip_suffix(&[0]).unwrap_or(&buffer[..len]);
+    match serde_json::from_slice::<Filter>(message) {
+        Ok(filter) => {
+            info!("Live stream session {} subscribed to {:?}", session, filter);
+            let mut clients = gs.live_clients.lock().unwrap();
+            if let Some(client_filter) = clients.filters.get_mut(&session) {
+                *client_filter = filter;
+            }
+        }
+        Err(e) => warn!("Invalid live stream subscription: {:?}", e),
+    }
+    Ok(())
+}
+
+/// Address of the client of the WebSocket handshake.
+fn handshake_client(ws: &EspHttpWsConnection) -> IpAddr {
+    match ws {
+        EspHttpWsConnection::New(_, raw_req) => auth::client_address(*raw_req),
+        _ => Ipv4Addr::UNSPECIFIED.into(),
+    }
+}
+
+/// `Authorization` header of the WebSocket handshake.
+fn handshake_authorization(ws: &EspHttpWsConnection) -> Option<String> {
+    let EspHttpWsConnection::New(_, raw_req) = ws else {
+        return None;
+    };
+    let name = CStr::from_bytes_with_nul(b"Authorization\0").unwrap();
+    let len = unsafe { httpd_req_get_hdr_value_len(*raw_req, name.as_ptr()) };
+    if len == 0 {
+        return None;
+    }
+    // With the NUL terminator
+    let mut buffer = vec![0u8; len + 1];
+    esp!(unsafe {
+        httpd_req_get_hdr_value_str(
+            *raw_req,
+            name.as_ptr(),
+            buffer.as_mut_ptr() as *mut c_char,
+            buffer.len(),
+        )
+    })
+    .ok()?;
+    buffer.truncate(len);
+    String::from_utf8(buffer).ok()
+}
+
+/// Queue the frames received from the slaves for the clients of the live
+/// stream. Never blocks: sending waits for the clients, the frames are
+/// dropped when the sending thread is behind.
+pub fn publish(frames: &[Frame], sender: &SyncSender<Vec<Frame>>) {
+    let gs = GlobalState::get();
+    if frames.is_empty() || gs.live_clients.lock().unwrap().filters.is_empty() {
+        return;
+    }
+    match sender.try_send(frames.to_vec()) {
+        Ok(()) => {}
+        Err(TrySendError::Full(frames)) => {
+            warn!("Live stream behind, {} frames dropped", frames.len())
+        }
+        Err(TrySendError::Disconnected(_)) => warn!("Live stream thread stopped"),
+    }
+}
+
+/// Thread sending the frames queued by [`publish`] to the clients.
+pub fn sender_task(receiver: Receiver<Vec<Frame>>) {
+    for frames in receiver {
+        send(&frames);
+    }
+}
+
+/// Send the frames to the clients subscribed to them. The clients that can
+/// not be reached are forgotten.
+fn send(frames: &[Frame]) {
+    let gs = GlobalState::get();
+    let mut clients = gs.live_clients.lock().unwrap();
+    if clients.filters.is_empty() {
+        return;
+    }
+    let mut senders = std::mem::take(&mut clients.senders);
+    let filters = clients.filters.clone();
+    drop(clients);
+
+    for frame in frames {
+        let Ok(message) = Message::try_from(frame) else {
+            continue;
+        };
+        let Ok((name, Some(device_id), fields)) = message_values(&message) else {
+            continue;
+        };
+        let is_subscribed = |session: i32| {
+            filters
+                .get(&session)
+                .is_some_and(|filter| filter.matches(device_id, &name))
+        };
+        if !senders.iter().any(|sender| is_subscribed(sender.session())) {
+            continue;
+        }
+
+        let live_frame = LiveFrame {
+            device: device_id,
+            message: name.clone(),
+            timestamp: Some(frame.timestamp()).filter(|&ts| !timestamp::is_relative(ts)),
+            fields: fields
+                .into_iter()
+                .map(|field| LiveField {
+                    name: field.name,
+                    value: field.value,
+                    unit: field.unit,
+                })
+                .collect(),
+        };
+        let json = match serde_json::to_string(&live_frame) {
+            Ok(json) => json,
+            Err(e) => {
+                warn!("Failed to serialize the live frame: {:?}", e);
+                continue;
+            }
+        };
+
+        senders.retain_mut(|sender| {
+            if !is_subscribed(sender.session()) {
+                return true;
+            }
+            match sender.send(FrameType::Text(false), json.as_bytes()) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!(
+                        "Live stream session {} unreachable: {:?}",
+                        sender.session(),
+                        e
+                    );
+                    false
+                }
+            }
+        });
+    }
+
+    // Give the senders back, after the ones of the clients connected in the
+    // meantime
+    let mut clients = gs.live_clients.lock().unwrap();
+    for session in filters.keys() {
+        if !senders.iter().any(|sender| sender.session() == *session) {
+            clients.filters.remove(session);
+        }
+    }
+    // The clients closed in the meantime have no filter anymore
+    senders.retain(|sender| clients.filters.contains_key(&sender.session()));
+    clients.senders.append(&mut senders);
+}
@@ -0,0 +1,54 @@
+use anyhow::Error;
+use esp_idf_svc::mdns::EspMdns;
+use log::{info, warn};
+
+use super::{
+    constants::{FIRMWARE_VERSION, HTTPS_PORT, HTTP_PORT, MDNS_INSTANCE_NAME},
+    global_state::GlobalState,
+    netif::NetifSettings,
+};
+
+/// Advertise the hub and its services with mDNS.
+/// Must be called after every new connection, the responder is restarted so
+/// that the hub is announced again on the network.
+pub fn announce() {
+    let gs = GlobalState::get();
+    let mut mdns = gs.mdns.lock().unwrap();
+
+    // Stop the previous responder before taking a new one
+    mdns.take();
+    match start() {
+        Ok(responder) => {
+            mdns.replace(responder);
+        }
+        Err(e) => warn!("Failed to start mDNS: {:?}", e),
+    }
+}
+
+/// Start the mDNS responder with the hostname and the services of the hub.
+fn start() -> Result<EspMdns, Error> {
+    let gs = GlobalState::get();
+    let hostname = NetifSettings::load()?.hostname;
+    let devices = gs
+        .nvs_connect_configs_ns
+        .lock()
+        .unwrap()
+        .get_u8("Num of slaves")?
+        .unwrap_or(0)
+        .to_string();
+    let txt = [("devices", devices.as_str()), ("version", FIRMWARE_VERSION)];
+
+    let mut mdns = EspMdns::take()?;
+    mdns.set_hostname(&hostname)?;
+    mdns.set_instance_name(MDNS_INSTANCE_NAME)?;
+    if *gs.https_enabled.lock().unwrap() {
+        mdns.add_service(None, "_https", "_tcp", HTTPS_PORT, &txt)?;
+        mdns.add_service(None, "_smarthome", "_tcp", HTTPS_PORT, &txt)?;
+    } else {
+        mdns.add_service(None, "_http", "_tcp", HTTP_PORT, &txt)?;
+        mdns.add_service(None, "_smarthome", "_tcp", HTTP_PORT, &txt)?;
+    }
+    info!("mDNS started, hostname: {}.local", hostname);
+
+    Ok(mdns)
+}
@@ -0,0 +1,151 @@
+use anyhow::Error;
+use esp_idf_hal::io::Write;
+use esp_idf_svc::http::server::{EspHttpConnection, Request};
+use firmware::{
+    definitions::{message_values, Message},
+    utilities::metrics::{write_metric, LatestValues},
+};
+use log::warn;
+use messages::Frame;
+
+use super::{auth, constants::METRICS_MAX_SERIES, espnow, global_state::GlobalState, telemetry};
+
+/// Latest values of the fields, empty until the first frame.
+pub fn latest_values() -> LatestValues {
+    LatestValues::new(METRICS_MAX_SERIES)
+}
+
+/// Keep the values of the frames received from the slaves, for the next
+/// scrape.
+pub fn record(frames: &[Frame]) {
+    if frames.is_empty() {
+        return;
+    }
+    let gs = GlobalState::get();
+    let mut latest_values = gs.latest_values.lock().unwrap();
+    for frame in frames {
+        let Ok(message) = Message::try_from(frame) else {
+            continue;
+        };
+        let Ok((name, Some(device_id), fields)) = message_values(&message) else {
+            continue;
+        };
+        for field in fields {
+            if !latest_values.update(
+                device_id,
+                &name,
+                &field.name,
+                field.unit.as_deref(),
+                field.value,
+            ) {
+                warn!(
+                    "Too many fields for the metrics, {} of {} ignored",
+                    field.name, name
+                );
+            }
+        }
+    }
+}
+
+/// Handle the GET request of Prometheus, with the latest values of the fields
+/// and the counters of the hub. Once the admin password is set, the scrape
+/// must give it with HTTP basic authentication (`basic_auth` in Prometheus).
+pub fn metrics_handler(mut req: Request<&mut EspHttpConnection>) -> Result<(), Error> {
+    if auth::is_admin_set()
+        && let Err(e) = auth::authorize(&mut req)
+    {
+        return auth::write_auth_error(req, e);
+    }
+
+    let gs = GlobalState::get();
+    let mut body = String::new();
+    gs.latest_values.lock().unwrap().write(&mut body);
+
+    let espnow = espnow::stats();
+    let uplink = *gs.uplink_stats.lock().unwrap();
+    let counters = [
+        (
+            "smarthome_hub_uptime_seconds",
+            "gauge",
+            "Seconds since the boot",
+            telemetry::uptime() as f64,
+        ),
+        (
+            "smarthome_hub_free_heap_bytes",
+            "gauge",
+            "Free heap memory",
+            telemetry::free_heap() as f64,
+        ),
+        (
+            "smarthome_hub_espnow_received_total",
+            "counter",
+            "Data received from the slaves",
+            espnow.received as f64,
+        ),
+        (
+            "smarthome_hub_espnow_dropped_total",
+            "counter",
+            "Data from the slaves dropped because the hub was behind",
+            espnow.dropped as f64,
+        ),
+        (
+            "smarthome_hub_espnow_pending",
+            "gauge",
+            "Data from the slaves waiting to be processed",
+            espnow.pending as f64,
+        ),
+        (
+            "smarthome_hub_uplink_connected",
+            "gauge",
+            "Whether the connection to the server is open",
+            gs.uplink.lock().unwrap().is_some() as u8 as f64,
+        ),
+        (
+            "smarthome_hub_uplink_connections_total",
+            "counter",
+            "Connections opened to the server",
+            uplink.connections as f64,
+        ),
+        (
+            "smarthome_hub_uplink_sent_total",
+            "counter",
+            "Frames delivered to the server",
+            uplink.sent as f64,
+        ),
+        (
+            "smarthome_hub_uplink_failed_total",
+            "counter",
+            "Frames refused by the server or not convertible to points",
+            uplink.failed as f64,
+        ),
+        (
+            "smarthome_hub_uplink_queued",
+            "gauge",
+            "Frames waiting to be sent again",
+            uplink.queued as f64,
+        ),
+        (
+            "smarthome_hub_buffered",
+            "gauge",
+            "Frames kept in memory while neither the server nor the storage are available",
+            uplink.buffered as f64,
+        ),
+        (
+            "smarthome_hub_dropped_total",
+            "counter",
+            "Frames dropped because the memory queue was full",
+            uplink.dropped as f64,
+        ),
+    ];
+    for (name, kind, help, value) in counters {
+        write_metric(&mut body, name, kind, help, value);
+    }
+
+    req.into_response(
+        200,
+        None,
+        &[("Content-Type", "text/plain; version=0.0.4; charset=utf-8")],
+    )?
+    .write_all(body.as_bytes())?;
+    Ok(())
+}
@@ -1,6 +1,21 @@
+pub mod auth;
+pub mod backup;
+pub mod captive_portal;
+pub mod clock;
 pub mod constants;
 pub mod espnow;
+pub mod factory_reset;
+pub mod frame_queue;
 pub mod global_state;
 pub mod http_server;
+pub mod live_stream;
+pub mod mdns;
+pub mod metrics;
+pub mod netif;
+pub mod sd_card;
+pub mod storage;
 pub mod tcp_client;
+pub mod telemetry;
+pub mod tls;
+pub mod uplink;
 pub mod wifi;
@@ -0,0 +1,155 @@
+use std::net::Ipv4Addr;
+
+use anyhow::{Context, Error};
+use esp_idf_svc::ipv4::{self, ClientSettings, DHCPClientSettings, Mask, Subnet};
+use esp_idf_svc::netif::{EspNetif, NetifConfiguration};
+use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
+use log::info;
+
+use super::{constants::HOSTNAME, global_state::GlobalState};
+
+/// NVS keys of the station interface settings
+const HOSTNAME_KEY: &str = "Hostname";
+const STATIC_IP_KEY: &str = "Static IP";
+const GATEWAY_KEY: &str = "Gateway";
+const PREFIX_KEY: &str = "Netmask";
+const DNS_KEY: &str = "DNS";
+
+/// Static IPv4 configuration of the station interface.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StaticIp {
+    pub ip: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    /// Netmask as a prefix length
+    pub prefix: u8,
+    pub dns: Ipv4Addr,
+}
+
+/// Settings of the station interface.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetifSettings {
+    pub hostname: heapless::String<32>,
+    /// Static IPv4 configuration, `None` to use DHCP
+    pub static_ip: Option<StaticIp>,
+}
+
+impl Default for NetifSettings {
+    fn default() -> Self {
+        Self {
+            hostname: HOSTNAME.try_into().unwrap(),
+            static_ip: None,
+        }
+    }
+}
+
+impl NetifSettings {
+    /// Load the settings stored in the NVS, the defaults are used for the
+    /// missing ones.
+    pub fn load() -> Result<Self, Error> {
+        let gs = GlobalState::get();
+        let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+        let mut settings = Self::default();
+
+        // One more byte for the null terminator
+        let mut buffer = [0u8; 33];
+        if let Some(hostname) = nvs.get_str(HOSTNAME_KEY, &mut buffer)?
+            && !hostname.is_empty()
+        {
+            settings.hostname = hostname.try_into().unwrap_or(settings.hostname);
+        }
+
+        // An address of 0 means that DHCP is used
+        let ip = nvs.get_u32(STATIC_IP_KEY)?.unwrap_or(0);
+        if ip != 0 {
+            let gateway = nvs.get_u32(GATEWAY_KEY)?.unwrap_or(0);
+            settings.static_ip = Some(StaticIp {
+                ip: Ipv4Addr::from(ip),
+                gateway: Ipv4Addr::from(gateway),
+                prefix: nvs.get_u8(PREFIX_KEY)?.unwrap_or(24),
+                dns: Ipv4Addr::from(nvs.get_u32(DNS_KEY)?.unwrap_or(gateway)),
+            });
+        }
+
+        Ok(settings)
+    }
+
+    /// Store the settings in the NVS.
+    pub fn store(&self) -> Result<(), Error> {
+        let gs = GlobalState::get();
+        let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+        nvs.set_str(HOSTNAME_KEY, &self.hostname)?;
+        match &self.static_ip {
+            Some(static_ip) => {
+                nvs.set_u32(STATIC_IP_KEY, static_ip.ip.into())?;
+                nvs.set_u32(GATEWAY_KEY, static_ip.gateway.into())?;
+                nvs.set_u8(PREFIX_KEY, static_ip.prefix)?;
+                nvs.set_u32(DNS_KEY, static_ip.dns.into())?;
+            }
+            None => {
+                nvs.set_u32(STATIC_IP_KEY, 0)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Configuration of the station interface with these settings.
+    fn netif_configuration(&self) -> NetifConfiguration {
+        let ip_configuration = match &self.static_ip {
+            Some(static_ip) => ipv4::ClientConfiguration::Fixed(ClientSettings {
+                ip: static_ip.ip,
+                subnet: Subnet {
+                    gateway: static_ip.gateway,
+                    mask: Mask(static_ip.prefix),
+                },
+                dns: Some(static_ip.dns),
+                secondary_dns: None,
+            }),
+            None => ipv4::ClientConfiguration::DHCP(DHCPClientSettings::default()),
+        };
+
+        NetifConfiguration {
+            ip_configuration: Some(ipv4::Configuration::Client(ip_configuration)),
+            ..NetifConfiguration::wifi_default_client()
+        }
+    }
+}
+
+/// Replace the station interface with one configured with the settings.
+/// Must be called while the station is disconnected.
+pub fn apply(
+    wifi: &mut BlockingWifi<EspWifi<'static>>,
+    settings: &NetifSettings,
+) -> Result<(), Error> {
+    info!("Applying station interface settings: {:?}", settings);
+    let mut netif = EspNetif::new_with_conf(&settings.netif_configuration())
+        .context("Failed to create the station interface")?;
+    netif
+        .set_hostname(&settings.hostname)
+        .context("Failed to set the hostname")?;
+    wifi.wifi_mut()
+        .swap_netif_sta(netif)
+        .context("Failed to replace the station interface")?;
+    Ok(())
+}
+
+/// Parse a netmask, either as a prefix length (`24`) or in dotted notation
+/// (`255.255.255.0`), returning the prefix length.
+pub fn parse_netmask(netmask: &str) -> Option<u8> {
+    if let Ok(prefix) = netmask.parse::<u8>() {
+        return (prefix <= 32).then_some(prefix);
+    }
+    let bits = u32::from(netmask.parse::<Ipv4Addr>().ok()?);
+    // The ones of the mask must be contiguous
+    (bits.leading_ones() + bits.trailing_zeros() == 32).then_some(bits.leading_ones() as u8)
+}
+
+/// Check that the hostname is a valid DNS label.
+pub fn is_valid_hostname(hostname: &str) -> bool {
+    !hostname.is_empty()
+        && hostname.len() <= 32
+        && !hostname.starts_with('-')
+        && !hostname.ends_with('-')
+        && hostname
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
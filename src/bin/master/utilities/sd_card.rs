@@ -0,0 +1,93 @@
+use std::time::SystemTime;
+
+use anyhow::Error;
+use esp_idf_hal::gpio::{AnyInputPin, Input, PinDriver};
+use firmware::utilities::frame_store::{FrameStore, StoreUsage};
+use messages::Frame;
+use telegraf::{Metric, Point};
+
+use super::{clock, constants::SD_RETRY_INTERVAL, global_state::GlobalState};
+
+/// NVS key of the card-detect switch setting
+const CARD_DETECT_KEY: &str = "SD card detect";
+
+/// Usage of the SD card, reported to the server.
+#[derive(Metric)]
+#[measurement = "hub_sd_card"]
+struct SdCardMetric {
+    capacity: u64,
+    used: u64,
+    free: u64,
+    backlog: u64,
+}
+
+/// Check if the SD card slot has a card-detect switch on GPIO8, closed to the
+/// ground when a card is inserted. Off by default: without the switch the
+/// pin reads high, and the card would never be mounted.
+pub fn has_card_detect() -> bool {
+    let gs = GlobalState::get();
+    let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+    matches!(nvs.get_u8(CARD_DETECT_KEY), Ok(Some(1)))
+}
+
+/// Use the card-detect switch or poll the card, from the next restart.
+pub fn set_card_detect(enabled: bool) -> Result<(), Error> {
+    let gs = GlobalState::get();
+    let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+    nvs.set_u8(CARD_DETECT_KEY, enabled as u8)?;
+    Ok(())
+}
+
+/// Detection of the insertion and the removal of the SD card.
+pub struct CardDetect<'d> {
+    /// Card-detect switch, closed to the ground when a card is inserted
+    pin: Option<PinDriver<'d, AnyInputPin, Input>>,
+    last_poll: Option<SystemTime>,
+}
+
+impl<'d> CardDetect<'d> {
+    /// Without the card-detect switch, the card is polled.
+    pub fn new(pin: Option<PinDriver<'d, AnyInputPin, Input>>) -> Self {
+        Self {
+            pin,
+            last_poll: None,
+        }
+    }
+
+    /// Check if a card is inserted, `None` when it is not yet time to poll.
+    /// Without the switch, the mounted card is asked if it still answers,
+    /// and a card is assumed to be inserted when none is mounted so that
+    /// mounting it is tried again.
+    pub fn is_inserted(&mut self, mounted: Option<&mut (dyn FrameStore + '_)>) -> Option<bool> {
+        if let Some(pin) = &self.pin {
+            return Some(pin.is_low());
+        }
+
+        if self
+            .last_poll
+            .is_some_and(|last_poll| last_poll.elapsed().unwrap_or_default() < SD_RETRY_INTERVAL)
+        {
+            return None;
+        }
+        self.last_poll = Some(SystemTime::now());
+        Some(mounted.map_or(true, |store| store.is_present()))
+    }
+}
+
+/// Frame of the SD card status message, sent to the server.
+pub fn status_frame(mounted: bool) -> Frame {
+    let message = firmware::SdCardStatusMessage::new().with_sd_card_status(mounted);
+    let frame: Frame = message.into();
+    frame.set_timestamp(clock::now())
+}
+
+/// Point reporting the usage of the SD card to the server.
+pub fn usage_point(usage: StoreUsage) -> Point {
+    SdCardMetric {
+        capacity: usage.capacity,
+        used: usage.used,
+        free: usage.capacity.saturating_sub(usage.used),
+        backlog: usage.backlog,
+    }
+    .to_point()
+}
@@ -0,0 +1,110 @@
+use std::fmt::Debug;
+
+use anyhow::Error;
+use embedded_sdmmc::SdMmcSpi;
+use firmware::utilities::{flash_store::FlashStore, frame_store::FrameStore, sd::SD};
+use log::{info, warn};
+
+use super::global_state::GlobalState;
+
+/// NVS key of the storage of the frames waiting for the server
+const STORAGE_KEY: &str = "Storage";
+
+/// Storage of the frames that could not be sent to the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Storage {
+    /// The SD card if there is one at boot, else the internal flash
+    Auto = 0,
+    SdCard = 1,
+    Flash = 2,
+}
+
+impl Storage {
+    /// Name of the storage, in the configuration form and the backups.
+    pub fn name(self) -> &'static str {
+        match self {
+            Storage::Auto => "auto",
+            Storage::SdCard => "sd",
+            Storage::Flash => "flash",
+        }
+    }
+
+    /// Parse the name of a storage, empty for the default.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "" | "auto" => Some(Storage::Auto),
+            "sd" => Some(Storage::SdCard),
+            "flash" => Some(Storage::Flash),
+            _ => None,
+        }
+    }
+
+    /// Storage stored in the NVS.
+    pub fn load() -> Self {
+        let gs = GlobalState::get();
+        let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+        match nvs.get_u8(STORAGE_KEY) {
+            Ok(Some(1)) => Storage::SdCard,
+            Ok(Some(2)) => Storage::Flash,
+            _ => Storage::Auto,
+        }
+    }
+
+    /// Store the storage, from the next restart.
+    pub fn store(self) -> Result<(), Error> {
+        let gs = GlobalState::get();
+        let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+        nvs.set_u8(STORAGE_KEY, self as u8)?;
+        Ok(())
+    }
+}
+
+/// Open the SD card.
+pub fn open_sd<'a, DR, CS>(spi_device: &'a mut SdMmcSpi<DR, CS>) -> Option<Box<dyn FrameStore + 'a>>
+where
+    CS: embedded_hal_0_2::digital::v2::OutputPin + 'a,
+    DR: embedded_hal_0_2::blocking::spi::Transfer<u8> + 'a,
+    DR::Error: Debug,
+{
+    match SD::new(spi_device) {
+        Ok(sd) => Some(Box::new(sd)),
+        Err(e) => {
+            warn!("Failed to open the SD card: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Open the internal flash.
+pub fn open_flash<'a>() -> Option<Box<dyn FrameStore + 'a>> {
+    match FlashStore::new() {
+        Ok(flash) => Some(Box::new(flash)),
+        Err(e) => {
+            warn!("Failed to open the internal flash storage: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Open the storage of the frames, with the one actually used: the SD card or
+/// the flash. `None` if it is not available.
+pub fn open<'a, DR, CS>(
+    storage: Storage,
+    spi_device: &'a mut SdMmcSpi<DR, CS>,
+) -> Option<(Box<dyn FrameStore + 'a>, Storage)>
+where
+    CS: embedded_hal_0_2::digital::v2::OutputPin + 'a,
+    DR: embedded_hal_0_2::blocking::spi::Transfer<u8> + 'a,
+    DR::Error: Debug,
+{
+    info!("Frames storage: {}", storage.name());
+    let sd = match storage {
+        Storage::Auto | Storage::SdCard => open_sd(spi_device),
+        Storage::Flash => None,
+    };
+    match sd {
+        Some(sd) => Some((sd, Storage::SdCard)),
+        None if storage != Storage::SdCard => open_flash().map(|flash| (flash, Storage::Flash)),
+        None => None,
+    }
+}
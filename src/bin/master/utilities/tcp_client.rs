@@ -1,23 +1,43 @@
+use std::time::Instant;
+
 use log::{info, warn};
-use telegraf::Client;
+use messages::Frame;
+use telegraf::Point;
 
-use crate::utilities::constants::TCP_SERVER_ADDR;
+use crate::utilities::{
+    clock::{self, ClockSource},
+    constants::{TCP_SERVER_ADDR, UPLINK_BATCH_SIZE},
+    global_state::GlobalState,
+    uplink::{self, Uplink, UplinkError, UplinkSettings},
+};
 
-/// Connect to the TCP server with the IP address stored in the NVS.
+/// Connect to the server with the address stored in the NVS.
+/// A plain TCP or UDP server that can not be reached is replaced by the
+/// default one, a server using TLS or the InfluxDB write API is kept and
+/// retried after the backoff.
 pub fn connect() {
-    let gs = crate::utilities::global_state::GlobalState::get();
-    let mut buffer: [u8; 63] = [0; 63];
-    gs.nvs_connect_configs_ns
+    let gs = GlobalState::get();
+    let mut buffer: [u8; 64] = [0; 64];
+    let address = gs
+        .nvs_connect_configs_ns
         .lock()
         .unwrap()
         .get_str("Server IP", &mut buffer)
-        .unwrap();
-    let ip = std::str::from_utf8(&buffer).unwrap();
+        .unwrap()
+        .unwrap_or(TCP_SERVER_ADDR)
+        .to_string();
+    let settings = UplinkSettings::load().unwrap_or_else(|e| {
+        warn!("Failed to load the uplink settings: {:?}", e);
+        UplinkSettings::default()
+    });
 
-    info!("About to open a TCP connection with ip: {}", ip);
-    let mut connect = Client::new(ip);
-    if connect.is_err() {
-        warn!("Failed to connect to the TCP server: {:?}", connect.err());
+    info!("About to open a connection with the server: {}", address);
+    let mut connect = Uplink::connect(&address, &settings);
+    if connect.is_err() && address != TCP_SERVER_ADDR && !uplink::is_secure_address(&address) {
+        warn!(
+            "Failed to connect to the server: {:?}",
+            connect.as_ref().err()
+        );
         info!(
             "Trying with default TCP server address: {}",
             TCP_SERVER_ADDR
@@ -28,21 +48,145 @@ pub fn connect() {
             .set_str("Server IP", TCP_SERVER_ADDR)
             .unwrap();
 
-        connect = Client::new(TCP_SERVER_ADDR);
+        connect = Uplink::connect(TCP_SERVER_ADDR, &settings);
     }
-    if let Ok(stream) = connect {
-        // Save the TCP stream in the global state
-        gs.tcp_stream.lock().unwrap().replace(stream);
-    } else {
-        warn!(
-            "Failed to connect to the default TCP server: {:?}",
-            connect.err()
-        );
+    match connect {
+        // Save the connection in the global state
+        Ok(uplink) => {
+            gs.uplink.lock().unwrap().replace(uplink);
+            gs.uplink_stats.lock().unwrap().connections += 1;
+        }
+        Err(e) => {
+            warn!("Failed to connect to the server: {:?}", e);
+            let delay = gs.uplink_backoff.lock().unwrap().on_failure(Instant::now());
+            info!("Next connection attempt in {:?}", delay);
+        }
+    }
+}
+
+/// Connect to the server if the backoff after the last failure has elapsed.
+pub fn try_connect() {
+    let gs = GlobalState::get();
+    let is_ready = gs.uplink_backoff.lock().unwrap().is_ready(Instant::now());
+    if is_ready {
+        connect();
+    }
+}
+
+/// Send the frames to the server in batches, returning the ones that could
+/// not be sent, to be stored in the SD card.
+/// A broken connection is closed, it is opened again by [`try_connect`].
+/// The frames are held until the clock is set, to be sent with the
+/// wall-clock time.
+pub fn send(frames: Vec<Frame>) -> Vec<Frame> {
+    let gs = GlobalState::get();
+    let mut uplink = gs.uplink.lock().unwrap();
+    let Some(stream) = uplink.as_mut().filter(|_| clock::is_set()) else {
+        gs.uplink_stats.lock().unwrap().queued = frames.len() as u64;
+        return frames;
+    };
+    let frames = clock::to_wall_clock(frames);
+
+    if !frames.is_empty() {
+        info!("Sending {} frames to the server", frames.len());
+    }
+    for (index, batch) in frames.chunks(UPLINK_BATCH_SIZE).enumerate() {
+        let mut points = Vec::with_capacity(batch.len());
+        for frame in batch {
+            match frame.to_point() {
+                Ok(point) => points.push(point),
+                Err(_) => {
+                    warn!(
+                        "Failed to convert the frame {:?} to InfluxDB line protocol",
+                        frame
+                    );
+                    gs.uplink_stats.lock().unwrap().failed += 1;
+                }
+            }
+        }
+        if points.is_empty() {
+            continue;
+        }
+
+        let result = stream.write_points(&points);
+        let mut stats = gs.uplink_stats.lock().unwrap();
+        match result {
+            Ok(()) => {
+                stats.sent += points.len() as u64;
+                gs.uplink_backoff.lock().unwrap().reset();
+            }
+            Err(UplinkError::Rejected(e)) => {
+                warn!("The server refused {} points: {:?}", points.len(), e);
+                stats.failed += points.len() as u64;
+            }
+            Err(UplinkError::Connection(e)) => {
+                warn!("Failed to send data to the server: {:?}", e);
+                let unsent = frames[index * UPLINK_BATCH_SIZE..].to_vec();
+                stats.queued = unsent.len() as u64;
+                drop(stats);
+
+                // Close the connection, it is opened again after the backoff
+                *uplink = None;
+                let delay = gs.uplink_backoff.lock().unwrap().on_failure(Instant::now());
+                info!("Reconnecting to the server in {:?}", delay);
+                return unsent;
+            }
+        }
+    }
+    gs.uplink_stats.lock().unwrap().queued = 0;
+
+    Vec::new()
+}
+
+/// Set the clock from the time of the server, when NTP is blocked.
+pub fn sync_clock() {
+    let gs = GlobalState::get();
+    let mut uplink = gs.uplink.lock().unwrap();
+    let Some(stream) = uplink.as_mut() else {
+        return;
+    };
+
+    match stream.server_date() {
+        Ok(Some(date)) => clock::set_fallback_time(date, ClockSource::Http),
+        Ok(None) => {}
+        Err(UplinkError::Rejected(e)) => warn!("Failed to get the time of the server: {:?}", e),
+        Err(UplinkError::Connection(e)) => {
+            warn!("Failed to get the time of the server: {:?}", e);
+            // Close the connection, it is opened again after the backoff
+            *uplink = None;
+            let delay = gs.uplink_backoff.lock().unwrap().on_failure(Instant::now());
+            info!("Reconnecting to the server in {:?}", delay);
+        }
+    }
+}
+
+/// Send a point reporting the state of the hub, returning if it has been
+/// delivered or refused by the server, in both cases it must not be sent again.
+pub fn send_point(point: Point) -> bool {
+    let gs = GlobalState::get();
+    let mut uplink = gs.uplink.lock().unwrap();
+    let Some(stream) = uplink.as_mut() else {
+        return false;
+    };
+
+    match stream.write_points(&[point]) {
+        Ok(()) => true,
+        Err(UplinkError::Rejected(e)) => {
+            warn!("The server refused the point: {:?}", e);
+            true
+        }
+        Err(UplinkError::Connection(e)) => {
+            warn!("Failed to send data to the server: {:?}", e);
+            // Close the connection, it is opened again after the backoff
+            *uplink = None;
+            gs.uplink_backoff.lock().unwrap().on_failure(Instant::now());
+            false
+        }
     }
 }
 
 pub fn shutdown() {
-    let gs = crate::utilities::global_state::GlobalState::get();
-    // Close the TCP stream by dropping it
-    gs.tcp_stream.lock().unwrap().take();
+    let gs = GlobalState::get();
+    // Close the connection by dropping it
+    gs.uplink.lock().unwrap().take();
 }
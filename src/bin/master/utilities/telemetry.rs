@@ -0,0 +1,74 @@
+use esp_idf_hal::reset::ResetReason;
+use esp_idf_svc::sys::{
+    esp, esp_get_free_heap_size, esp_get_minimum_free_heap_size, esp_timer_get_time,
+    esp_wifi_sta_get_ap_info, heap_caps_get_free_size, wifi_ap_record_t, MALLOC_CAP_SPIRAM,
+};
+use telegraf::{Metric, Point};
+
+use super::{constants::FIRMWARE_VERSION, espnow, global_state::GlobalState};
+
+/// Health of the hub, reported to the server.
+#[derive(Metric)]
+#[measurement = "hub_health"]
+struct HubHealthMetric {
+    #[telegraf(tag)]
+    version: String,
+    /// Seconds since the boot
+    uptime: u64,
+    reset_reason: String,
+    free_heap: u64,
+    min_free_heap: u64,
+    free_psram: u64,
+    /// 0 while disconnected from the access point
+    rssi: i64,
+    channel: u64,
+    espnow_received: u64,
+    espnow_dropped: u64,
+    /// Data received from the ESP-NOW and not yet processed
+    espnow_pending: u64,
+    /// Bytes waiting in the SD card or the flash
+    storage_backlog: u64,
+    /// Connections opened to the server since the boot
+    uplink_connections: u64,
+}
+
+/// Point reporting the health of the hub to the server, with the bytes
+/// waiting in the storage.
+pub fn health_point(storage_backlog: u64) -> Point {
+    let gs = GlobalState::get();
+    let espnow = espnow::stats();
+
+    let mut ap_info = wifi_ap_record_t::default();
+    let rssi = if esp!(unsafe { esp_wifi_sta_get_ap_info(&mut ap_info) }).is_ok() {
+        ap_info.rssi as i64
+    } else {
+        0
+    };
+
+    HubHealthMetric {
+        version: FIRMWARE_VERSION.to_string(),
+        uptime: uptime(),
+        reset_reason: format!("{:?}", ResetReason::get()),
+        free_heap: free_heap(),
+        min_free_heap: unsafe { esp_get_minimum_free_heap_size() } as u64,
+        free_psram: unsafe { heap_caps_get_free_size(MALLOC_CAP_SPIRAM) } as u64,
+        rssi,
+        channel: gs.espnow_channel.lock().unwrap().unwrap_or_default() as u64,
+        espnow_received: espnow.received as u64,
+        espnow_dropped: espnow.dropped as u64,
+        espnow_pending: espnow.pending as u64,
+        storage_backlog,
+        uplink_connections: gs.uplink_stats.lock().unwrap().connections,
+    }
+    .to_point()
+}
+
+/// Seconds since the boot.
+pub fn uptime() -> u64 {
+    (unsafe { esp_timer_get_time() } / 1_000_000) as u64
+}
+
+/// Free internal and external memory, in bytes.
+pub fn free_heap() -> u64 {
+    unsafe { esp_get_free_heap_size() as u64 }
+}
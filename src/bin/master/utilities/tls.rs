@@ -0,0 +1,226 @@
+use core::ffi::{c_int, c_void, CStr};
+use std::ffi::CString;
+
+use anyhow::{anyhow, bail, Context, Error};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use esp_idf_svc::sys::*;
+use log::info;
+use sha2::{Digest, Sha256};
+
+use super::{global_state::GlobalState, netif::NetifSettings};
+
+/// NVS keys of the HTTPS settings and certificate
+const HTTPS_KEY: &str = "HTTPS";
+const CERTIFICATE_KEY: &str = "TLS cert";
+const PRIVATE_KEY_KEY: &str = "TLS key";
+/// Max length of the PEM encoded certificate and private key
+const PEM_MAX_LEN: usize = 2048;
+/// Validity of the self-signed certificate
+// Safety: NUL terminated, without interior NUL
+const NOT_BEFORE: &CStr = unsafe { CStr::from_bytes_with_nul_unchecked(b"20240101000000\0") };
+const NOT_AFTER: &CStr = unsafe { CStr::from_bytes_with_nul_unchecked(b"20991231235959\0") };
+
+/// Self-signed certificate of the HTTPS server.
+pub struct ServerCertificate {
+    /// PEM encoded certificate, null terminated
+    pub certificate: CString,
+    /// PEM encoded private key, null terminated
+    pub private_key: CString,
+}
+
+impl ServerCertificate {
+    /// SHA-256 fingerprint of the certificate, to be checked by the users on
+    /// the first connection.
+    pub fn fingerprint(&self) -> Result<String, Error> {
+        let pem = self.certificate.to_str()?;
+        let base64: String = pem
+            .lines()
+            .filter(|line| !line.starts_with("-----"))
+            .collect();
+        let der = BASE64.decode(base64)?;
+        let fingerprint = Sha256::digest(der);
+        Ok(fingerprint
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(":"))
+    }
+}
+
+/// Check if the HTTPS server is enabled.
+pub fn is_enabled() -> bool {
+    let gs = GlobalState::get();
+    let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+    matches!(nvs.get_u8(HTTPS_KEY), Ok(Some(1)))
+}
+
+/// Enable or disable the HTTPS server, from the next restart.
+pub fn set_enabled(enabled: bool) -> Result<(), Error> {
+    let gs = GlobalState::get();
+    let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+    nvs.set_u8(HTTPS_KEY, enabled as u8)?;
+    Ok(())
+}
+
+/// Load the certificate of the device from the NVS, generating it on the
+/// first boot.
+pub fn load_or_generate() -> Result<ServerCertificate, Error> {
+    let gs = GlobalState::get();
+    let mut certificate_buffer = vec![0u8; PEM_MAX_LEN];
+    let mut private_key_buffer = vec![0u8; PEM_MAX_LEN];
+    {
+        let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+        if let (Some(certificate), Some(private_key)) = (
+            nvs.get_str(CERTIFICATE_KEY, &mut certificate_buffer)?,
+            nvs.get_str(PRIVATE_KEY_KEY, &mut private_key_buffer)?,
+        ) {
+            return Ok(ServerCertificate {
+                certificate: CString::new(certificate)?,
+                private_key: CString::new(private_key)?,
+            });
+        }
+    }
+
+    info!("Generating the HTTPS certificate");
+    let hostname = NetifSettings::load()?.hostname;
+    let certificate = generate(&hostname)?;
+
+    let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+    nvs.set_str(CERTIFICATE_KEY, certificate.certificate.to_str()?)?;
+    nvs.set_str(PRIVATE_KEY_KEY, certificate.private_key.to_str()?)?;
+
+    Ok(certificate)
+}
+
+/// Generate a self-signed certificate with an ECDSA P-256 key.
+fn generate(hostname: &str) -> Result<ServerCertificate, Error> {
+    let name = CString::new(format!("CN={}.local,O=Smart Home Hub", hostname))?;
+    let mut serial = [0u8; 16];
+    unsafe { esp_fill_random(serial.as_mut_ptr().cast(), serial.len()) };
+    // The serial number must be positive
+    serial[0] &= 0x7F;
+
+    let mut entropy = mbedtls_entropy_context::default();
+    let mut ctr_drbg = mbedtls_ctr_drbg_context::default();
+    let mut key = mbedtls_pk_context::default();
+    let mut crt = mbedtls_x509write_cert::default();
+    let mut certificate = vec![0u8; PEM_MAX_LEN];
+    let mut private_key = vec![0u8; PEM_MAX_LEN];
+
+    let result = unsafe {
+        mbedtls_entropy_init(&mut entropy);
+        mbedtls_ctr_drbg_init(&mut ctr_drbg);
+        mbedtls_pk_init(&mut key);
+        mbedtls_x509write_crt_init(&mut crt);
+
+        let rng = &mut ctr_drbg as *mut mbedtls_ctr_drbg_context as *mut c_void;
+        let personalization = b"smarthome-hub";
+        (|| -> Result<(), Error> {
+            check(
+                mbedtls_ctr_drbg_seed(
+                    &mut ctr_drbg,
+                    Some(mbedtls_entropy_func),
+                    &mut entropy as *mut mbedtls_entropy_context as *mut c_void,
+                    personalization.as_ptr(),
+                    personalization.len(),
+                ),
+                "seed the random generator",
+            )?;
+
+            // Key pair
+            check(
+                mbedtls_pk_setup(
+                    &mut key,
+                    mbedtls_pk_info_from_type(mbedtls_pk_type_t_MBEDTLS_PK_ECKEY),
+                ),
+                "setup the key",
+            )?;
+            check(
+                mbedtls_ecp_gen_key(
+                    mbedtls_ecp_group_id_MBEDTLS_ECP_DP_SECP256R1,
+                    key.private_pk_ctx as *mut mbedtls_ecp_keypair,
+                    Some(mbedtls_ctr_drbg_random),
+                    rng,
+                ),
+                "generate the key",
+            )?;
+
+            // Self-signed certificate
+            mbedtls_x509write_crt_set_version(&mut crt, MBEDTLS_X509_CRT_VERSION_3 as c_int);
+            mbedtls_x509write_crt_set_md_alg(&mut crt, mbedtls_md_type_t_MBEDTLS_MD_SHA256);
+            mbedtls_x509write_crt_set_subject_key(&mut crt, &mut key);
+            mbedtls_x509write_crt_set_issuer_key(&mut crt, &mut key);
+            check(
+                mbedtls_x509write_crt_set_subject_name(&mut crt, name.as_ptr()),
+                "set the subject name",
+            )?;
+            check(
+                mbedtls_x509write_crt_set_issuer_name(&mut crt, name.as_ptr()),
+                "set the issuer name",
+            )?;
+            check(
+                mbedtls_x509write_crt_set_serial_raw(&mut crt, serial.as_mut_ptr(), serial.len()),
+                "set the serial number",
+            )?;
+            check(
+                mbedtls_x509write_crt_set_validity(
+                    &mut crt,
+                    NOT_BEFORE.as_ptr(),
+                    NOT_AFTER.as_ptr(),
+                ),
+                "set the validity",
+            )?;
+            check(
+                mbedtls_x509write_crt_set_basic_constraints(&mut crt, 0, -1),
+                "set the basic constraints",
+            )?;
+
+            check(
+                mbedtls_x509write_crt_pem(
+                    &mut crt,
+                    certificate.as_mut_ptr(),
+                    certificate.len(),
+                    Some(mbedtls_ctr_drbg_random),
+                    rng,
+                ),
+                "write the certificate",
+            )?;
+            check(
+                mbedtls_pk_write_key_pem(&mut key, private_key.as_mut_ptr(), private_key.len()),
+                "write the private key",
+            )?;
+            Ok(())
+        })()
+    };
+
+    unsafe {
+        mbedtls_x509write_crt_free(&mut crt);
+        mbedtls_pk_free(&mut key);
+        mbedtls_ctr_drbg_free(&mut ctr_drbg);
+        mbedtls_entropy_free(&mut entropy);
+    }
+    result?;
+
+    Ok(ServerCertificate {
+        certificate: pem_to_cstring(certificate)?,
+        private_key: pem_to_cstring(private_key)?,
+    })
+}
+
+/// Convert the null terminated PEM written by mbed TLS.
+fn pem_to_cstring(mut pem: Vec<u8>) -> Result<CString, Error> {
+    let Some(len) = pem.iter().position(|&byte| byte == 0) else {
+        bail!("PEM not null terminated");
+    };
+    pem.truncate(len);
+    Ok(CString::new(pem)?)
+}
+
+/// Check the return value of an mbed TLS function.
+fn check(result: c_int, action: &str) -> Result<(), Error> {
+    if result < 0 {
+        return Err(anyhow!("mbed TLS error -0x{:04X}", -result))
+            .with_context(|| format!("Failed to {}", action));
+    }
+    Ok(())
+}
@@ -0,0 +1,290 @@
+use core::time::Duration;
+use std::ffi::CString;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use anyhow::{anyhow, bail, Context, Error};
+use esp_idf_svc::tls::{self, EspTls, InternalSocket, X509};
+use firmware::utilities::influx::{InfluxClient, InfluxConfig, InfluxError};
+use serde::Serialize;
+use telegraf::{Client, Point};
+
+use super::global_state::GlobalState;
+
+/// NVS keys of the uplink settings
+const INFLUX_ORG_KEY: &str = "Influx org";
+const INFLUX_BUCKET_KEY: &str = "Influx bucket";
+const INFLUX_TOKEN_KEY: &str = "Influx token";
+const SERVER_CERT_KEY: &str = "Server cert";
+/// Max length of the PEM encoded server certificate
+pub const SERVER_CERT_MAX_LEN: usize = 2048;
+/// Timeout of the uplink connections
+const UPLINK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Default ports of the uplinks
+const DEFAULT_TELEGRAF_PORT: u16 = 8094;
+const DEFAULT_INFLUX_PORT: u16 = 8086;
+
+/// Settings of the uplinks using TLS or the InfluxDB write API.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UplinkSettings {
+    pub influx_org: String,
+    pub influx_bucket: String,
+    pub influx_token: String,
+    /// PEM encoded certificate pinned for the TLS connections, the CA bundle
+    /// is used when empty
+    pub server_cert: String,
+}
+
+impl UplinkSettings {
+    /// Load the settings stored in the NVS.
+    pub fn load() -> Result<Self, Error> {
+        let gs = GlobalState::get();
+        let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+        let mut buffer = vec![0u8; SERVER_CERT_MAX_LEN + 1];
+        let mut get = |key: &str| -> Result<String, Error> {
+            Ok(nvs
+                .get_str(key, &mut buffer)?
+                .unwrap_or_default()
+                .to_string())
+        };
+
+        Ok(Self {
+            influx_org: get(INFLUX_ORG_KEY)?,
+            influx_bucket: get(INFLUX_BUCKET_KEY)?,
+            influx_token: get(INFLUX_TOKEN_KEY)?,
+            server_cert: get(SERVER_CERT_KEY)?,
+        })
+    }
+
+    /// Store the settings in the NVS.
+    pub fn store(&self) -> Result<(), Error> {
+        let gs = GlobalState::get();
+        let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+        nvs.set_str(INFLUX_ORG_KEY, &self.influx_org)?;
+        nvs.set_str(INFLUX_BUCKET_KEY, &self.influx_bucket)?;
+        nvs.set_str(INFLUX_TOKEN_KEY, &self.influx_token)?;
+        nvs.set_str(SERVER_CERT_KEY, &self.server_cert)?;
+        Ok(())
+    }
+
+    /// Check if the settings allow to use the InfluxDB write API.
+    pub fn has_influx_credentials(&self) -> bool {
+        !self.influx_org.is_empty()
+            && !self.influx_bucket.is_empty()
+            && !self.influx_token.is_empty()
+    }
+}
+
+/// Error of a write to the server.
+#[derive(Debug)]
+pub enum UplinkError {
+    /// The connection is broken or the server is unavailable, the points must
+    /// be sent again later
+    Connection(Error),
+    /// The server refused the points, sending them again would fail again
+    Rejected(Error),
+}
+
+impl From<InfluxError> for UplinkError {
+    fn from(error: InfluxError) -> Self {
+        match error {
+            // Rate limited or server errors are temporary
+            InfluxError::Status { code, .. } if code != 429 && code < 500 => {
+                UplinkError::Rejected(error.into())
+            }
+            _ => UplinkError::Connection(error.into()),
+        }
+    }
+}
+
+/// Counters of the frames sent to the server.
+#[derive(Serialize, Clone, Copy, Debug, Default)]
+pub struct UplinkStats {
+    /// Frames delivered to the server
+    pub sent: u64,
+    /// Frames dropped, refused by the server or not convertible to points
+    pub failed: u64,
+    /// Frames of the last attempt waiting to be sent again
+    pub queued: u64,
+    /// Connections opened to the server
+    pub connections: u64,
+    /// Frames kept in memory while neither the server nor the SD card are
+    /// available
+    pub buffered: u64,
+    /// Frames dropped because the memory queue was full
+    pub dropped: u64,
+}
+
+/// Connection used by the InfluxDB client.
+pub enum Transport {
+    Tcp(TcpStream),
+    Tls(EspTls<InternalSocket>),
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tcp(stream) => stream.read(buf),
+            Transport::Tls(tls) => tls.read(buf).map_err(io::Error::other),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tcp(stream) => stream.write(buf),
+            Transport::Tls(tls) => tls.write(buf).map_err(io::Error::other),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Tcp(stream) => stream.flush(),
+            Transport::Tls(_) => Ok(()),
+        }
+    }
+}
+
+/// Connection to the server receiving the data.
+pub enum Uplink {
+    /// Telegraf socket listener, over TCP or UDP (`tcp://` and `udp://`)
+    Telegraf(Client),
+    /// Telegraf socket listener over TLS (`tls://`)
+    TelegrafTls(EspTls<InternalSocket>),
+    /// InfluxDB v2 write API, over HTTP or HTTPS (`http://` and `https://`)
+    Influx(InfluxClient<Transport>),
+}
+
+impl Uplink {
+    /// Open the connection to the server at the address.
+    pub fn connect(address: &str, settings: &UplinkSettings) -> Result<Self, Error> {
+        let (scheme, host, port) = parse_address(address)?;
+        match scheme {
+            "tcp" | "udp" => Ok(Uplink::Telegraf(
+                Client::new(address).map_err(|e| anyhow!("{:?}", e))?,
+            )),
+            "tls" => Ok(Uplink::TelegrafTls(connect_tls(host, port, settings)?)),
+            "http" | "https" => {
+                if !settings.has_influx_credentials() {
+                    bail!("The InfluxDB organization, bucket and token are required");
+                }
+                let transport = if scheme == "https" {
+                    Transport::Tls(connect_tls(host, port, settings)?)
+                } else {
+                    let stream = TcpStream::connect((host, port))?;
+                    stream.set_read_timeout(Some(UPLINK_TIMEOUT))?;
+                    stream.set_write_timeout(Some(UPLINK_TIMEOUT))?;
+                    Transport::Tcp(stream)
+                };
+                let config = InfluxConfig {
+                    host: host.to_string(),
+                    org: settings.influx_org.clone(),
+                    bucket: settings.influx_bucket.clone(),
+                    token: settings.influx_token.clone(),
+                };
+                Ok(Uplink::Influx(InfluxClient::new(transport, config)))
+            }
+            _ => bail!("Unsupported server address: {}", address),
+        }
+    }
+
+    /// Time of the server in ms since the UNIX epoch, asked to the InfluxDB
+    /// server. `None` for Telegraf, that does not give it.
+    pub fn server_date(&mut self) -> Result<Option<u64>, UplinkError> {
+        match self {
+            Uplink::Influx(client) => {
+                client.ping()?;
+                Ok(client.server_date())
+            }
+            Uplink::Telegraf(_) | Uplink::TelegrafTls(_) => Ok(None),
+        }
+    }
+
+    /// Send the points to the server in a single write, one per line.
+    pub fn write_points(&mut self, points: &[Point]) -> Result<(), UplinkError> {
+        match self {
+            Uplink::Telegraf(client) => client
+                .write_points(points)
+                .map_err(|e| UplinkError::Connection(anyhow!("{:?}", e))),
+            Uplink::TelegrafTls(tls) => tls
+                .write_all(to_lines(points).as_bytes())
+                .map_err(|e| UplinkError::Connection(e.into())),
+            Uplink::Influx(client) => Ok(client.write(&to_lines(points))?),
+        }
+    }
+}
+
+/// Line protocol of the points, each one terminated by a new line.
+fn to_lines(points: &[Point]) -> String {
+    let mut lines = String::new();
+    for point in points {
+        lines.push_str(point.to_lp().to_str().trim_end());
+        lines.push('\n');
+    }
+    lines
+}
+
+/// Check if the scheme of the server address is supported.
+pub fn is_supported_address(address: &str) -> bool {
+    ["tcp://", "udp://", "tls://", "http://", "https://"]
+        .iter()
+        .any(|scheme| address.starts_with(scheme))
+}
+
+/// Check if the server address uses the InfluxDB write API.
+pub fn is_influx_address(address: &str) -> bool {
+    address.starts_with("http://") || address.starts_with("https://")
+}
+
+/// Check if the server address uses TLS or the InfluxDB write API. These
+/// connections never fall back to the default server, which would receive
+/// the data in clear without the credentials.
+pub fn is_secure_address(address: &str) -> bool {
+    address.starts_with("tls://") || is_influx_address(address)
+}
+
+/// Split the server address in scheme, host and port.
+fn parse_address(address: &str) -> Result<(&str, &str, u16), Error> {
+    let (scheme, rest) = address
+        .split_once("://")
+        .with_context(|| format!("Invalid server address: {}", address))?;
+    // The path of the URL is not used
+    let authority = rest.split('/').next().unwrap_or_default();
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse()
+                .with_context(|| format!("Invalid port in: {}", address))?,
+        ),
+        None if scheme == "http" || scheme == "https" => (authority, DEFAULT_INFLUX_PORT),
+        None => (authority, DEFAULT_TELEGRAF_PORT),
+    };
+    if host.is_empty() {
+        bail!("Invalid server address: {}", address);
+    }
+    Ok((scheme, host, port))
+}
+
+/// Open a TLS connection, checking the server certificate against the pinned
+/// one or against the CA bundle.
+fn connect_tls(
+    host: &str,
+    port: u16,
+    settings: &UplinkSettings,
+) -> Result<EspTls<InternalSocket>, Error> {
+    let server_cert = CString::new(settings.server_cert.as_str())?;
+    let pinned = !settings.server_cert.is_empty();
+    let config = tls::Config {
+        common_name: Some(host),
+        ca_cert: pinned.then(|| X509::pem(&server_cert)),
+        use_crt_bundle_attach: !pinned,
+        timeout_ms: UPLINK_TIMEOUT.as_millis() as u32,
+        ..Default::default()
+    };
+
+    let mut tls = EspTls::new()?;
+    tls.connect(host, port, &config)
+        .with_context(|| format!("TLS connection to {}:{} failed", host, port))?;
+    Ok(tls)
+}
@@ -1,17 +1,34 @@
 use core::time::Duration;
 use std::{thread, time::SystemTime};
 
-use esp_idf_svc::sntp;
+use anyhow::Error;
+use esp_idf_svc::sys::{
+    esp, esp_eap_client_set_identity, esp_eap_client_set_password, esp_eap_client_set_username,
+    esp_wifi_sta_enterprise_disable, esp_wifi_sta_enterprise_enable,
+};
+use esp_idf_svc::wifi::{AuthMethod, Configuration};
 use log::{info, warn};
 
 use crate::utilities::{self, constants::ESP_NOW_INIT_TIMEOUT};
 
-use super::{constants::WIFI_RETRY_INTERVAL, global_state::GlobalState};
+use super::{
+    constants::WIFI_RETRY_INTERVAL,
+    global_state::GlobalState,
+    http_server::EapCredentials,
+    netif::{self, NetifSettings},
+};
+
+/// NVS keys of the WPA2-Enterprise credentials
+const EAP_IDENTITY_KEY: &str = "EAP identity";
+const EAP_USERNAME_KEY: &str = "EAP username";
+const EAP_PASSWORD_KEY: &str = "EAP password";
 
 /// Task that handles WiFi connection and reconnects if disconnected.
 pub fn connection_task() {
     let gs = GlobalState::get();
     let mut last_try_reconnect: Option<SystemTime> = None;
+    // The stored interface settings are applied before the first connection
+    let mut netif_applied = false;
     loop {
         thread::sleep(Duration::from_millis(500));
 
@@ -19,6 +36,10 @@ pub fn connection_task() {
         if gs.wifi.lock().unwrap().is_none() {
             continue;
         }
+        // Skip if there is no network to join
+        if !is_provisioned() {
+            continue;
+        }
         if !is_connected() {
             // Try to connect
             if last_try_reconnect.is_none()
@@ -31,6 +52,14 @@ pub fn connection_task() {
                 info!("Trying to connect to Wi-Fi");
                 let mut wifi_option_lock = gs.wifi.lock().unwrap();
                 let wifi_lock = wifi_option_lock.as_mut().unwrap();
+                if !netif_applied {
+                    match NetifSettings::load()
+                        .and_then(|settings| netif::apply(wifi_lock, &settings))
+                    {
+                        Ok(()) => netif_applied = true,
+                        Err(e) => warn!("Failed to apply the station interface settings: {:?}", e),
+                    }
+                }
                 match wifi_lock.connect() {
                     Ok(_) => {
                         // Connected
@@ -63,12 +92,15 @@ pub fn connection_task() {
                         // Drop the lock
                         drop(wifi_option_lock);
 
+                        // Announce the hub again on the new connection
+                        utilities::mdns::announce();
+
                         // Start SNTP service if not already started
                         if gs.sntp.lock().unwrap().is_some() {
                             // Already started
                             continue;
                         }
-                        let sntp = sntp::EspSntp::new_default().expect("Failed to initialize SNTP");
+                        let sntp = utilities::clock::start_sntp();
                         // Keeping it around or else the SNTP service will stop
                         gs.sntp.lock().unwrap().replace(sntp);
 
@@ -85,6 +117,18 @@ pub fn connection_task() {
     }
 }
 
+/// Check if the station has been configured with a network to join.
+pub fn is_provisioned() -> bool {
+    let gs = GlobalState::get();
+    let binding = gs.wifi.lock().unwrap();
+    match binding.as_ref().map(|wifi| wifi.get_configuration()) {
+        Some(Ok(Configuration::Mixed(client, _))) | Some(Ok(Configuration::Client(client))) => {
+            !client.ssid.is_empty()
+        }
+        _ => false,
+    }
+}
+
 /// Check if the device is connected to a Wi-Fi network.
 pub fn is_connected() -> bool {
     let gs = GlobalState::get();
@@ -97,3 +141,76 @@ pub fn is_connected() -> bool {
         .is_sta_connected()
         .unwrap_or(false)
 }
+
+/// Minimum authentication method accepted when connecting to a network using
+/// `auth_method`. Mixed networks are joined with the weakest method they accept.
+pub fn auth_threshold(auth_method: AuthMethod) -> AuthMethod {
+    match auth_method {
+        AuthMethod::WPAWPA2Personal => AuthMethod::WPA,
+        AuthMethod::WPA2WPA3Personal => AuthMethod::WPA2Personal,
+        auth_method => auth_method,
+    }
+}
+
+/// Store the WPA2-Enterprise credentials in the NVS, they are not kept by the
+/// Wi-Fi driver.
+pub fn store_eap_credentials(eap: &EapCredentials) -> Result<(), Error> {
+    let gs = GlobalState::get();
+    let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+    nvs.set_str(EAP_IDENTITY_KEY, &eap.identity)?;
+    nvs.set_str(EAP_USERNAME_KEY, &eap.username)?;
+    nvs.set_str(EAP_PASSWORD_KEY, &eap.password)?;
+    Ok(())
+}
+
+/// Load the WPA2-Enterprise credentials stored in the NVS.
+pub fn load_eap_credentials() -> Result<EapCredentials, Error> {
+    let gs = GlobalState::get();
+    let nvs = gs.nvs_connect_configs_ns.lock().unwrap();
+    let mut eap = EapCredentials::default();
+    for (key, value) in [
+        (EAP_IDENTITY_KEY, &mut eap.identity),
+        (EAP_USERNAME_KEY, &mut eap.username),
+        (EAP_PASSWORD_KEY, &mut eap.password),
+    ] {
+        // One more byte for the null terminator
+        let mut buffer = [0u8; 65];
+        if let Some(stored) = nvs.get_str(key, &mut buffer)? {
+            *value = stored.try_into().unwrap_or_default();
+        }
+    }
+    Ok(eap)
+}
+
+/// Set the WPA2-Enterprise (EAP-PEAP) credentials and enable it on the station.
+pub fn enable_enterprise(eap: &EapCredentials) -> Result<(), Error> {
+    // The username is sent as the outer identity if no identity is given
+    let identity = if eap.identity.is_empty() {
+        &eap.username
+    } else {
+        &eap.identity
+    };
+    unsafe {
+        esp!(esp_eap_client_set_identity(
+            identity.as_ptr(),
+            identity.len() as i32
+        ))?;
+        esp!(esp_eap_client_set_username(
+            eap.username.as_ptr(),
+            eap.username.len() as i32
+        ))?;
+        esp!(esp_eap_client_set_password(
+            eap.password.as_ptr(),
+            eap.password.len() as i32
+        ))?;
+        esp!(esp_wifi_sta_enterprise_enable())?;
+    }
+    Ok(())
+}
+
+/// Disable WPA2-Enterprise on the station.
+pub fn disable_enterprise() {
+    unsafe {
+        esp_wifi_sta_enterprise_disable();
+    }
+}
@@ -1,9 +1,9 @@
-use core::sync::atomic::AtomicBool;
-use core::sync::atomic::Ordering;
 use core::time::Duration;
+use std::sync::Mutex;
 
 use dht_sensor::{dht11, DhtReading};
 use embedded_svc::wifi::{ClientConfiguration, Configuration};
+use esp_idf_hal::i2c::{I2cConfig, I2cDriver};
 use esp_idf_hal::{delay, gpio, prelude::*};
 use esp_idf_svc::espnow::{EspNow, PeerInfo, SendStatus};
 use esp_idf_svc::eventloop::EspSystemEventLoop;
@@ -13,11 +13,14 @@ use esp_idf_svc::sys::{
     WIFI_PROTOCOL_11N, WIFI_PROTOCOL_LR,
 };
 use esp_idf_svc::wifi::WifiDriver;
+use firmware::sensors::bme280::{self, Bme280};
+use firmware::sensors::sht3x::{self, Sht3x};
 use firmware::utilities::channel::*;
-use firmware::{FireAlarmMessage, HumidityMessage, TemperatureMessage};
+use firmware::utilities::channel_discovery::ChannelDiscovery;
+use firmware::{FireAlarmMessage, HumidityMessage, PressureMessage, TemperatureMessage};
 use messages::Frame;
 
-static IS_SEARCHING_CHANNEL: AtomicBool = AtomicBool::new(true);
+static DISCOVERY: Mutex<ChannelDiscovery> = Mutex::new(ChannelDiscovery::new());
 
 fn main() {
     // Init
@@ -34,6 +37,16 @@ fn main() {
     // flame sensor
     let flame_pin = gpio::PinDriver::input(peripherals.pins.gpio4).unwrap();
 
+    // I2C environmental sensor (BME280 or SHT3x)
+    let i2c_config = I2cConfig::new().baudrate(100.kHz().into());
+    let mut i2c = I2cDriver::new(
+        peripherals.i2c0,
+        peripherals.pins.gpio8,
+        peripherals.pins.gpio9,
+        &i2c_config,
+    )
+    .unwrap();
+
     // ESP-NOW
 
     // Setup the Wi-Fi driver
@@ -64,13 +77,20 @@ fn main() {
 
     // Create a channel to communicate between threads
     let (sender, reciver) = std::sync::mpsc::sync_channel(10);
-    // Notification to search for a master board on other channels
-    let (channel_search_sender, channel_search_notifier) = std::sync::mpsc::sync_channel(1);
+    // Notification to search for a master board on other channels (`None`)
+    // or to follow the master on the announced channel (`Some`)
+    let (channel_search_sender, channel_search_notifier) = std::sync::mpsc::sync_channel(4);
+    let channel_retune_sender = channel_search_sender.clone();
 
     // register reciving callback, this is used to add the master board to the peer list
     esp_now
-        .register_recv_cb(|mac_address, _data| {
-            IS_SEARCHING_CHANNEL.store(false, Ordering::Relaxed);
+        .register_recv_cb(|mac_address, data| {
+            // Follow the master if it announced a new channel
+            if let Some(channel) = master_channel(data) {
+                if let Some(channel) = DISCOVERY.lock().unwrap().on_master_channel(channel) {
+                    let _ = channel_retune_sender.try_send(Some(channel));
+                }
+            }
             // Convert slice to array
             let mac_address_array = mac_address.try_into().unwrap();
             // If peer does not exist, add it
@@ -86,40 +106,68 @@ fn main() {
         .unwrap();
 
     // Register the send callback, this is used to detect if the master is not reachable
-    let mut num_fail: usize = 0;
     esp_now
         .register_send_cb(|_mac_addres, status| {
             // if a send fails for more than 10 times, start searching for the master board on other channels
-            if let SendStatus::SUCCESS = status {
-                num_fail = 0;
-                IS_SEARCHING_CHANNEL.store(false, Ordering::Relaxed)
-            } else {
-                num_fail = num_fail.checked_add(1).unwrap_or(0);
-            }
-
-            if num_fail > 10 {
-                IS_SEARCHING_CHANNEL.store(true, Ordering::Relaxed);
-                let _ = channel_search_sender.try_send(());
+            let success = matches!(status, SendStatus::SUCCESS);
+            if DISCOVERY.lock().unwrap().on_send_status(success) {
+                let _ = channel_search_sender.try_send(None);
             }
         })
         .unwrap();
 
-    // Create a thread to read the dhtt sensor
-    let sender_dhtt = sender.clone();
+    // Create a thread to read the I2C environmental sensor, or the DHT11 when
+    // there is none, so that a single sensor reports the temperature
+    let sender_env = sender.clone();
     std::thread::spawn(move || {
-        loop {
-            if let Ok(reading) = dht11::Reading::read(&mut delay::Ets, &mut dhtt_pin) {
-                // convert the reading to a message
-                let message_temp = TemperatureMessage::new()
-                    .with_temperature(reading.temperature.try_into().unwrap());
-                let frame: Frame = message_temp.into();
-                // send it to the main task
-                sender_dhtt.send(frame).unwrap();
-                let message_hum = HumidityMessage::new().with_humidity(reading.relative_humidity);
-                let frame: Frame = message_hum.into();
-                sender_dhtt.send(frame).unwrap();
+        if let Ok(mut bme280) = Bme280::new(&mut i2c, bme280::DEFAULT_ADDRESS, &mut delay::FreeRtos)
+        {
+            println!("BME280 found");
+            loop {
+                if let Ok(measurement) = bme280.measure(&mut delay::FreeRtos) {
+                    let message_temp =
+                        TemperatureMessage::new().with_temperature(measurement.temperature);
+                    sender_env.send(message_temp.into()).unwrap();
+                    let message_hum =
+                        HumidityMessage::new().with_humidity(measurement.humidity.round() as u8);
+                    sender_env.send(message_hum.into()).unwrap();
+                    let message_pres = PressureMessage::new().with_pressure(measurement.pressure);
+                    sender_env.send(message_pres.into()).unwrap();
+                }
+                std::thread::sleep(std::time::Duration::from_secs(10));
+            }
+        } else if let Ok(mut sht3x) =
+            Sht3x::new(&mut i2c, sht3x::DEFAULT_ADDRESS, &mut delay::FreeRtos)
+        {
+            println!("SHT3x found");
+            loop {
+                if let Ok(measurement) = sht3x.measure(&mut delay::FreeRtos) {
+                    let message_temp =
+                        TemperatureMessage::new().with_temperature(measurement.temperature);
+                    sender_env.send(message_temp.into()).unwrap();
+                    let message_hum =
+                        HumidityMessage::new().with_humidity(measurement.humidity.round() as u8);
+                    sender_env.send(message_hum.into()).unwrap();
+                }
+                std::thread::sleep(std::time::Duration::from_secs(10));
+            }
+        } else {
+            println!("No I2C environmental sensor found, reading the DHT11");
+            loop {
+                if let Ok(reading) = dht11::Reading::read(&mut delay::Ets, &mut dhtt_pin) {
+                    // convert the reading to a message
+                    let message_temp = TemperatureMessage::new()
+                        .with_temperature(reading.temperature.try_into().unwrap());
+                    let frame: Frame = message_temp.into();
+                    // send it to the main task
+                    sender_env.send(frame).unwrap();
+                    let message_hum =
+                        HumidityMessage::new().with_humidity(reading.relative_humidity);
+                    let frame: Frame = message_hum.into();
+                    sender_env.send(frame).unwrap();
+                }
+                std::thread::sleep(std::time::Duration::from_secs(10));
             }
-            std::thread::sleep(std::time::Duration::from_secs(10));
         }
     });
 
@@ -135,17 +183,19 @@ fn main() {
     });
 
     // Scan for an evailable channel
-    std::thread::spawn(move || {
-        let mut channel = 6;
-        loop {
-            while IS_SEARCHING_CHANNEL.load(Ordering::Relaxed) {
+    std::thread::spawn(move || loop {
+        while DISCOVERY.lock().unwrap().is_searching() {
+            let channel = DISCOVERY.lock().unwrap().next_scan_channel();
+            set_channel(channel);
+            // Stay on the channel, unless the master announces where it is
+            let notification = channel_search_notifier.recv_timeout(Duration::from_secs(5));
+            if let Ok(Some(channel)) = notification {
                 set_channel(channel);
-                // channels 1, 6 and 11 are the most common channels
-                channel = (channel + 5) % 15;
-                std::thread::sleep(Duration::from_secs(5));
             }
-            // channel found, wait untill a notification is received
-            channel_search_notifier.recv().unwrap();
+        }
+        // channel found, wait untill a notification is received
+        if let Some(channel) = channel_search_notifier.recv().unwrap() {
+            set_channel(channel);
         }
     });
 
@@ -1,17 +1,19 @@
-// Slave that is used to read data from LM35 sensor and Gas sensor and send it to the master.
-use core::sync::atomic::AtomicBool;
-use core::sync::atomic::Ordering;
+// Slave that is used to read data from LM35 sensor, Gas sensor and air quality sensors
+// (SCD4x and SGP30) and send it to the master.
+use std::cell::RefCell;
 
+use embedded_hal_bus::i2c::RefCellDevice;
 use embedded_svc::wifi::ClientConfiguration;
 use embedded_svc::wifi::Configuration;
 
 use esp_idf_hal::adc::config::Resolution::Resolution12Bit;
 use esp_idf_hal::adc::*;
-use esp_idf_hal::delay::BLOCK;
+use esp_idf_hal::delay::FreeRtos;
 use esp_idf_hal::gpio::Gpio11;
 use esp_idf_hal::gpio::Gpio5;
+use esp_idf_hal::i2c::{I2cConfig, I2cDriver};
 use esp_idf_hal::peripherals::Peripherals;
-use esp_idf_hal::task::wait_notification;
+use esp_idf_hal::units::FromValueType;
 use esp_idf_svc::espnow::SendStatus;
 use esp_idf_svc::espnow::{EspNow, PeerInfo};
 use esp_idf_svc::eventloop::EspSystemEventLoop;
@@ -22,15 +24,23 @@ use esp_idf_svc::sys::{
 };
 use esp_idf_svc::wifi::WifiDriver;
 
-use firmware::utilities::channel::set_channel;
+use firmware::sensors::scd4x::{self, Scd4x};
+use firmware::sensors::sgp30::{self, Sgp30};
+use firmware::utilities::channel::{master_channel, set_channel};
+use firmware::utilities::channel_discovery::ChannelDiscovery;
+use firmware::CarbonDioxideMessage;
 use firmware::GasLeakageMessage;
 use firmware::TemperatureMessage;
+use firmware::VolatileOrganicCompoundsMessage;
 use messages::Frame;
+use std::sync::Mutex;
 use std::thread;
 use std::time::Duration;
 
-static IS_SEARCHING_CHANNEL: AtomicBool = AtomicBool::new(true);
+static DISCOVERY: Mutex<ChannelDiscovery> = Mutex::new(ChannelDiscovery::new());
 const GAS_THRESHOLD: u16 = 2000;
+/// Interval between two air quality messages (s)
+const AIR_QUALITY_INTERVAL_SECS: u32 = 10;
 
 fn main() {
     // Init
@@ -61,6 +71,19 @@ fn main() {
         AdcChannelDriver::new(peripherals.pins.gpio5).unwrap();
     let mut is_gas_leakage = false;
 
+    // ------------------------------ //
+    //    I2C air quality sensors     //
+    // ------------------------------ //
+
+    let i2c_config = I2cConfig::new().baudrate(100.kHz().into());
+    let i2c = I2cDriver::new(
+        peripherals.i2c0,
+        peripherals.pins.gpio8,
+        peripherals.pins.gpio9,
+        &i2c_config,
+    )
+    .unwrap();
+
     // ------------------------------ //
     //            ESP-NOW             //
     // ------------------------------ //
@@ -93,12 +116,20 @@ fn main() {
 
     // Create a channel to communicate between threads
     let (sender, reciever) = std::sync::mpsc::sync_channel(10);
-    // Notification to search for a master board on other channels
-    let (channel_search_sender, _channel_search_notifier) = std::sync::mpsc::sync_channel(1);
+    // Notification to search for a master board on other channels (`None`)
+    // or to follow the master on the announced channel (`Some`)
+    let (channel_search_sender, channel_search_notifier) = std::sync::mpsc::sync_channel(4);
+    let channel_retune_sender = channel_search_sender.clone();
 
     // register reciving callback, this is used to add master board to the peer list
     esp_now
-        .register_recv_cb(|mac_address, _data| {
+        .register_recv_cb(|mac_address, data| {
+            // Follow the master if it announced a new channel
+            if let Some(channel) = master_channel(data) {
+                if let Some(channel) = DISCOVERY.lock().unwrap().on_master_channel(channel) {
+                    let _ = channel_retune_sender.try_send(Some(channel));
+                }
+            }
             // Convert slice to array
             let mac_address_array = mac_address.try_into().unwrap();
             // If peer does not exist, add it
@@ -114,20 +145,12 @@ fn main() {
         .unwrap();
 
     // Register the send callback, this is used to detect if the master is not reachable
-    let mut num_fail: usize = 0;
     esp_now
         .register_send_cb(|_mac_addres, status| {
             // if a send fails for more than 10 times, start searching for the master board on other channels
-            if let SendStatus::SUCCESS = status {
-                num_fail = 0;
-                IS_SEARCHING_CHANNEL.store(false, Ordering::Relaxed)
-            } else {
-                num_fail = num_fail.checked_add(1).unwrap_or(0);
-            }
-
-            if num_fail > 10 {
-                IS_SEARCHING_CHANNEL.store(true, Ordering::Relaxed);
-                let _ = channel_search_sender.try_send(());
+            let success = matches!(status, SendStatus::SUCCESS);
+            if DISCOVERY.lock().unwrap().on_send_status(success) {
+                let _ = channel_search_sender.try_send(None);
             }
         })
         .unwrap();
@@ -156,6 +179,63 @@ fn main() {
         thread::sleep(Duration::from_secs(5));
     });
 
+    // Create a thread to read the air quality sensors, if any
+    let sender_air = sender.clone();
+    std::thread::spawn(move || {
+        // Both sensors share the same bus
+        let i2c = RefCell::new(i2c);
+        let mut scd4x = Scd4x::new(
+            RefCellDevice::new(&i2c),
+            scd4x::DEFAULT_ADDRESS,
+            &mut FreeRtos,
+        )
+        .ok();
+        let mut sgp30 = Sgp30::new(
+            RefCellDevice::new(&i2c),
+            sgp30::DEFAULT_ADDRESS,
+            &mut FreeRtos,
+        )
+        .ok();
+        println!(
+            "Air quality sensors: SCD4x found: {}, SGP30 found: {}",
+            scd4x.is_some(),
+            sgp30.is_some()
+        );
+        if scd4x.is_none() && sgp30.is_none() {
+            return;
+        }
+
+        let mut seconds: u32 = 0;
+        loop {
+            let is_time_to_send = seconds % AIR_QUALITY_INTERVAL_SECS == 0;
+
+            // The SGP30 must be measured every second for its baseline compensation
+            if let Some(sgp30) = sgp30.as_mut() {
+                if let Ok(measurement) = sgp30.measure(&mut FreeRtos) {
+                    if is_time_to_send {
+                        println!("SGP30: TVOC: {} ppb", measurement.tvoc);
+                        let message =
+                            VolatileOrganicCompoundsMessage::new().with_tvoc(measurement.tvoc);
+                        sender_air.send(message.into()).unwrap();
+                    }
+                }
+            }
+
+            if let Some(scd4x) = scd4x.as_mut() {
+                if is_time_to_send {
+                    if let Ok(Some(measurement)) = scd4x.read(&mut FreeRtos) {
+                        println!("SCD4x: CO2: {} ppm", measurement.co2);
+                        let message = CarbonDioxideMessage::new().with_co2(measurement.co2);
+                        sender_air.send(message.into()).unwrap();
+                    }
+                }
+            }
+
+            seconds = seconds.wrapping_add(1);
+            thread::sleep(Duration::from_secs(1));
+        }
+    });
+
     // Create a thread to read the gas sensor
     std::thread::spawn(move || loop {
         // Read the data from the gas sensor using ADC
@@ -176,17 +256,19 @@ fn main() {
     });
 
     // Scan for an evailable channel
-    std::thread::spawn(move || {
-        let mut channel = 6;
-        loop {
-            while IS_SEARCHING_CHANNEL.load(Ordering::Relaxed) {
+    std::thread::spawn(move || loop {
+        while DISCOVERY.lock().unwrap().is_searching() {
+            let channel = DISCOVERY.lock().unwrap().next_scan_channel();
+            set_channel(channel);
+            // Stay on the channel, unless the master announces where it is
+            let notification = channel_search_notifier.recv_timeout(Duration::from_secs(5));
+            if let Ok(Some(channel)) = notification {
                 set_channel(channel);
-                // channels 1, 6 and 11 are the most common channels
-                channel = (channel + 5) % 15;
-                std::thread::sleep(Duration::from_secs(5));
             }
-            // channel found, wait untill a notification is received
-            wait_notification(BLOCK);
+        }
+        // channel found, wait untill a notification is received
+        if let Some(channel) = channel_search_notifier.recv().unwrap() {
+            set_channel(channel);
         }
     });
 
@@ -0,0 +1,299 @@
+// Slave that is used to read door/window contacts and a PIR motion sensor and send
+// their changes to the master, so that the hub can be used as an alarm panel.
+use core::num::NonZeroU32;
+use core::time::Duration;
+use std::sync::Mutex;
+
+use embedded_svc::wifi::{ClientConfiguration, Configuration};
+use esp_idf_hal::delay::TickType;
+use esp_idf_hal::gpio::{AnyInputPin, Input, InterruptType, PinDriver, Pull};
+use esp_idf_hal::prelude::*;
+use esp_idf_hal::task::notification::Notification;
+use esp_idf_svc::espnow::{EspNow, PeerInfo, SendStatus};
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::sys::{
+    esp_wifi_set_protocol, wifi_interface_t_WIFI_IF_STA, WIFI_PROTOCOL_11B, WIFI_PROTOCOL_11G,
+    WIFI_PROTOCOL_11N, WIFI_PROTOCOL_LR,
+};
+use esp_idf_svc::wifi::WifiDriver;
+use firmware::utilities::channel::*;
+use firmware::utilities::channel_discovery::ChannelDiscovery;
+use firmware::{ContactMessage, MotionMessage};
+use messages::Frame;
+
+static DISCOVERY: Mutex<ChannelDiscovery> = Mutex::new(ChannelDiscovery::new());
+/// Number of retries of a message that was not delivered to the master
+const MAX_SEND_RETRIES: usize = 5;
+/// Time to wait for the delivery status of a message
+const SEND_STATUS_TIMEOUT: Duration = Duration::from_millis(100);
+/// Time to wait before retrying to send a message, doubled at each retry
+const SEND_RETRY_DELAY: Duration = Duration::from_millis(50);
+/// Time to wait for the inputs to settle after an edge
+const DEBOUNCE_TIME: Duration = Duration::from_millis(20);
+/// Interval of the report of all the inputs, so that the master can detect a
+/// missing slave
+const SUPERVISION_INTERVAL: Duration = Duration::from_secs(60);
+/// Notification bit of the PIR motion sensor
+const MOTION_BIT: u32 = 1 << 30;
+/// Notification bit of the tamper switch
+const TAMPER_BIT: u32 = 1 << 31;
+
+type InputPin = PinDriver<'static, AnyInputPin, Input>;
+
+fn main() {
+    // Init
+    esp_idf_svc::sys::link_patches();
+    esp_idf_svc::log::EspLogger::initialize_default();
+
+    // Take the peripherals
+    let peripherals = Peripherals::take().unwrap();
+
+    // ------------------------------ //
+    //         Security inputs        //
+    // ------------------------------ //
+
+    // Reed contacts, closed (low) when the magnet is near
+    let mut contact_pins = [
+        PinDriver::input(peripherals.pins.gpio4.downgrade_input()).unwrap(),
+        PinDriver::input(peripherals.pins.gpio5.downgrade_input()).unwrap(),
+    ];
+    for pin in contact_pins.iter_mut() {
+        pin.set_pull(Pull::Up).unwrap();
+    }
+
+    // PIR motion sensor, high when motion is detected
+    let mut motion_pin = PinDriver::input(peripherals.pins.gpio6.downgrade_input()).unwrap();
+    motion_pin.set_pull(Pull::Down).unwrap();
+
+    // Tamper switch of the enclosure, closed (low) when the enclosure is closed
+    let mut tamper_pin = PinDriver::input(peripherals.pins.gpio7.downgrade_input()).unwrap();
+    tamper_pin.set_pull(Pull::Up).unwrap();
+
+    // ------------------------------ //
+    //            ESP-NOW             //
+    // ------------------------------ //
+
+    // Setup the Wi-Fi driver
+    let sys_loop = EspSystemEventLoop::take().unwrap();
+    let nvs = EspDefaultNvsPartition::take().unwrap();
+
+    // Create a WifiDriver instance.
+    let mut wifi_driver = WifiDriver::new(peripherals.modem, sys_loop, Some(nvs)).unwrap();
+
+    // Set the Wi-Fi configuration as a client
+    wifi_driver
+        .set_configuration(&Configuration::Client(ClientConfiguration::default()))
+        .unwrap();
+
+    // Set protocol to accept Long range also
+    unsafe {
+        esp_wifi_set_protocol(
+            wifi_interface_t_WIFI_IF_STA,
+            (WIFI_PROTOCOL_11B | WIFI_PROTOCOL_11G | WIFI_PROTOCOL_11N | WIFI_PROTOCOL_LR) as u8,
+        );
+    }
+
+    // Wi-Fi start
+    wifi_driver.start().unwrap();
+
+    // Start ESP-NOW
+    let esp_now = EspNow::take().unwrap();
+
+    // Create a channel to communicate between threads
+    let (sender, reciver) = std::sync::mpsc::sync_channel(10);
+    // Notification to search for a master board on other channels (`None`)
+    // or to follow the master on the announced channel (`Some`)
+    let (channel_search_sender, channel_search_notifier) = std::sync::mpsc::sync_channel(4);
+    let channel_retune_sender = channel_search_sender.clone();
+    // Delivery status of the sent messages
+    let (send_status_sender, send_status_receiver) = std::sync::mpsc::sync_channel(1);
+
+    // register reciving callback, this is used to add the master board to the peer list
+    esp_now
+        .register_recv_cb(|mac_address, data| {
+            // Follow the master if it announced a new channel
+            if let Some(channel) = master_channel(data) {
+                if let Some(channel) = DISCOVERY.lock().unwrap().on_master_channel(channel) {
+                    let _ = channel_retune_sender.try_send(Some(channel));
+                }
+            }
+            // Convert slice to array
+            let mac_address_array = mac_address.try_into().unwrap();
+            // If peer does not exist, add it
+            if let Ok(false) = esp_now.peer_exists(mac_address_array) {
+                // Add the peer
+                let peer = PeerInfo {
+                    peer_addr: mac_address_array,
+                    ..Default::default()
+                };
+                esp_now.add_peer(peer).unwrap();
+            }
+        })
+        .unwrap();
+
+    // Register the send callback, this is used to detect if the master is not reachable
+    // and to retry the messages that were not delivered
+    esp_now
+        .register_send_cb(|_mac_addres, status| {
+            // if a send fails for more than 10 times, start searching for the master board on other channels
+            let success = matches!(status, SendStatus::SUCCESS);
+            if DISCOVERY.lock().unwrap().on_send_status(success) {
+                let _ = channel_search_sender.try_send(None);
+            }
+            let _ = send_status_sender.try_send(success);
+        })
+        .unwrap();
+
+    // ------------------------------ //
+    //            Threads             //
+    // ------------------------------ //
+
+    // Create a thread to report the changes of the inputs
+    std::thread::spawn(move || {
+        // Every edge notifies this thread, with a bit for each input
+        let notification = Notification::new();
+        for (i, pin) in contact_pins.iter_mut().enumerate() {
+            subscribe_edges(pin, &notification, 1 << i);
+        }
+        subscribe_edges(&mut motion_pin, &notification, MOTION_BIT);
+        subscribe_edges(&mut tamper_pin, &notification, TAMPER_BIT);
+
+        let mut contacts: Vec<bool> = contact_pins.iter().map(|pin| pin.is_high()).collect();
+        let mut motion = motion_pin.is_high();
+        let mut tamper = tamper_pin.is_high();
+        let report_all = |contacts: &[bool], motion: bool, tamper: bool| {
+            for (i, open) in contacts.iter().enumerate() {
+                send_contact(&sender, i, *open, tamper);
+            }
+            send_motion(&sender, motion, tamper);
+        };
+        report_all(&contacts, motion, tamper);
+
+        let supervision_ticks = TickType::from(SUPERVISION_INTERVAL).ticks();
+        loop {
+            let Some(bits) = notification.wait(supervision_ticks) else {
+                // No edge for a while, report all the inputs
+                report_all(&contacts, motion, tamper);
+                continue;
+            };
+            let bits = bits.get();
+            std::thread::sleep(DEBOUNCE_TIME);
+
+            if bits & TAMPER_BIT != 0 {
+                if tamper != tamper_pin.is_high() {
+                    tamper = !tamper;
+                    println!("Tamper: {}", tamper);
+                    // Every message carries the tamper state
+                    report_all(&contacts, motion, tamper);
+                }
+                tamper_pin.enable_interrupt().unwrap();
+            }
+
+            for (i, pin) in contact_pins.iter_mut().enumerate() {
+                if bits & (1 << i) == 0 {
+                    continue;
+                }
+                if contacts[i] != pin.is_high() {
+                    contacts[i] = !contacts[i];
+                    println!("Contact {}: open: {}", i, contacts[i]);
+                    send_contact(&sender, i, contacts[i], tamper);
+                }
+                pin.enable_interrupt().unwrap();
+            }
+
+            if bits & MOTION_BIT != 0 {
+                if motion != motion_pin.is_high() {
+                    motion = !motion;
+                    println!("Motion: {}", motion);
+                    send_motion(&sender, motion, tamper);
+                }
+                motion_pin.enable_interrupt().unwrap();
+            }
+        }
+    });
+
+    // Scan for an evailable channel
+    std::thread::spawn(move || loop {
+        while DISCOVERY.lock().unwrap().is_searching() {
+            let channel = DISCOVERY.lock().unwrap().next_scan_channel();
+            set_channel(channel);
+            // Stay on the channel, unless the master announces where it is
+            let notification = channel_search_notifier.recv_timeout(Duration::from_secs(5));
+            if let Ok(Some(channel)) = notification {
+                set_channel(channel);
+            }
+        }
+        // channel found, wait untill a notification is received
+        if let Some(channel) = channel_search_notifier.recv().unwrap() {
+            set_channel(channel);
+        }
+    });
+
+    // Main task
+    loop {
+        // Wait for a message
+        let frame_to_send: Frame = reciver.recv().unwrap();
+        let data = frame_to_send.serialize();
+
+        // Send the message right away, retrying until it is delivered
+        let mut retry_delay = SEND_RETRY_DELAY;
+        let mut delivered = false;
+        for _ in 0..=MAX_SEND_RETRIES {
+            // Discard the status of the previous messages
+            while send_status_receiver.try_recv().is_ok() {}
+
+            // If the peer exists, send the message
+            if let Ok(peer) = esp_now.fetch_peer(true) {
+                if esp_now.send(peer.peer_addr, &data).is_ok() {
+                    delivered = send_status_receiver
+                        .recv_timeout(SEND_STATUS_TIMEOUT)
+                        .unwrap_or(false);
+                }
+            }
+            if delivered {
+                break;
+            }
+
+            std::thread::sleep(retry_delay);
+            retry_delay *= 2;
+        }
+        if !delivered {
+            println!("Failed to deliver message to the master");
+        }
+    }
+}
+
+/// Notify the `notification` with `bit` on every edge of the `pin`.
+fn subscribe_edges(pin: &mut InputPin, notification: &Notification, bit: u32) {
+    let notifier = notification.notifier();
+    pin.set_interrupt_type(InterruptType::AnyEdge).unwrap();
+    // Safety: the callback only notifies the input thread, which is allowed in an ISR
+    unsafe {
+        pin.subscribe(move || {
+            notifier.notify_and_yield(NonZeroU32::new(bit).unwrap());
+        })
+        .unwrap();
+    }
+    pin.enable_interrupt().unwrap();
+}
+
+/// Send the state of a contact to the main task.
+fn send_contact(
+    sender: &std::sync::mpsc::SyncSender<Frame>,
+    input: usize,
+    open: bool,
+    tamper: bool,
+) {
+    let message = ContactMessage::new()
+        .with_input(input as u8)
+        .with_open(open)
+        .with_tamper(tamper);
+    sender.send(message.into()).unwrap();
+}
+
+/// Send the state of the motion sensor to the main task.
+fn send_motion(sender: &std::sync::mpsc::SyncSender<Frame>, motion: bool, tamper: bool) {
+    let message = MotionMessage::new().with_motion(motion).with_tamper(tamper);
+    sender.send(message.into()).unwrap();
+}
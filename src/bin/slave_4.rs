@@ -0,0 +1,324 @@
+// Slave that is used to read the energy consumption, from a PZEM-004T energy monitor
+// and from the pulse output of a utility meter, and send it to the master.
+use core::time::Duration;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+
+use embedded_io::{ErrorType, Read, Write};
+use embedded_svc::wifi::{ClientConfiguration, Configuration};
+use esp_idf_hal::delay::TickType;
+use esp_idf_hal::gpio::AnyIOPin;
+use esp_idf_hal::io::EspIOError;
+use esp_idf_hal::pcnt::{
+    PcntChannel, PcntChannelConfig, PcntControlMode, PcntCountMode, PcntDriver, PinIndex,
+};
+use esp_idf_hal::prelude::*;
+use esp_idf_hal::uart::{self, UartDriver};
+use esp_idf_svc::espnow::{EspNow, PeerInfo, SendStatus};
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use esp_idf_svc::sys::{
+    esp_wifi_set_protocol, wifi_interface_t_WIFI_IF_STA, WIFI_PROTOCOL_11B, WIFI_PROTOCOL_11G,
+    WIFI_PROTOCOL_11N, WIFI_PROTOCOL_LR,
+};
+use esp_idf_svc::wifi::WifiDriver;
+use firmware::sensors::pulse_meter::PulseMeter;
+use firmware::sensors::pzem004t::{self, Pzem004t};
+use firmware::utilities::channel::*;
+use firmware::utilities::channel_discovery::ChannelDiscovery;
+use firmware::{EnergyMessage, MainsMessage, PowerMessage};
+use messages::Frame;
+
+static DISCOVERY: Mutex<ChannelDiscovery> = Mutex::new(ChannelDiscovery::new());
+/// Meter ID of the PZEM-004T in the messages
+const METER_PZEM: u8 = 0;
+/// Meter ID of the pulse counter in the messages
+const METER_PULSE: u8 = 1;
+/// Pulses per kWh of the utility meter (printed on the meter as imp/kWh)
+const PULSES_PER_KWH: u32 = 1000;
+/// Interval between two readings of the meters
+const READ_INTERVAL: Duration = Duration::from_secs(10);
+/// Interval between two saves of the pulse count in the NVS, to limit the flash wear
+const PULSES_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+/// Pulses counted since the last save that trigger a save at once (10 Wh)
+const PULSES_SAVE_DELTA: u64 = PULSES_PER_KWH as u64 / 100;
+/// Attempts to send a frame to the master
+const SEND_ATTEMPTS: usize = 3;
+/// Delay between two attempts to send a frame
+const SEND_RETRY_DELAY: Duration = Duration::from_millis(50);
+/// Time to wait for the answer of the PZEM-004T
+const UART_TIMEOUT: Duration = Duration::from_millis(200);
+/// NVS namespace of the energy meter
+const NVS_NAMESPACE: &str = "Energy meter";
+/// NVS key of the total pulses counted
+const NVS_PULSES_KEY: &str = "Pulses";
+
+/// UART returning 0 bytes when a read times out, so that a missing PZEM-004T
+/// does not block the thread forever.
+struct TimeoutUart<'d>(UartDriver<'d>);
+
+impl ErrorType for TimeoutUart<'_> {
+    type Error = EspIOError;
+}
+
+impl Read for TimeoutUart<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        Ok(self.0.read(buf, TickType::from(UART_TIMEOUT).ticks())?)
+    }
+}
+
+impl Write for TimeoutUart<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        Ok(self.0.write(buf)?)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(self.0.wait_tx_done(TickType::from(UART_TIMEOUT).ticks())?)
+    }
+}
+
+fn main() {
+    // Init
+    esp_idf_svc::sys::link_patches();
+    esp_idf_svc::log::EspLogger::initialize_default();
+
+    // Take the peripherals
+    let peripherals = Peripherals::take().unwrap();
+    let nvs = EspDefaultNvsPartition::take().unwrap();
+
+    // ------------------------------ //
+    //        PZEM-004T (UART)        //
+    // ------------------------------ //
+
+    let uart_config = uart::config::Config::new().baudrate(Hertz(pzem004t::BAUD_RATE));
+    let uart = UartDriver::new(
+        peripherals.uart1,
+        peripherals.pins.gpio17,
+        peripherals.pins.gpio18,
+        Option::<AnyIOPin>::None,
+        Option::<AnyIOPin>::None,
+        &uart_config,
+    )
+    .unwrap();
+    let mut pzem = Pzem004t::new(TimeoutUart(uart), pzem004t::DEFAULT_ADDRESS);
+
+    // ------------------------------ //
+    //      Pulse counter (PCNT)      //
+    // ------------------------------ //
+
+    let mut pcnt = PcntDriver::new(
+        peripherals.pcnt0,
+        Some(peripherals.pins.gpio4),
+        Option::<AnyIOPin>::None,
+        Option::<AnyIOPin>::None,
+        Option::<AnyIOPin>::None,
+    )
+    .unwrap();
+    // Count the rising edges of the pulse output
+    pcnt.channel_config(
+        PcntChannel::Channel0,
+        PinIndex::Pin0,
+        PinIndex::Pin1,
+        &PcntChannelConfig {
+            lctrl_mode: PcntControlMode::Keep,
+            hctrl_mode: PcntControlMode::Keep,
+            pos_mode: PcntCountMode::Increment,
+            neg_mode: PcntCountMode::Hold,
+            counter_h_lim: i16::MAX,
+            counter_l_lim: 0,
+        },
+    )
+    .unwrap();
+    // Ignore glitches shorter than 1023 APB cycles (~13 us)
+    pcnt.set_filter_value(1023).unwrap();
+    pcnt.filter_enable().unwrap();
+    pcnt.counter_pause().unwrap();
+    pcnt.counter_clear().unwrap();
+    pcnt.counter_resume().unwrap();
+
+    // The total pulses are kept in the NVS, to survive reboots
+    let pulses_nvs = EspNvs::new(nvs.clone(), NVS_NAMESPACE, true).unwrap();
+    let total_pulses = pulses_nvs.get_u64(NVS_PULSES_KEY).unwrap().unwrap_or(0);
+    println!("Pulses counted before reboot: {}", total_pulses);
+    let mut pulse_meter = PulseMeter::new(PULSES_PER_KWH, total_pulses).unwrap();
+
+    // ------------------------------ //
+    //            ESP-NOW             //
+    // ------------------------------ //
+
+    // Setup the Wi-Fi driver
+    let sys_loop = EspSystemEventLoop::take().unwrap();
+
+    // Create a WifiDriver instance.
+    let mut wifi_driver = WifiDriver::new(peripherals.modem, sys_loop, Some(nvs)).unwrap();
+
+    // Set the Wi-Fi configuration as a client
+    wifi_driver
+        .set_configuration(&Configuration::Client(ClientConfiguration::default()))
+        .unwrap();
+
+    // Set protocol to accept Long range also
+    unsafe {
+        esp_wifi_set_protocol(
+            wifi_interface_t_WIFI_IF_STA,
+            (WIFI_PROTOCOL_11B | WIFI_PROTOCOL_11G | WIFI_PROTOCOL_11N | WIFI_PROTOCOL_LR) as u8,
+        );
+    }
+
+    // Wi-Fi start
+    wifi_driver.start().unwrap();
+
+    // Start ESP-NOW
+    let esp_now = EspNow::take().unwrap();
+
+    // Create a channel to communicate between threads
+    let (sender, reciever) = std::sync::mpsc::sync_channel::<Frame>(10);
+    // Notification to search for a master board on other channels (`None`)
+    // or to follow the master on the announced channel (`Some`)
+    let (channel_search_sender, channel_search_notifier) = std::sync::mpsc::sync_channel(4);
+    let channel_retune_sender = channel_search_sender.clone();
+
+    // register reciving callback, this is used to add master board to the peer list
+    esp_now
+        .register_recv_cb(|mac_address, data| {
+            // Follow the master if it announced a new channel
+            if let Some(channel) = master_channel(data) {
+                if let Some(channel) = DISCOVERY.lock().unwrap().on_master_channel(channel) {
+                    let _ = channel_retune_sender.try_send(Some(channel));
+                }
+            }
+            // Convert slice to array
+            let mac_address_array = mac_address.try_into().unwrap();
+            // If peer does not exist, add it
+            if let Ok(false) = esp_now.peer_exists(mac_address_array) {
+                // Add the peer
+                let peer = PeerInfo {
+                    peer_addr: mac_address_array,
+                    ..Default::default()
+                };
+                esp_now.add_peer(peer).unwrap();
+            }
+        })
+        .unwrap();
+
+    // Register the send callback, this is used to detect if the master is not reachable
+    esp_now
+        .register_send_cb(|_mac_addres, status| {
+            // if a send fails for more than 10 times, start searching for the master board on other channels
+            let success = matches!(status, SendStatus::SUCCESS);
+            if DISCOVERY.lock().unwrap().on_send_status(success) {
+                let _ = channel_search_sender.try_send(None);
+            }
+        })
+        .unwrap();
+
+    // ------------------------------ //
+    //            Threads             //
+    // ------------------------------ //
+
+    // Create a thread to read the PZEM-004T
+    let sender_pzem = sender.clone();
+    thread::spawn(move || loop {
+        match pzem.measure() {
+            Ok(measurement) => {
+                println!("PZEM-004T: {:?}", measurement);
+                let message_mains = MainsMessage::new()
+                    .with_voltage(measurement.voltage)
+                    .with_current(measurement.current)
+                    .with_frequency(measurement.frequency)
+                    .with_power_factor(measurement.power_factor);
+                sender_pzem.send(message_mains.into()).unwrap();
+                let message_power = PowerMessage::new()
+                    .with_power(measurement.power)
+                    .with_meter(METER_PZEM);
+                sender_pzem.send(message_power.into()).unwrap();
+                let message_energy = EnergyMessage::new()
+                    .with_energy(measurement.energy)
+                    .with_meter(METER_PZEM);
+                sender_pzem.send(message_energy.into()).unwrap();
+            }
+            Err(e) => println!("PZEM-004T: failed to read: {:?}", e),
+        }
+        thread::sleep(READ_INTERVAL);
+    });
+
+    // Create a thread to read the pulse counter
+    thread::spawn(move || {
+        let mut last_read = Instant::now();
+        let mut last_save = Instant::now();
+        let mut saved_pulses = pulse_meter.total_pulses();
+        loop {
+            thread::sleep(READ_INTERVAL);
+
+            // Read and clear the counter, the pulses are too slow to be missed
+            // while the counter is paused
+            pcnt.counter_pause().unwrap();
+            let pulses = pcnt.get_counter_value().unwrap();
+            pcnt.counter_clear().unwrap();
+            pcnt.counter_resume().unwrap();
+
+            let reading = pulse_meter.add_pulses(pulses as u32, last_read.elapsed());
+            last_read = Instant::now();
+            println!("Pulse counter: {} pulses, {:?}", pulses, reading);
+
+            let message_power = PowerMessage::new()
+                .with_power(reading.power)
+                .with_meter(METER_PULSE);
+            sender.send(message_power.into()).unwrap();
+            let message_energy = EnergyMessage::new()
+                .with_energy(reading.energy.try_into().unwrap_or(u32::MAX))
+                .with_meter(METER_PULSE);
+            sender.send(message_energy.into()).unwrap();
+
+            // Save the pulses counted, at once if many were counted, so that
+            // a reset loses little energy
+            let unsaved_pulses = pulse_meter.total_pulses() - saved_pulses;
+            if unsaved_pulses >= PULSES_SAVE_DELTA
+                || (unsaved_pulses > 0 && last_save.elapsed() > PULSES_SAVE_INTERVAL)
+            {
+                saved_pulses = pulse_meter.total_pulses();
+                if let Err(e) = pulses_nvs.set_u64(NVS_PULSES_KEY, saved_pulses) {
+                    println!("Failed to save the pulses counted: {:?}", e);
+                }
+                last_save = Instant::now();
+            }
+        }
+    });
+
+    // Scan for an evailable channel
+    thread::spawn(move || loop {
+        while DISCOVERY.lock().unwrap().is_searching() {
+            let channel = DISCOVERY.lock().unwrap().next_scan_channel();
+            set_channel(channel);
+            // Stay on the channel, unless the master announces where it is
+            let notification = channel_search_notifier.recv_timeout(Duration::from_secs(5));
+            if let Ok(Some(channel)) = notification {
+                set_channel(channel);
+            }
+        }
+        // channel found, wait untill a notification is received
+        if let Some(channel) = channel_search_notifier.recv().unwrap() {
+            set_channel(channel);
+        }
+    });
+
+    // Main task
+    loop {
+        // Wait for a message
+        let frame_to_send = reciever.recv().unwrap();
+        // If a peer is available, send the message
+        if let Ok(peer) = esp_now.fetch_peer(true) {
+            let data = frame_to_send.serialize();
+            for attempt in 1..=SEND_ATTEMPTS {
+                match esp_now.send(peer.peer_addr, &data) {
+                    Ok(()) => break,
+                    Err(e) => {
+                        println!("Failed to send a frame (attempt {}): {:?}", attempt, e);
+                        thread::sleep(SEND_RETRY_DELAY);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -18,3 +18,78 @@ pub fn set_message_device_id(message: &mut Message, device_id: u8) -> Result<()>
     // set device_id field of message
     message.set_field(device_id_field, AnyField::U64(device_id.into()))
 }
+
+/// Get the Wi-Fi channel carried by a message of the master (Ping or Channel Change).
+pub fn get_message_channel(message: &Message) -> Result<u8> {
+    // get id of message
+    let id = message.get_id();
+    // get channel field of message
+    let channel_field = database()
+        .get(&id.into())
+        .unwrap()
+        .fields
+        .iter()
+        .find(|field| field.name == "Channel")
+        .ok_or(Error::FrameIsNotMessage)?;
+    // read channel field of message
+    match message.get_field(channel_field)? {
+        AnyField::U64(channel) => u8::try_from(channel).map_err(|_| Error::FrameIsNotMessage),
+        _ => Err(Error::FrameIsNotMessage),
+    }
+}
+
+/// Names of the alarm messages, never dropped by the master.
+const ALARM_MESSAGES: [&str; 4] = ["Fire Alarm", "Gas Leakage", "Contact", "Motion"];
+
+/// Check if the frame carries an alarm message.
+pub fn is_alarm_frame(frame: &messages::Frame) -> bool {
+    let Ok(message) = Message::try_from(frame) else {
+        return false;
+    };
+    // get id of message
+    let id = message.get_id();
+    database()
+        .get(&id.into())
+        .is_some_and(|definition| ALARM_MESSAGES.iter().any(|name| definition.name == *name))
+}
+
+/// Numeric value of a field of a message, with its unit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldValue {
+    pub name: String,
+    pub unit: Option<String>,
+    pub value: f64,
+}
+
+/// Numeric values of a message: its name, the ID of the device that sent it
+/// and its fields, the booleans as 0 or 1. The device ID and the padding are
+/// not in the fields.
+pub fn message_values(message: &Message) -> Result<(String, Option<u8>, Vec<FieldValue>)> {
+    // get id of message
+    let id = message.get_id();
+    let definition = database().get(&id.into()).ok_or(Error::FrameIsNotMessage)?;
+
+    let mut device_id = None;
+    let mut fields = vec![];
+    for field in definition.fields.iter() {
+        let value = match message.get_field(field) {
+            Ok(AnyField::U64(value)) => value as f64,
+            Ok(AnyField::I64(value)) => value as f64,
+            Ok(AnyField::F32(value)) => value as f64,
+            Ok(AnyField::F64(value)) => value,
+            Ok(AnyField::Bool(value)) => value as u8 as f64,
+            // Padding
+            _ => continue,
+        };
+        if field.name == "Device ID" {
+            device_id = u8::try_from(value as u64).ok();
+            continue;
+        }
+        fields.push(FieldValue {
+            name: field.name.to_string(),
+            unit: field.unit.as_ref().map(|unit| unit.to_string()),
+            value,
+        });
+    }
+    Ok((definition.name.to_string(), device_id, fields))
+}
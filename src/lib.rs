@@ -1,4 +1,5 @@
 pub mod definitions;
+pub mod sensors;
 pub mod utilities;
 
 pub use definitions::*;
@@ -0,0 +1,389 @@
+//! Driver for the Bosch BME280 temperature, humidity and pressure sensor.
+//!
+//! The sensor is used in forced mode: a measurement is triggered on request
+//! and the sensor goes back to sleep afterwards.
+//! The compensation formulas are the integer ones of the datasheet.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+
+use super::SensorError;
+
+/// I2C address with SDO connected to GND.
+pub const DEFAULT_ADDRESS: u8 = 0x76;
+/// I2C address with SDO connected to VDDIO.
+pub const SECONDARY_ADDRESS: u8 = 0x77;
+
+/// Value of the chip ID register.
+const CHIP_ID: u8 = 0x60;
+
+const REG_CALIB_00: u8 = 0x88;
+const REG_CHIP_ID: u8 = 0xD0;
+const REG_RESET: u8 = 0xE0;
+const REG_CALIB_26: u8 = 0xE1;
+const REG_CTRL_HUM: u8 = 0xF2;
+const REG_STATUS: u8 = 0xF3;
+const REG_CTRL_MEAS: u8 = 0xF4;
+const REG_CONFIG: u8 = 0xF5;
+const REG_DATA: u8 = 0xF7;
+
+const RESET_COMMAND: u8 = 0xB6;
+/// Oversampling x1 for every measurement
+const OVERSAMPLING_X1: u8 = 0b001;
+const MODE_FORCED: u8 = 0b01;
+/// Measuring bit of the status register
+const STATUS_MEASURING: u8 = 0b1000;
+/// Conversion status bit of the status register
+const STATUS_IM_UPDATE: u8 = 0b1;
+
+/// Start-up time after a soft reset (ms)
+const STARTUP_TIME_MS: u32 = 2;
+/// Polling interval of the status register (ms)
+const POLL_INTERVAL_MS: u32 = 2;
+/// Max number of polls of the status register before giving up
+const MAX_POLLS: usize = 50;
+
+/// Compensated measurement.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Measurement {
+    /// Temperature in °C
+    pub temperature: f32,
+    /// Relative humidity in %
+    pub humidity: f32,
+    /// Pressure in hPa
+    pub pressure: f32,
+}
+
+/// Calibration data stored in the sensor NVM.
+#[derive(Debug, Clone, Copy, Default)]
+struct Calibration {
+    t1: u16,
+    t2: i16,
+    t3: i16,
+    p1: u16,
+    p2: i16,
+    p3: i16,
+    p4: i16,
+    p5: i16,
+    p6: i16,
+    p7: i16,
+    p8: i16,
+    p9: i16,
+    h1: u8,
+    h2: i16,
+    h3: u8,
+    h4: i16,
+    h5: i16,
+    h6: i8,
+}
+
+impl Calibration {
+    /// Parse the calibration registers `0x88..=0xA1` and `0xE1..=0xE7`.
+    fn parse(tp: &[u8; 26], h: &[u8; 7]) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([tp[i], tp[i + 1]]);
+        let i16_at = |i: usize| i16::from_le_bytes([tp[i], tp[i + 1]]);
+
+        Self {
+            t1: u16_at(0),
+            t2: i16_at(2),
+            t3: i16_at(4),
+            p1: u16_at(6),
+            p2: i16_at(8),
+            p3: i16_at(10),
+            p4: i16_at(12),
+            p5: i16_at(14),
+            p6: i16_at(16),
+            p7: i16_at(18),
+            p8: i16_at(20),
+            p9: i16_at(22),
+            // 0xA0 is not used
+            h1: tp[25],
+            h2: i16::from_le_bytes([h[0], h[1]]),
+            h3: h[2],
+            // H4 and H5 are 12 bits values sharing the nibbles of 0xE5
+            h4: ((h[3] as i8 as i16) << 4) | (h[4] & 0x0F) as i16,
+            h5: ((h[5] as i8 as i16) << 4) | (h[4] >> 4) as i16,
+            h6: h[6] as i8,
+        }
+    }
+
+    /// Compensate the raw temperature.
+    /// Returns the temperature in 0.01 °C and the fine temperature used by
+    /// the other compensations.
+    fn compensate_temperature(&self, adc_t: i32) -> (i32, i32) {
+        let t1 = self.t1 as i32;
+        let var1 = (((adc_t >> 3) - (t1 << 1)) * self.t2 as i32) >> 11;
+        let var2 = (((((adc_t >> 4) - t1) * ((adc_t >> 4) - t1)) >> 12) * self.t3 as i32) >> 14;
+        let t_fine = var1 + var2;
+        ((t_fine * 5 + 128) >> 8, t_fine)
+    }
+
+    /// Compensate the raw pressure.
+    /// Returns the pressure in Pa as unsigned Q24.8.
+    fn compensate_pressure(&self, adc_p: i32, t_fine: i32) -> u32 {
+        let mut var1 = t_fine as i64 - 128000;
+        let mut var2 = var1 * var1 * self.p6 as i64;
+        var2 += (var1 * self.p5 as i64) << 17;
+        var2 += (self.p4 as i64) << 35;
+        var1 = ((var1 * var1 * self.p3 as i64) >> 8) + ((var1 * self.p2 as i64) << 12);
+        var1 = (((1i64 << 47) + var1) * self.p1 as i64) >> 33;
+        if var1 == 0 {
+            // Avoid a division by zero
+            return 0;
+        }
+        let mut p = 1048576 - adc_p as i64;
+        p = (((p << 31) - var2) * 3125) / var1;
+        var1 = (self.p9 as i64 * (p >> 13) * (p >> 13)) >> 25;
+        var2 = (self.p8 as i64 * p) >> 19;
+        (((p + var1 + var2) >> 8) + ((self.p7 as i64) << 4)) as u32
+    }
+
+    /// Compensate the raw humidity.
+    /// Returns the relative humidity in % as unsigned Q22.10.
+    fn compensate_humidity(&self, adc_h: i32, t_fine: i32) -> u32 {
+        let mut v = t_fine - 76800;
+        v = ((((adc_h << 14) - ((self.h4 as i32) << 20) - (self.h5 as i32 * v)) + 16384) >> 15)
+            * (((((((v * self.h6 as i32) >> 10) * (((v * self.h3 as i32) >> 11) + 32768)) >> 10)
+                + 2097152)
+                * self.h2 as i32
+                + 8192)
+                >> 14);
+        v -= ((((v >> 15) * (v >> 15)) >> 7) * self.h1 as i32) >> 4;
+        v = v.clamp(0, 419430400);
+        (v >> 12) as u32
+    }
+}
+
+/// BME280 driver.
+pub struct Bme280<I2C> {
+    i2c: I2C,
+    address: u8,
+    calibration: Calibration,
+}
+
+impl<I2C: I2c> Bme280<I2C> {
+    /// Reset the sensor, check its chip ID and read the calibration data.
+    pub fn new<D: DelayNs>(
+        i2c: I2C,
+        address: u8,
+        delay: &mut D,
+    ) -> Result<Self, SensorError<I2C::Error>> {
+        let mut bme280 = Self {
+            i2c,
+            address,
+            calibration: Calibration::default(),
+        };
+
+        let chip_id = bme280.read_register(REG_CHIP_ID)?;
+        if chip_id != CHIP_ID {
+            return Err(SensorError::InvalidChipId(chip_id));
+        }
+
+        bme280.write_register(REG_RESET, RESET_COMMAND)?;
+        delay.delay_ms(STARTUP_TIME_MS);
+        // Wait for the NVM data to be copied to the registers
+        bme280.wait_status(STATUS_IM_UPDATE, delay)?;
+
+        let mut tp = [0u8; 26];
+        bme280.read_registers(REG_CALIB_00, &mut tp)?;
+        let mut h = [0u8; 7];
+        bme280.read_registers(REG_CALIB_26, &mut h)?;
+        bme280.calibration = Calibration::parse(&tp, &h);
+
+        // Filter off, standby time is not used in forced mode
+        bme280.write_register(REG_CONFIG, 0)?;
+        // Humidity oversampling, applied at the next write of ctrl_meas
+        bme280.write_register(REG_CTRL_HUM, OVERSAMPLING_X1)?;
+
+        Ok(bme280)
+    }
+
+    /// Trigger a measurement and wait for its result.
+    pub fn measure<D: DelayNs>(
+        &mut self,
+        delay: &mut D,
+    ) -> Result<Measurement, SensorError<I2C::Error>> {
+        let ctrl_meas = (OVERSAMPLING_X1 << 5) | (OVERSAMPLING_X1 << 2) | MODE_FORCED;
+        self.write_register(REG_CTRL_MEAS, ctrl_meas)?;
+        self.wait_status(STATUS_MEASURING, delay)?;
+
+        let mut data = [0u8; 8];
+        self.read_registers(REG_DATA, &mut data)?;
+        Ok(self.compensate(&data))
+    }
+
+    /// Release the I2C bus.
+    pub fn release(self) -> I2C {
+        self.i2c
+    }
+
+    /// Compensate the raw data registers `0xF7..=0xFE`.
+    fn compensate(&self, data: &[u8; 8]) -> Measurement {
+        let adc_p = ((data[0] as i32) << 12) | ((data[1] as i32) << 4) | ((data[2] as i32) >> 4);
+        let adc_t = ((data[3] as i32) << 12) | ((data[4] as i32) << 4) | ((data[5] as i32) >> 4);
+        let adc_h = ((data[6] as i32) << 8) | data[7] as i32;
+
+        let (temperature, t_fine) = self.calibration.compensate_temperature(adc_t);
+        let pressure = self.calibration.compensate_pressure(adc_p, t_fine);
+        let humidity = self.calibration.compensate_humidity(adc_h, t_fine);
+
+        Measurement {
+            temperature: temperature as f32 / 100.0,
+            humidity: humidity as f32 / 1024.0,
+            // Pa to hPa
+            pressure: pressure as f32 / 256.0 / 100.0,
+        }
+    }
+
+    /// Wait until the given bit of the status register is cleared.
+    fn wait_status<D: DelayNs>(
+        &mut self,
+        bit: u8,
+        delay: &mut D,
+    ) -> Result<(), SensorError<I2C::Error>> {
+        for _ in 0..MAX_POLLS {
+            if self.read_register(REG_STATUS)? & bit == 0 {
+                return Ok(());
+            }
+            delay.delay_ms(POLL_INTERVAL_MS);
+        }
+        Err(SensorError::Timeout)
+    }
+
+    fn read_register(&mut self, register: u8) -> Result<u8, SensorError<I2C::Error>> {
+        let mut buffer = [0u8; 1];
+        self.read_registers(register, &mut buffer)?;
+        Ok(buffer[0])
+    }
+
+    fn read_registers(
+        &mut self,
+        register: u8,
+        buffer: &mut [u8],
+    ) -> Result<(), SensorError<I2C::Error>> {
+        self.i2c.write_read(self.address, &[register], buffer)?;
+        Ok(())
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> Result<(), SensorError<I2C::Error>> {
+        self.i2c.write(self.address, &[register, value])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mock::{MockI2c, NoDelay};
+    use super::*;
+
+    /// Calibration of the datasheet example for the temperature and the
+    /// pressure, typical values for the humidity.
+    fn sensor() -> MockI2c {
+        let mut i2c = MockI2c::new(DEFAULT_ADDRESS);
+        i2c.set_registers(REG_CHIP_ID, &[CHIP_ID]);
+        let tp: [i32; 12] = [
+            27504, 26435, -1000, 36477, -10685, 3024, 2855, 140, -7, 15500, -14600, 6000,
+        ];
+        for (i, value) in tp.iter().enumerate() {
+            i2c.set_registers(REG_CALIB_00 + 2 * i as u8, &(*value as u16).to_le_bytes());
+        }
+        // H1, then H2 = 362, H3 = 0, H4 = 313, H5 = 50, H6 = 30
+        i2c.set_registers(REG_CALIB_00 + 25, &[75]);
+        i2c.set_registers(REG_CALIB_26, &[0x6A, 0x01, 0, 0x13, 0x29, 0x03, 30]);
+        // adc_P = 415148, adc_T = 519888, adc_H = 30000
+        i2c.set_registers(REG_DATA, &[0x65, 0x5A, 0xC0, 0x7E, 0xED, 0x00, 0x75, 0x30]);
+        i2c
+    }
+
+    /// Floating point humidity compensation of the datasheet.
+    fn humidity(t_fine: f64, adc_h: f64) -> f64 {
+        let (h1, h2, h3, h4, h5, h6) = (75.0, 362.0, 0.0, 313.0, 50.0, 30.0);
+        let h = t_fine - 76800.0;
+        let h = (adc_h - (h4 * 64.0 + h5 / 16384.0 * h))
+            * (h2 / 65536.0 * (1.0 + h6 / 67108864.0 * h * (1.0 + h3 / 67108864.0 * h)));
+        (h * (1.0 - h1 * h / 524288.0)).clamp(0.0, 100.0)
+    }
+
+    #[test]
+    fn parses_the_calibration() {
+        let mut i2c = sensor();
+        let bme280 = Bme280::new(&mut i2c, DEFAULT_ADDRESS, &mut NoDelay).unwrap();
+        let calibration = bme280.calibration;
+        assert_eq!(
+            (calibration.t1, calibration.t2, calibration.t3),
+            (27504, 26435, -1000)
+        );
+        assert_eq!((calibration.p1, calibration.p9), (36477, 6000));
+        assert_eq!(
+            (calibration.h1, calibration.h2, calibration.h3),
+            (75, 362, 0)
+        );
+        assert_eq!(
+            (calibration.h4, calibration.h5, calibration.h6),
+            (313, 50, 30)
+        );
+    }
+
+    #[test]
+    fn compensates_a_measurement() {
+        let mut i2c = sensor();
+        let mut bme280 = Bme280::new(&mut i2c, DEFAULT_ADDRESS, &mut NoDelay).unwrap();
+        let measurement = bme280.measure(&mut NoDelay).unwrap();
+
+        // Results of the datasheet example: 25.08 °C and 1006.53 hPa
+        assert_eq!(measurement.temperature, 25.08);
+        assert!((measurement.pressure - 1006.53).abs() < 0.01);
+        let expected = humidity(128422.0, 30000.0) as f32;
+        assert!((measurement.humidity - expected).abs() < 0.01);
+    }
+
+    #[test]
+    fn configures_forced_mode() {
+        let mut i2c = sensor();
+        let mut bme280 = Bme280::new(&mut i2c, DEFAULT_ADDRESS, &mut NoDelay).unwrap();
+        bme280.measure(&mut NoDelay).unwrap();
+
+        assert_eq!(i2c.writes[1], [REG_RESET, RESET_COMMAND]);
+        let ctrl_hum = i2c
+            .writes
+            .iter()
+            .position(|w| w[..] == [REG_CTRL_HUM, 0b001]);
+        let ctrl_meas = i2c
+            .writes
+            .iter()
+            .position(|w| w[..] == [REG_CTRL_MEAS, 0b0010_0101]);
+        // The humidity oversampling is applied by the write of ctrl_meas
+        assert!(ctrl_hum.unwrap() < ctrl_meas.unwrap());
+    }
+
+    #[test]
+    fn rejects_another_chip() {
+        let mut i2c = sensor();
+        i2c.set_registers(REG_CHIP_ID, &[0x58]);
+        assert!(matches!(
+            Bme280::new(&mut i2c, DEFAULT_ADDRESS, &mut NoDelay),
+            Err(SensorError::InvalidChipId(0x58))
+        ));
+    }
+
+    #[test]
+    fn missing_sensor() {
+        let mut i2c = sensor();
+        assert!(matches!(
+            Bme280::new(&mut i2c, SECONDARY_ADDRESS, &mut NoDelay),
+            Err(SensorError::Bus(_))
+        ));
+    }
+
+    #[test]
+    fn measurement_timeout() {
+        let mut i2c = sensor();
+        let mut bme280 = Bme280::new(&mut i2c, DEFAULT_ADDRESS, &mut NoDelay).unwrap();
+        // The sensor never finishes measuring
+        bme280.i2c.set_registers(REG_STATUS, &[STATUS_MEASURING]);
+        assert!(matches!(
+            bme280.measure(&mut NoDelay),
+            Err(SensorError::Timeout)
+        ));
+    }
+}
@@ -0,0 +1,238 @@
+//! Driver for the Maxim DS3231 real-time clock.
+//!
+//! The clock keeps the time on its backup battery while the hub is off. The
+//! time is kept in UTC, in 24 hours mode, with a resolution of one second.
+
+use embedded_hal::i2c::I2c;
+
+use super::SensorError;
+use crate::utilities::timestamp::{civil_from_days, days_from_civil};
+
+/// I2C address of the DS3231.
+pub const DEFAULT_ADDRESS: u8 = 0x68;
+
+const REGISTER_SECONDS: u8 = 0x00;
+const REGISTER_STATUS: u8 = 0x0F;
+/// Oscillator stop flag, set when the clock stopped (battery removed)
+const STATUS_OSF: u8 = 0x80;
+/// 12 hours mode bit of the hours register
+const HOURS_12: u8 = 0x40;
+const HOURS_PM: u8 = 0x20;
+/// Century bit of the month register
+const MONTH_CENTURY: u8 = 0x80;
+
+/// DS3231 driver.
+pub struct Ds3231<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C: I2c> Ds3231<I2C> {
+    /// Check that the clock answers.
+    pub fn new(i2c: I2C, address: u8) -> Result<Self, SensorError<I2C::Error>> {
+        let mut ds3231 = Self { i2c, address };
+        ds3231.read_status()?;
+        Ok(ds3231)
+    }
+
+    /// Time in ms since the UNIX epoch, `None` if the clock stopped since it
+    /// was last set.
+    pub fn time(&mut self) -> Result<Option<u64>, SensorError<I2C::Error>> {
+        if self.read_status()? & STATUS_OSF != 0 {
+            return Ok(None);
+        }
+
+        let mut registers = [0u8; 7];
+        self.i2c
+            .write_read(self.address, &[REGISTER_SECONDS], &mut registers)?;
+        let [seconds, minutes, hours, _, day, month, year] = registers;
+
+        let hours = if hours & HOURS_12 != 0 {
+            from_bcd(hours & 0x1F) % 12 + if hours & HOURS_PM != 0 { 12 } else { 0 }
+        } else {
+            from_bcd(hours & 0x3F)
+        };
+        let century = if month & MONTH_CENTURY != 0 {
+            2100
+        } else {
+            2000
+        };
+        let (year, month, day) = (
+            century + from_bcd(year),
+            from_bcd(month & 0x1F),
+            from_bcd(day & 0x3F),
+        );
+        let (minutes, seconds) = (from_bcd(minutes & 0x7F), from_bcd(seconds & 0x7F));
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hours > 23 || minutes > 59 {
+            return Err(SensorError::InvalidResponse);
+        }
+
+        let days = days_from_civil(year, month, day);
+        Ok(Some(
+            (((days * 24 + hours) * 60 + minutes) * 60 + seconds) * 1000,
+        ))
+    }
+
+    /// Set the time in ms since the UNIX epoch, from 2000 to 2199.
+    pub fn set_time(&mut self, time: u64) -> Result<(), SensorError<I2C::Error>> {
+        let seconds = time / 1000;
+        let days = seconds / 86_400;
+        let (year, month, day) = civil_from_days(days);
+        if !(2000..2200).contains(&year) {
+            return Err(SensorError::InvalidResponse);
+        }
+        let century = if year >= 2100 { MONTH_CENTURY } else { 0 };
+        // Day of the week from 1 (Monday), the 1st of January 1970 was a Thursday
+        let weekday = (days + 3) % 7 + 1;
+
+        self.i2c.write(
+            self.address,
+            &[
+                REGISTER_SECONDS,
+                to_bcd(seconds % 60),
+                to_bcd(seconds / 60 % 60),
+                to_bcd(seconds / 3600 % 24),
+                weekday as u8,
+                to_bcd(day),
+                to_bcd(month) | century,
+                to_bcd(year % 100),
+            ],
+        )?;
+
+        // The time is valid again
+        let status = self.read_status()?;
+        self.i2c
+            .write(self.address, &[REGISTER_STATUS, status & !STATUS_OSF])?;
+        Ok(())
+    }
+
+    /// Release the I2C bus.
+    pub fn release(self) -> I2C {
+        self.i2c
+    }
+
+    fn read_status(&mut self) -> Result<u8, SensorError<I2C::Error>> {
+        let mut status = [0u8];
+        self.i2c
+            .write_read(self.address, &[REGISTER_STATUS], &mut status)?;
+        Ok(status[0])
+    }
+}
+
+fn from_bcd(value: u8) -> u64 {
+    (value >> 4) as u64 * 10 + (value & 0x0F) as u64
+}
+
+fn to_bcd(value: u64) -> u8 {
+    (((value / 10) << 4) | (value % 10)) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal::i2c::ErrorKind;
+
+    use super::*;
+    use crate::sensors::mock::MockI2c;
+
+    /// 2026-10-18 12:34:56 UTC, a Sunday
+    const TIME: u64 = 1_792_326_896_000;
+
+    fn ds3231() -> Ds3231<MockI2c> {
+        Ds3231::new(MockI2c::new(DEFAULT_ADDRESS), DEFAULT_ADDRESS).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut ds3231 = ds3231();
+        // The milliseconds are dropped
+        ds3231.set_time(TIME + 789).unwrap();
+
+        assert_eq!(
+            ds3231.i2c.registers[..7],
+            [0x56, 0x34, 0x12, 7, 0x18, 0x10, 0x26]
+        );
+        assert_eq!(ds3231.time().unwrap(), Some(TIME));
+    }
+
+    #[test]
+    fn leap_day() {
+        let mut ds3231 = ds3231();
+        // 2028-02-29 23:59:59 UTC, a Tuesday
+        ds3231.set_time(1_835_481_599_000).unwrap();
+
+        assert_eq!(
+            ds3231.i2c.registers[..7],
+            [0x59, 0x59, 0x23, 2, 0x29, 0x02, 0x28]
+        );
+        assert_eq!(ds3231.time().unwrap(), Some(1_835_481_599_000));
+    }
+
+    #[test]
+    fn century() {
+        let mut ds3231 = ds3231();
+        // 2100-01-01 00:00:00 UTC, a Friday
+        ds3231.set_time(4_102_444_800_000).unwrap();
+        assert_eq!(
+            ds3231.i2c.registers[..7],
+            [0x00, 0x00, 0x00, 5, 0x01, MONTH_CENTURY | 0x01, 0x00]
+        );
+        assert_eq!(ds3231.time().unwrap(), Some(4_102_444_800_000));
+
+        for time in [946_684_800_000, 4_102_444_799_000, 7_258_118_399_000] {
+            ds3231.set_time(time).unwrap();
+            assert_eq!(ds3231.time().unwrap(), Some(time));
+        }
+        // 1999-12-31 23:59:59 and 2200-01-01 00:00:00
+        assert!(ds3231.set_time(946_684_799_000).is_err());
+        assert!(ds3231.set_time(7_258_118_400_000).is_err());
+    }
+
+    #[test]
+    fn twelve_hours_mode() {
+        let mut ds3231 = ds3231();
+        ds3231.set_time(TIME).unwrap();
+
+        // 12:34:56 PM
+        ds3231.i2c.registers[2] = HOURS_12 | HOURS_PM | 0x12;
+        assert_eq!(ds3231.time().unwrap(), Some(TIME));
+        // 12:34:56 AM, after midnight
+        ds3231.i2c.registers[2] = HOURS_12 | 0x12;
+        assert_eq!(ds3231.time().unwrap(), Some(TIME - 12 * 3_600_000));
+        // 11:34:56 PM
+        ds3231.i2c.registers[2] = HOURS_12 | HOURS_PM | 0x11;
+        assert_eq!(ds3231.time().unwrap(), Some(TIME + 11 * 3_600_000));
+    }
+
+    #[test]
+    fn stopped_oscillator() {
+        let mut i2c = MockI2c::new(DEFAULT_ADDRESS);
+        // Oscillator stopped, 32 kHz output enabled
+        i2c.registers[REGISTER_STATUS as usize] = STATUS_OSF | 0x08;
+        let mut ds3231 = Ds3231::new(i2c, DEFAULT_ADDRESS).unwrap();
+        assert_eq!(ds3231.time().unwrap(), None);
+
+        // Setting the time clears the flag only
+        ds3231.set_time(TIME).unwrap();
+        assert_eq!(ds3231.i2c.registers[REGISTER_STATUS as usize], 0x08);
+        assert_eq!(ds3231.time().unwrap(), Some(TIME));
+    }
+
+    #[test]
+    fn invalid_registers() {
+        let mut ds3231 = ds3231();
+        ds3231.set_time(TIME).unwrap();
+
+        // Month 13
+        ds3231.i2c.registers[5] = 0x13;
+        assert!(matches!(ds3231.time(), Err(SensorError::InvalidResponse)));
+    }
+
+    #[test]
+    fn missing_device() {
+        let i2c = MockI2c::new(DEFAULT_ADDRESS + 1);
+        assert!(matches!(
+            Ds3231::new(i2c, DEFAULT_ADDRESS),
+            Err(SensorError::Bus(ErrorKind::NoAcknowledge(_)))
+        ));
+    }
+}
@@ -0,0 +1,88 @@
+//! I2C device simulated in memory, for the tests of the drivers.
+
+use std::collections::VecDeque;
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::{ErrorKind, ErrorType, I2c, NoAcknowledgeSource, Operation};
+
+/// Device answering at a single address.
+///
+/// The first byte of each write selects a register and the next bytes are
+/// written from it, the reads continue from the selected register, like most
+/// register based devices. The command based devices are simulated by
+/// queuing the data of their reads.
+pub struct MockI2c {
+    address: u8,
+    pub registers: [u8; 256],
+    pointer: u8,
+    /// Data of the next reads, instead of the registers
+    pub reads: VecDeque<Vec<u8>>,
+    /// Data of every write, in order
+    pub writes: Vec<Vec<u8>>,
+}
+
+impl MockI2c {
+    pub fn new(address: u8) -> Self {
+        Self {
+            address,
+            registers: [0; 256],
+            pointer: 0,
+            reads: VecDeque::new(),
+            writes: Vec::new(),
+        }
+    }
+
+    /// Set the registers from `register`.
+    pub fn set_registers(&mut self, register: u8, values: &[u8]) {
+        for (offset, value) in values.iter().enumerate() {
+            self.registers[register as usize + offset] = *value;
+        }
+    }
+}
+
+impl ErrorType for MockI2c {
+    type Error = ErrorKind;
+}
+
+impl I2c for MockI2c {
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        if address != self.address {
+            return Err(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address));
+        }
+        for operation in operations {
+            match operation {
+                Operation::Write(data) => {
+                    self.writes.push(data.to_vec());
+                    if let Some((&register, values)) = data.split_first() {
+                        self.pointer = register;
+                        for value in values {
+                            self.registers[self.pointer as usize] = *value;
+                            self.pointer = self.pointer.wrapping_add(1);
+                        }
+                    }
+                }
+                Operation::Read(buffer) => match self.reads.pop_front() {
+                    Some(data) => buffer.copy_from_slice(&data[..buffer.len()]),
+                    None => {
+                        for byte in buffer.iter_mut() {
+                            *byte = self.registers[self.pointer as usize];
+                            self.pointer = self.pointer.wrapping_add(1);
+                        }
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Delay returning at once.
+pub struct NoDelay;
+
+impl DelayNs for NoDelay {
+    fn delay_ns(&mut self, _ns: u32) {}
+}
@@ -0,0 +1,36 @@
+//! Drivers of the sensors connected to the slaves, and of the real-time
+//! clock of the master.
+
+use core::fmt::Debug;
+
+pub mod bme280;
+pub mod ds3231;
+#[cfg(test)]
+mod mock;
+pub mod pulse_meter;
+pub mod pzem004t;
+pub mod scd4x;
+mod sensirion;
+pub mod sgp30;
+pub mod sht3x;
+
+/// Error of a sensor driver.
+#[derive(Debug)]
+pub enum SensorError<E> {
+    /// Error of the underlying bus
+    Bus(E),
+    /// The device answering at the address is not the expected one
+    InvalidChipId(u8),
+    /// The checksum of the received data is wrong
+    Crc,
+    /// The device answered with an unexpected or error response
+    InvalidResponse,
+    /// The sensor did not complete the operation in time
+    Timeout,
+}
+
+impl<E: embedded_hal::i2c::Error + Debug> From<E> for SensorError<E> {
+    fn from(error: E) -> Self {
+        SensorError::Bus(error)
+    }
+}
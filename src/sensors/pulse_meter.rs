@@ -0,0 +1,125 @@
+//! Energy meter counting the pulses of the LED or S0 output of a utility meter.
+//!
+//! The counting of the pulses is done by the hardware, this module only turns
+//! them into energy and power.
+
+use core::time::Duration;
+
+/// Reading of the meter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reading {
+    /// Average power since the previous reading, in W
+    pub power: f32,
+    /// Total energy, in Wh
+    pub energy: u64,
+}
+
+/// Pulse energy meter.
+#[derive(Debug, Clone)]
+pub struct PulseMeter {
+    pulses_per_kwh: u32,
+    total_pulses: u64,
+}
+
+impl PulseMeter {
+    /// Create a meter with the constant printed on the utility meter (e.g.
+    /// 1000 imp/kWh), starting from the pulses counted before a reboot.
+    /// `None` if the constant is zero.
+    pub fn new(pulses_per_kwh: u32, total_pulses: u64) -> Option<Self> {
+        if pulses_per_kwh == 0 {
+            return None;
+        }
+        Some(Self {
+            pulses_per_kwh,
+            total_pulses,
+        })
+    }
+
+    /// Total pulses counted, to be stored across reboots.
+    pub fn total_pulses(&self) -> u64 {
+        self.total_pulses
+    }
+
+    /// Add the pulses counted during `elapsed`.
+    pub fn add_pulses(&mut self, pulses: u32, elapsed: Duration) -> Reading {
+        self.total_pulses += pulses as u64;
+
+        let power = if elapsed.is_zero() {
+            0.0
+        } else {
+            // 1 kWh = 3 600 000 W·s
+            pulses as f32 * 3_600_000.0 / (self.pulses_per_kwh as f32 * elapsed.as_secs_f32())
+        };
+
+        Reading {
+            power,
+            energy: self.energy(),
+        }
+    }
+
+    /// Total energy, in Wh, rounded down.
+    pub fn energy(&self) -> u64 {
+        self.total_pulses * 1000 / self.pulses_per_kwh as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_a_zero_constant() {
+        assert!(PulseMeter::new(0, 0).is_none());
+    }
+
+    #[test]
+    fn computes_the_power() {
+        let mut meter = PulseMeter::new(1000, 0).unwrap();
+        // 10 Wh in 60 s
+        let reading = meter.add_pulses(10, Duration::from_secs(60));
+        assert!((reading.power - 600.0).abs() < 0.01);
+        assert_eq!(reading.energy, 10);
+
+        // 1 Wh in 1.5 s
+        let reading = meter.add_pulses(1, Duration::from_millis(1500));
+        assert!((reading.power - 2400.0).abs() < 0.01);
+        assert_eq!(reading.energy, 11);
+    }
+
+    #[test]
+    fn no_power_without_elapsed_time() {
+        let mut meter = PulseMeter::new(1000, 0).unwrap();
+        let reading = meter.add_pulses(5, Duration::ZERO);
+        assert_eq!(reading.power, 0.0);
+        // The pulses are still counted
+        assert_eq!(reading.energy, 5);
+        assert_eq!(meter.total_pulses(), 5);
+    }
+
+    #[test]
+    fn accumulates_the_restored_pulses() {
+        let mut meter = PulseMeter::new(1000, 123_456).unwrap();
+        assert_eq!(meter.energy(), 123_456);
+
+        meter.add_pulses(44, Duration::from_secs(10));
+        let reading = meter.add_pulses(500, Duration::from_secs(10));
+        assert_eq!(meter.total_pulses(), 124_000);
+        assert_eq!(reading.energy, 124_000);
+        assert!((reading.power - 180_000.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn rounds_the_energy_down() {
+        // 1 pulse is 1.25 Wh
+        let meter = PulseMeter::new(800, 3).unwrap();
+        assert_eq!(meter.energy(), 3);
+        let meter = PulseMeter::new(800, 4).unwrap();
+        assert_eq!(meter.energy(), 5);
+
+        // 1 pulse is 0.1 Wh
+        let meter = PulseMeter::new(10_000, 19).unwrap();
+        assert_eq!(meter.energy(), 1);
+        let meter = PulseMeter::new(10_000, 9).unwrap();
+        assert_eq!(meter.energy(), 0);
+    }
+}
@@ -0,0 +1,232 @@
+//! Driver for the Peacefair PZEM-004T v3 AC energy monitor.
+//!
+//! The module speaks Modbus-RTU over a 9600 baud UART (8N1). All the
+//! measurements are read at once from its 10 input registers. The energy is
+//! accumulated by the module itself and kept across power cycles.
+
+use embedded_io::{Read, ReadExactError, Write};
+
+use super::SensorError;
+
+/// General address, answered by any module. Usable with a single module on the bus.
+pub const DEFAULT_ADDRESS: u8 = 0xF8;
+/// Baud rate of the UART.
+pub const BAUD_RATE: u32 = 9600;
+
+const FUNCTION_READ_INPUT_REGISTERS: u8 = 0x04;
+/// Flag set in the function code of an exception response
+const EXCEPTION_FLAG: u8 = 0x80;
+/// Number of input registers holding the measurements
+const REGISTER_COUNT: u16 = 10;
+
+/// Measurement of the module.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Measurement {
+    /// Voltage in V
+    pub voltage: f32,
+    /// Current in A
+    pub current: f32,
+    /// Active power in W
+    pub power: f32,
+    /// Active energy in Wh, since the last reset of the module
+    pub energy: u32,
+    /// Frequency in Hz
+    pub frequency: f32,
+    /// Power factor
+    pub power_factor: f32,
+}
+
+/// PZEM-004T driver.
+pub struct Pzem004t<S> {
+    serial: S,
+    address: u8,
+}
+
+impl<S: Read + Write> Pzem004t<S> {
+    /// Create the driver. The serial must return 0 bytes when a read times out.
+    pub fn new(serial: S, address: u8) -> Self {
+        Self { serial, address }
+    }
+
+    /// Read all the measurements.
+    pub fn measure(&mut self) -> Result<Measurement, SensorError<S::Error>> {
+        let mut request = [0u8; 8];
+        request[0] = self.address;
+        request[1] = FUNCTION_READ_INPUT_REGISTERS;
+        // Starting register
+        request[2..4].copy_from_slice(&0u16.to_be_bytes());
+        request[4..6].copy_from_slice(&REGISTER_COUNT.to_be_bytes());
+        let crc = crc16(&request[..6]);
+        request[6..].copy_from_slice(&crc.to_le_bytes());
+        self.serial.write_all(&request).map_err(SensorError::Bus)?;
+        self.serial.flush().map_err(SensorError::Bus)?;
+
+        // Address, function and length (or exception code)
+        let mut response = [0u8; 3 + 2 * REGISTER_COUNT as usize + 2];
+        self.read_exact(&mut response[..3])?;
+        if response[1] & EXCEPTION_FLAG != 0 {
+            // Read the CRC too, it would be taken as the start of the next
+            // response
+            self.read_exact(&mut response[3..5])?;
+            if crc16(&response[..3]) != u16::from_le_bytes([response[3], response[4]]) {
+                return Err(SensorError::Crc);
+            }
+            return Err(SensorError::InvalidResponse);
+        }
+        if response[1] != FUNCTION_READ_INPUT_REGISTERS || response[2] as u16 != 2 * REGISTER_COUNT
+        {
+            return Err(SensorError::InvalidResponse);
+        }
+        self.read_exact(&mut response[3..])?;
+
+        let (payload, crc) = response.split_at(response.len() - 2);
+        if crc16(payload) != u16::from_le_bytes([crc[0], crc[1]]) {
+            return Err(SensorError::Crc);
+        }
+
+        Ok(parse_registers(&payload[3..]))
+    }
+
+    /// Release the serial.
+    pub fn release(self) -> S {
+        self.serial
+    }
+
+    fn read_exact(&mut self, buffer: &mut [u8]) -> Result<(), SensorError<S::Error>> {
+        self.serial.read_exact(buffer).map_err(|error| match error {
+            ReadExactError::UnexpectedEof => SensorError::Timeout,
+            ReadExactError::Other(error) => SensorError::Bus(error),
+        })
+    }
+}
+
+/// Parse the input registers. The 32 bits values have the low word first.
+fn parse_registers(data: &[u8]) -> Measurement {
+    let register = |i: usize| u16::from_be_bytes([data[2 * i], data[2 * i + 1]]) as u32;
+    let register_32 = |i: usize| register(i) | (register(i + 1) << 16);
+
+    Measurement {
+        voltage: register(0) as f32 / 10.0,
+        current: register_32(1) as f32 / 1000.0,
+        power: register_32(3) as f32 / 10.0,
+        energy: register_32(5),
+        frequency: register(7) as f32 / 10.0,
+        power_factor: register(8) as f32 / 100.0,
+    }
+}
+
+/// Modbus CRC-16 (polynomial 0xA001 reflected, initialization 0xFFFF).
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_io::{ErrorType, Read, Write};
+
+    use super::*;
+
+    /// Serial returning the queued bytes, 0 bytes once empty.
+    struct MockSerial {
+        input: Vec<u8>,
+        output: Vec<u8>,
+    }
+
+    impl ErrorType for MockSerial {
+        type Error = core::convert::Infallible;
+    }
+
+    impl Read for MockSerial {
+        fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+            let len = buffer.len().min(self.input.len());
+            buffer[..len].copy_from_slice(&self.input[..len]);
+            self.input.drain(..len);
+            Ok(len)
+        }
+    }
+
+    impl Write for MockSerial {
+        fn write(&mut self, buffer: &[u8]) -> Result<usize, Self::Error> {
+            self.output.extend_from_slice(buffer);
+            Ok(buffer.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    fn with_crc(mut frame: Vec<u8>) -> Vec<u8> {
+        let crc = crc16(&frame);
+        frame.extend_from_slice(&crc.to_le_bytes());
+        frame
+    }
+
+    fn pzem(input: Vec<u8>) -> Pzem004t<MockSerial> {
+        Pzem004t::new(
+            MockSerial {
+                input,
+                output: Vec::new(),
+            },
+            1,
+        )
+    }
+
+    #[test]
+    fn measures() {
+        // 230.0 V, 1.234 A, 283.8 W, 12345 Wh, 50.0 Hz, 0.99
+        let response = with_crc(vec![
+            0x01, 0x04, 20, 0x08, 0xFC, 0x04, 0xD2, 0x00, 0x00, 0x0B, 0x16, 0x00, 0x00, 0x30, 0x39,
+            0x00, 0x00, 0x01, 0xF4, 0x00, 0x63, 0x00, 0x00,
+        ]);
+        let mut pzem = pzem(response);
+        let measurement = pzem.measure().unwrap();
+        assert_eq!(measurement.voltage, 230.0);
+        assert_eq!(measurement.current, 1.234);
+        assert_eq!(measurement.power, 283.8);
+        assert_eq!(measurement.energy, 12345);
+        assert_eq!(measurement.frequency, 50.0);
+        assert_eq!(measurement.power_factor, 0.99);
+        assert_eq!(
+            pzem.release().output,
+            [0x01, 0x04, 0x00, 0x00, 0x00, 0x0A, 0x70, 0x0D]
+        );
+    }
+
+    #[test]
+    fn exception_consumes_its_crc() {
+        let mut input = with_crc(vec![0x01, 0x84, 0x02]);
+        let next = with_crc(vec![0x01, 0x84, 0x03]);
+        input.extend_from_slice(&next);
+        let mut pzem = pzem(input);
+
+        assert!(matches!(pzem.measure(), Err(SensorError::InvalidResponse)));
+        assert_eq!(pzem.serial.input, next);
+    }
+
+    #[test]
+    fn exception_with_a_wrong_crc() {
+        let mut input = with_crc(vec![0x01, 0x84, 0x02]);
+        input[4] ^= 1;
+        assert!(matches!(pzem(input).measure(), Err(SensorError::Crc)));
+    }
+
+    #[test]
+    fn timeout() {
+        assert!(matches!(
+            pzem(Vec::new()).measure(),
+            Err(SensorError::Timeout)
+        ));
+    }
+}
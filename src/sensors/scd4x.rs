@@ -0,0 +1,183 @@
+//! Driver for the Sensirion SCD4x (SCD40, SCD41) photoacoustic CO2 sensor.
+//!
+//! The sensor runs in periodic measurement mode, providing a new measurement
+//! every 5 seconds.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+
+use super::sensirion;
+use super::SensorError;
+
+/// I2C address of the sensor.
+pub const DEFAULT_ADDRESS: u8 = 0x62;
+
+const COMMAND_START_PERIODIC_MEASUREMENT: u16 = 0x21B1;
+const COMMAND_STOP_PERIODIC_MEASUREMENT: u16 = 0x3F86;
+const COMMAND_GET_DATA_READY_STATUS: u16 = 0xE4B8;
+const COMMAND_READ_MEASUREMENT: u16 = 0xEC05;
+const COMMAND_GET_SERIAL_NUMBER: u16 = 0x3682;
+
+/// Time needed to stop the periodic measurement (ms)
+const STOP_TIME_MS: u32 = 500;
+/// Execution time of the read commands (ms)
+const COMMAND_TIME_MS: u32 = 1;
+/// Bits of the data ready status that are set when a measurement is available
+const DATA_READY_MASK: u16 = 0x07FF;
+
+/// Measurement of the sensor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Measurement {
+    /// CO2 concentration in ppm
+    pub co2: u16,
+    /// Temperature in °C
+    pub temperature: f32,
+    /// Relative humidity in %
+    pub humidity: f32,
+}
+
+/// SCD4x driver.
+pub struct Scd4x<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C: I2c> Scd4x<I2C> {
+    /// Check the sensor and start the periodic measurement.
+    pub fn new<D: DelayNs>(
+        i2c: I2C,
+        address: u8,
+        delay: &mut D,
+    ) -> Result<Self, SensorError<I2C::Error>> {
+        let mut scd4x = Self { i2c, address };
+
+        // The measurement may still be running since the last boot, and the
+        // sensor accepts no other command in that state
+        scd4x.command(COMMAND_STOP_PERIODIC_MEASUREMENT)?;
+        delay.delay_ms(STOP_TIME_MS);
+
+        // The serial number is protected by a CRC, reading it checks the sensor
+        scd4x.command(COMMAND_GET_SERIAL_NUMBER)?;
+        delay.delay_ms(COMMAND_TIME_MS);
+        sensirion::read_words::<_, 3>(&mut scd4x.i2c, scd4x.address)?;
+
+        scd4x.command(COMMAND_START_PERIODIC_MEASUREMENT)?;
+
+        Ok(scd4x)
+    }
+
+    /// Read the last measurement, if a new one is available.
+    pub fn read<D: DelayNs>(
+        &mut self,
+        delay: &mut D,
+    ) -> Result<Option<Measurement>, SensorError<I2C::Error>> {
+        self.command(COMMAND_GET_DATA_READY_STATUS)?;
+        delay.delay_ms(COMMAND_TIME_MS);
+        let [status] = sensirion::read_words(&mut self.i2c, self.address)?;
+        if status & DATA_READY_MASK == 0 {
+            return Ok(None);
+        }
+
+        self.command(COMMAND_READ_MEASUREMENT)?;
+        delay.delay_ms(COMMAND_TIME_MS);
+        let [co2, raw_temperature, raw_humidity] =
+            sensirion::read_words(&mut self.i2c, self.address)?;
+
+        Ok(Some(Measurement {
+            co2,
+            temperature: -45.0 + 175.0 * raw_temperature as f32 / 65535.0,
+            humidity: 100.0 * raw_humidity as f32 / 65535.0,
+        }))
+    }
+
+    /// Stop the periodic measurement and release the I2C bus.
+    pub fn release(mut self) -> Result<I2C, SensorError<I2C::Error>> {
+        self.command(COMMAND_STOP_PERIODIC_MEASUREMENT)?;
+        Ok(self.i2c)
+    }
+
+    fn command(&mut self, command: u16) -> Result<(), SensorError<I2C::Error>> {
+        sensirion::command(&mut self.i2c, self.address, command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mock::{MockI2c, NoDelay};
+    use super::*;
+
+    /// Words each followed by their CRC.
+    fn words(values: &[u16]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| {
+                let [msb, lsb] = value.to_be_bytes();
+                [msb, lsb, sensirion::crc8(&[msb, lsb])]
+            })
+            .collect()
+    }
+
+    fn sensor() -> MockI2c {
+        let mut i2c = MockI2c::new(DEFAULT_ADDRESS);
+        // Serial number
+        i2c.reads.push_back(words(&[0xF896, 0x9F07, 0x3BB3]));
+        i2c
+    }
+
+    #[test]
+    fn starts_the_periodic_measurement() {
+        let mut i2c = sensor();
+        Scd4x::new(&mut i2c, DEFAULT_ADDRESS, &mut NoDelay).unwrap();
+        assert_eq!(
+            i2c.writes,
+            [vec![0x3F, 0x86], vec![0x36, 0x82], vec![0x21, 0xB1]]
+        );
+    }
+
+    #[test]
+    fn reads_a_measurement() {
+        let mut i2c = sensor();
+        let mut scd4x = Scd4x::new(&mut i2c, DEFAULT_ADDRESS, &mut NoDelay).unwrap();
+        // Data ready status, then 500 ppm, 25 °C and 50 %
+        scd4x.i2c.reads.push_back(words(&[0x8006]));
+        scd4x.i2c.reads.push_back(words(&[500, 0x6666, 0x8000]));
+
+        let measurement = scd4x.read(&mut NoDelay).unwrap().unwrap();
+        assert_eq!(measurement.co2, 500);
+        assert!((measurement.temperature - 25.0).abs() < 0.01);
+        assert!((measurement.humidity - 50.0).abs() < 0.01);
+        assert_eq!(i2c.writes[3..], [vec![0xE4, 0xB8], vec![0xEC, 0x05]]);
+    }
+
+    #[test]
+    fn waits_for_the_data_ready_status() {
+        let mut i2c = sensor();
+        let mut scd4x = Scd4x::new(&mut i2c, DEFAULT_ADDRESS, &mut NoDelay).unwrap();
+        // Only the bits of the mask tell that the data is ready
+        scd4x.i2c.reads.push_back(words(&[0x8000]));
+
+        assert_eq!(scd4x.read(&mut NoDelay).unwrap(), None);
+        // The measurement is not read
+        assert_eq!(i2c.writes[3..], [vec![0xE4, 0xB8]]);
+    }
+
+    #[test]
+    fn rejects_a_wrong_crc() {
+        let mut i2c = MockI2c::new(DEFAULT_ADDRESS);
+        let mut serial = words(&[0xF896, 0x9F07, 0x3BB3]);
+        serial[5] ^= 0x01;
+        i2c.reads.push_back(serial);
+        assert!(matches!(
+            Scd4x::new(&mut i2c, DEFAULT_ADDRESS, &mut NoDelay),
+            Err(SensorError::Crc)
+        ));
+    }
+
+    #[test]
+    fn stops_the_measurement_on_release() {
+        let mut i2c = sensor();
+        let scd4x = Scd4x::new(&mut i2c, DEFAULT_ADDRESS, &mut NoDelay).unwrap();
+        scd4x.release().unwrap();
+        assert_eq!(i2c.writes.last().unwrap(), &[0x3F, 0x86]);
+    }
+}
@@ -0,0 +1,99 @@
+//! Helpers shared by the Sensirion sensors, which use 16 bits commands and
+//! 16 bits words protected by a CRC.
+
+use embedded_hal::i2c::I2c;
+
+use super::SensorError;
+
+/// Send a command to the sensor.
+pub(crate) fn command<I2C: I2c>(
+    i2c: &mut I2C,
+    address: u8,
+    command: u16,
+) -> Result<(), SensorError<I2C::Error>> {
+    i2c.write(address, &command.to_be_bytes())?;
+    Ok(())
+}
+
+/// Max number of words read at once.
+const MAX_WORDS: usize = 3;
+
+/// Compile time check of the number of words read.
+struct WordCount<const N: usize>;
+
+impl<const N: usize> WordCount<N> {
+    const VALID: () = assert!(N >= 1 && N <= MAX_WORDS, "read at most 3 words");
+}
+
+/// Read `N` words (at most 3) from the sensor, checking their CRC.
+pub(crate) fn read_words<I2C: I2c, const N: usize>(
+    i2c: &mut I2C,
+    address: u8,
+) -> Result<[u16; N], SensorError<I2C::Error>> {
+    let () = WordCount::<N>::VALID;
+
+    // Each word is followed by its CRC
+    let mut data = [0u8; MAX_WORDS * 3];
+    let data = &mut data[..N * 3];
+    i2c.read(address, data)?;
+
+    let mut words = [0u16; N];
+    for (word, chunk) in words.iter_mut().zip(data.chunks(3)) {
+        *word = check_word(chunk)?;
+    }
+    Ok(words)
+}
+
+/// Check the CRC of a word (2 bytes followed by their CRC) and return it.
+fn check_word<E>(data: &[u8]) -> Result<u16, SensorError<E>> {
+    if crc8(&data[..2]) != data[2] {
+        return Err(SensorError::Crc);
+    }
+    Ok(u16::from_be_bytes([data[0], data[1]]))
+}
+
+/// CRC-8 with polynomial 0x31 and initialization 0xFF.
+pub(super) fn crc8(data: &[u8]) -> u8 {
+    let mut crc: u8 = 0xFF;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mock::MockI2c;
+    use super::*;
+
+    #[test]
+    fn crc_of_the_datasheet() {
+        assert_eq!(crc8(&[0xBE, 0xEF]), 0x92);
+    }
+
+    #[test]
+    fn reads_the_max_words() {
+        let mut i2c = MockI2c::new(0x62);
+        i2c.reads
+            .push_back(vec![0xBE, 0xEF, 0x92, 0x00, 0x00, 0x81, 0xBE, 0xEF, 0x92]);
+        let words = read_words::<_, 3>(&mut i2c, 0x62).unwrap();
+        assert_eq!(words, [0xBEEF, 0x0000, 0xBEEF]);
+    }
+
+    #[test]
+    fn rejects_a_wrong_crc() {
+        let mut i2c = MockI2c::new(0x62);
+        i2c.reads.push_back(vec![0xBE, 0xEF, 0x93]);
+        assert!(matches!(
+            read_words::<_, 1>(&mut i2c, 0x62),
+            Err(SensorError::Crc)
+        ));
+    }
+}
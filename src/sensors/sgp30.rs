@@ -0,0 +1,158 @@
+//! Driver for the Sensirion SGP30 VOC and equivalent CO2 sensor.
+//!
+//! The sensor runs a dynamic baseline compensation, so [`Sgp30::measure`]
+//! must be called every second. The first 15 seconds after the
+//! initialization the sensor returns fixed values (400 ppm, 0 ppb).
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+
+use super::sensirion;
+use super::SensorError;
+
+/// I2C address of the sensor.
+pub const DEFAULT_ADDRESS: u8 = 0x58;
+
+const COMMAND_GET_FEATURE_SET: u16 = 0x202F;
+const COMMAND_INIT_AIR_QUALITY: u16 = 0x2003;
+const COMMAND_MEASURE_AIR_QUALITY: u16 = 0x2008;
+
+/// Product type of the SGP30 in the feature set
+const PRODUCT_TYPE: u16 = 0;
+/// Execution time of the feature set command (ms)
+const FEATURE_SET_TIME_MS: u32 = 10;
+/// Execution time of the initialization (ms)
+const INIT_TIME_MS: u32 = 10;
+/// Execution time of a measurement (ms)
+const MEASUREMENT_TIME_MS: u32 = 12;
+
+/// Measurement of the sensor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Measurement {
+    /// Equivalent CO2 concentration in ppm
+    pub co2_eq: u16,
+    /// Total VOC concentration in ppb
+    pub tvoc: u16,
+}
+
+/// SGP30 driver.
+pub struct Sgp30<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C: I2c> Sgp30<I2C> {
+    /// Check the product type and initialize the air quality measurement.
+    pub fn new<D: DelayNs>(
+        i2c: I2C,
+        address: u8,
+        delay: &mut D,
+    ) -> Result<Self, SensorError<I2C::Error>> {
+        let mut sgp30 = Self { i2c, address };
+
+        sgp30.command(COMMAND_GET_FEATURE_SET)?;
+        delay.delay_ms(FEATURE_SET_TIME_MS);
+        let [feature_set] = sensirion::read_words(&mut sgp30.i2c, sgp30.address)?;
+        // The product type is in the upper nibble
+        if feature_set >> 12 != PRODUCT_TYPE {
+            return Err(SensorError::InvalidChipId((feature_set >> 12) as u8));
+        }
+
+        sgp30.command(COMMAND_INIT_AIR_QUALITY)?;
+        delay.delay_ms(INIT_TIME_MS);
+
+        Ok(sgp30)
+    }
+
+    /// Measure the air quality.
+    pub fn measure<D: DelayNs>(
+        &mut self,
+        delay: &mut D,
+    ) -> Result<Measurement, SensorError<I2C::Error>> {
+        self.command(COMMAND_MEASURE_AIR_QUALITY)?;
+        delay.delay_ms(MEASUREMENT_TIME_MS);
+        let [co2_eq, tvoc] = sensirion::read_words(&mut self.i2c, self.address)?;
+
+        Ok(Measurement { co2_eq, tvoc })
+    }
+
+    /// Release the I2C bus.
+    pub fn release(self) -> I2C {
+        self.i2c
+    }
+
+    fn command(&mut self, command: u16) -> Result<(), SensorError<I2C::Error>> {
+        sensirion::command(&mut self.i2c, self.address, command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mock::{MockI2c, NoDelay};
+    use super::*;
+
+    /// Words each followed by their CRC.
+    fn words(values: &[u16]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| {
+                let [msb, lsb] = value.to_be_bytes();
+                [msb, lsb, sensirion::crc8(&[msb, lsb])]
+            })
+            .collect()
+    }
+
+    fn sensor() -> MockI2c {
+        let mut i2c = MockI2c::new(DEFAULT_ADDRESS);
+        // Feature set: SGP30, version 0x22
+        i2c.reads.push_back(words(&[0x0022]));
+        i2c
+    }
+
+    #[test]
+    fn initializes_the_air_quality_measurement() {
+        let mut i2c = sensor();
+        Sgp30::new(&mut i2c, DEFAULT_ADDRESS, &mut NoDelay).unwrap();
+        assert_eq!(i2c.writes, [vec![0x20, 0x2F], vec![0x20, 0x03]]);
+    }
+
+    #[test]
+    fn rejects_another_product() {
+        let mut i2c = MockI2c::new(DEFAULT_ADDRESS);
+        i2c.reads.push_back(words(&[0x1022]));
+        assert!(matches!(
+            Sgp30::new(&mut i2c, DEFAULT_ADDRESS, &mut NoDelay),
+            Err(SensorError::InvalidChipId(1))
+        ));
+        // The initialization is not sent
+        assert_eq!(i2c.writes, [vec![0x20, 0x2F]]);
+    }
+
+    #[test]
+    fn measures() {
+        let mut i2c = sensor();
+        let mut sgp30 = Sgp30::new(&mut i2c, DEFAULT_ADDRESS, &mut NoDelay).unwrap();
+        // 450 ppm CO2eq, 12 ppb TVOC
+        sgp30.i2c.reads.push_back(words(&[450, 12]));
+
+        let measurement = sgp30.measure(&mut NoDelay).unwrap();
+        assert_eq!(
+            measurement,
+            Measurement {
+                co2_eq: 450,
+                tvoc: 12
+            }
+        );
+        assert_eq!(i2c.writes[2..], [vec![0x20, 0x08]]);
+    }
+
+    #[test]
+    fn rejects_a_wrong_crc() {
+        let mut i2c = sensor();
+        let mut sgp30 = Sgp30::new(&mut i2c, DEFAULT_ADDRESS, &mut NoDelay).unwrap();
+        let mut data = words(&[450, 12]);
+        data[5] ^= 0x01;
+        sgp30.i2c.reads.push_back(data);
+        assert!(matches!(sgp30.measure(&mut NoDelay), Err(SensorError::Crc)));
+    }
+}
@@ -0,0 +1,142 @@
+//! Driver for the Sensirion SHT3x (SHT30, SHT31, SHT35) temperature and
+//! humidity sensor.
+//!
+//! Measurements are taken in single shot mode with high repeatability and
+//! clock stretching disabled.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+
+use super::sensirion;
+use super::SensorError;
+
+/// I2C address with ADDR connected to GND.
+pub const DEFAULT_ADDRESS: u8 = 0x44;
+/// I2C address with ADDR connected to VDD.
+pub const SECONDARY_ADDRESS: u8 = 0x45;
+
+const COMMAND_SOFT_RESET: u16 = 0x30A2;
+const COMMAND_READ_STATUS: u16 = 0xF32D;
+const COMMAND_SINGLE_SHOT_HIGH: u16 = 0x2400;
+
+/// Time needed after a soft reset (ms)
+const RESET_TIME_MS: u32 = 2;
+/// Max duration of a high repeatability measurement (ms)
+const MEASUREMENT_TIME_MS: u32 = 16;
+
+/// Measurement of the sensor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Measurement {
+    /// Temperature in °C
+    pub temperature: f32,
+    /// Relative humidity in %
+    pub humidity: f32,
+}
+
+/// SHT3x driver.
+pub struct Sht3x<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C: I2c> Sht3x<I2C> {
+    /// Reset the sensor and check that it answers with a valid status.
+    pub fn new<D: DelayNs>(
+        i2c: I2C,
+        address: u8,
+        delay: &mut D,
+    ) -> Result<Self, SensorError<I2C::Error>> {
+        let mut sht3x = Self { i2c, address };
+
+        sht3x.command(COMMAND_SOFT_RESET)?;
+        delay.delay_ms(RESET_TIME_MS);
+
+        // The status word is protected by a CRC, reading it checks the sensor
+        sht3x.command(COMMAND_READ_STATUS)?;
+        sensirion::read_words::<_, 1>(&mut sht3x.i2c, sht3x.address)?;
+
+        Ok(sht3x)
+    }
+
+    /// Trigger a measurement and wait for its result.
+    pub fn measure<D: DelayNs>(
+        &mut self,
+        delay: &mut D,
+    ) -> Result<Measurement, SensorError<I2C::Error>> {
+        self.command(COMMAND_SINGLE_SHOT_HIGH)?;
+        delay.delay_ms(MEASUREMENT_TIME_MS);
+
+        let [raw_temperature, raw_humidity] = sensirion::read_words(&mut self.i2c, self.address)?;
+
+        Ok(Measurement {
+            temperature: -45.0 + 175.0 * raw_temperature as f32 / 65535.0,
+            humidity: 100.0 * raw_humidity as f32 / 65535.0,
+        })
+    }
+
+    /// Release the I2C bus.
+    pub fn release(self) -> I2C {
+        self.i2c
+    }
+
+    fn command(&mut self, command: u16) -> Result<(), SensorError<I2C::Error>> {
+        sensirion::command(&mut self.i2c, self.address, command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mock::{MockI2c, NoDelay};
+    use super::*;
+
+    /// Word followed by its CRC.
+    fn word(value: u16) -> [u8; 3] {
+        let [msb, lsb] = value.to_be_bytes();
+        [msb, lsb, sensirion::crc8(&[msb, lsb])]
+    }
+
+    fn sensor() -> MockI2c {
+        let mut i2c = MockI2c::new(DEFAULT_ADDRESS);
+        // Status
+        i2c.reads.push_back(word(0x8010).to_vec());
+        i2c
+    }
+
+    #[test]
+    fn measures() {
+        let mut i2c = sensor();
+        let mut sht3x = Sht3x::new(&mut i2c, DEFAULT_ADDRESS, &mut NoDelay).unwrap();
+        let mut data = word(0x6666).to_vec();
+        data.extend(word(0x8000));
+        sht3x.i2c.reads.push_back(data);
+
+        let measurement = sht3x.measure(&mut NoDelay).unwrap();
+        assert!((measurement.temperature - 25.0).abs() < 0.01);
+        assert!((measurement.humidity - 50.0).abs() < 0.01);
+        assert_eq!(
+            i2c.writes,
+            [vec![0x30, 0xA2], vec![0xF3, 0x2D], vec![0x24, 0x00]]
+        );
+    }
+
+    #[test]
+    fn rejects_a_wrong_crc() {
+        let mut i2c = sensor();
+        let mut sht3x = Sht3x::new(&mut i2c, DEFAULT_ADDRESS, &mut NoDelay).unwrap();
+        let mut data = word(0x6666).to_vec();
+        data.extend(word(0x8000));
+        data[5] ^= 1;
+        sht3x.i2c.reads.push_back(data);
+
+        assert!(matches!(sht3x.measure(&mut NoDelay), Err(SensorError::Crc)));
+    }
+
+    #[test]
+    fn missing_sensor() {
+        let mut i2c = sensor();
+        assert!(matches!(
+            Sht3x::new(&mut i2c, SECONDARY_ADDRESS, &mut NoDelay),
+            Err(SensorError::Bus(_))
+        ));
+    }
+}
@@ -0,0 +1,113 @@
+//! Exponential backoff of the reconnections.
+//!
+//! The delay before the next attempt doubles at every failure, up to a max,
+//! and is reset by a success. The current time is passed by the caller, so
+//! the schedule can be run on the host.
+
+use core::time::Duration;
+use std::time::Instant;
+
+/// Schedule of the attempts to open a connection.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    failures: u32,
+    next_attempt: Option<Instant>,
+}
+
+impl Backoff {
+    /// Create a backoff waiting `initial` after the first failure, and at
+    /// most `max`.
+    pub const fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            failures: 0,
+            next_attempt: None,
+        }
+    }
+
+    /// Check if an attempt can be made.
+    pub fn is_ready(&self, now: Instant) -> bool {
+        self.next_attempt
+            .map_or(true, |next_attempt| now >= next_attempt)
+    }
+
+    /// Number of consecutive failures.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Record a failure, returning the delay before the next attempt.
+    pub fn on_failure(&mut self, now: Instant) -> Duration {
+        let exponent = self.failures.min(16);
+        let delay = self.initial.saturating_mul(1 << exponent).min(self.max);
+        self.failures = self.failures.saturating_add(1);
+        self.next_attempt = Some(now + delay);
+        delay
+    }
+
+    /// Record a success, the next attempt can be made right away.
+    pub fn reset(&mut self) {
+        self.failures = 0;
+        self.next_attempt = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INITIAL: Duration = Duration::from_secs(1);
+    const MAX: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn doubles_up_to_the_max() {
+        let mut backoff = Backoff::new(INITIAL, MAX);
+        let now = Instant::now();
+
+        let delays: Vec<_> = (0..8).map(|_| backoff.on_failure(now).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(backoff.failures(), 8);
+    }
+
+    #[test]
+    fn many_failures_do_not_overflow() {
+        let mut backoff = Backoff::new(INITIAL, Duration::MAX);
+        let now = Instant::now();
+
+        for _ in 0..100 {
+            backoff.on_failure(now);
+        }
+        assert_eq!(backoff.on_failure(now), INITIAL * (1 << 16));
+        assert_eq!(backoff.failures(), 101);
+    }
+
+    #[test]
+    fn ready_once_the_delay_elapsed() {
+        let mut backoff = Backoff::new(INITIAL, MAX);
+        let now = Instant::now();
+        assert!(backoff.is_ready(now));
+
+        backoff.on_failure(now);
+        backoff.on_failure(now);
+        assert!(!backoff.is_ready(now));
+        assert!(!backoff.is_ready(now + Duration::from_millis(1999)));
+        assert!(backoff.is_ready(now + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn reset_after_a_success() {
+        let mut backoff = Backoff::new(INITIAL, MAX);
+        let now = Instant::now();
+        for _ in 0..5 {
+            backoff.on_failure(now);
+        }
+
+        backoff.reset();
+        assert!(backoff.is_ready(now));
+        assert_eq!(backoff.failures(), 0);
+        assert_eq!(backoff.on_failure(now), INITIAL);
+    }
+}
@@ -0,0 +1,132 @@
+//! Bounded queue of the frames waiting for the uplink or the SD card.
+//!
+//! When the queue is full the oldest items are dropped first, except the
+//! critical ones (the alarms) that are never dropped: they can make the queue
+//! go over its capacity, they are sent only when a state changes so they stay
+//! few. The queue is generic over the items, so it can be run on the host.
+
+use std::collections::VecDeque;
+
+/// Queue keeping at most `capacity` items, plus the critical ones.
+#[derive(Debug)]
+pub struct BoundedQueue<T> {
+    items: VecDeque<T>,
+    capacity: usize,
+    is_critical: fn(&T) -> bool,
+    dropped: u64,
+}
+
+impl<T> BoundedQueue<T> {
+    /// Create a queue, the memory of all the items is allocated up front.
+    pub fn new(capacity: usize, is_critical: fn(&T) -> bool) -> Self {
+        Self {
+            items: VecDeque::with_capacity(capacity),
+            capacity,
+            is_critical,
+            dropped: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of items dropped since the creation of the queue.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Add an item at the end of the queue, dropping the oldest non critical
+    /// item if the queue is full.
+    pub fn push(&mut self, item: T) {
+        if self.items.len() >= self.capacity {
+            let is_critical = self.is_critical;
+            if let Some(index) = self.items.iter().position(|item| !is_critical(item)) {
+                self.items.remove(index);
+                self.dropped += 1;
+            } else if !is_critical(&item) {
+                // Only critical items are queued, drop the new one
+                self.dropped += 1;
+                return;
+            }
+        }
+        self.items.push_back(item);
+    }
+
+    /// Remove all the items, from the oldest.
+    pub fn drain(&mut self) -> Vec<T> {
+        self.items.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The negative items stand for the alarms.
+    fn is_critical(item: &i32) -> bool {
+        *item < 0
+    }
+
+    #[test]
+    fn keeps_the_items_below_capacity() {
+        let mut queue = BoundedQueue::new(3, is_critical);
+        queue.push(1);
+        queue.push(2);
+
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.dropped(), 0);
+        assert_eq!(queue.drain(), [1, 2]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn drops_the_oldest_items() {
+        let mut queue = BoundedQueue::new(3, is_critical);
+        for item in 1..=5 {
+            queue.push(item);
+        }
+
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(queue.drain(), [3, 4, 5]);
+    }
+
+    #[test]
+    fn never_drops_the_alarms() {
+        let mut queue = BoundedQueue::new(3, is_critical);
+        queue.push(-1);
+        queue.push(1);
+        queue.push(2);
+        // Drops 1 then 2, the oldest non critical items
+        queue.push(3);
+        queue.push(-2);
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(queue.drain(), [-1, 3, -2]);
+    }
+
+    #[test]
+    fn drops_the_new_item_when_full_of_alarms() {
+        let mut queue = BoundedQueue::new(2, is_critical);
+        queue.push(-1);
+        queue.push(-2);
+
+        queue.push(1);
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(queue.len(), 2);
+
+        // An alarm goes over the capacity
+        queue.push(-3);
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.capacity(), 2);
+        assert_eq!(queue.drain(), [-1, -2, -3]);
+    }
+}
@@ -0,0 +1,251 @@
+//! DNS server of the captive portal.
+//!
+//! While the hub is not configured, the devices connected to its access point
+//! have no Internet access. Answering every `A` query with the address of the
+//! access point makes the operating systems detect a captive portal and open
+//! the provisioning page. It only uses `std::net`, so it can be run on the host.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+
+/// Port of the DNS server.
+pub const DNS_PORT: u16 = 53;
+/// Max length of a DNS message over UDP.
+pub const MAX_MESSAGE_LEN: usize = 512;
+/// Time to live of the answers (s), short so that the clients do not keep
+/// the address once the hub is configured.
+pub const ANSWER_TTL: u32 = 60;
+
+const HEADER_LEN: usize = 12;
+/// Length of the answer: name pointer, type, class, TTL, data length, address
+const ANSWER_LEN: usize = 16;
+/// Pointer to the name of the question, right after the header
+const NAME_POINTER: u16 = 0xC000 | HEADER_LEN as u16;
+
+const TYPE_A: u16 = 1;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+/// Query/response flag
+const FLAG_QR: u16 = 1 << 15;
+/// Mask of the opcode field
+const FLAG_OPCODE: u16 = 0b1111 << 11;
+/// Authoritative answer flag
+const FLAG_AA: u16 = 1 << 10;
+/// Recursion desired flag, copied from the query
+const FLAG_RD: u16 = 1 << 8;
+/// Response code of unsupported queries
+const RCODE_NOT_IMPLEMENTED: u16 = 4;
+
+/// Build the response to `query`, answering with `address`.
+///
+/// Returns the length of the response written in `response`, or `None` if the
+/// query is malformed and must be ignored.
+pub fn build_response(query: &[u8], address: Ipv4Addr, response: &mut [u8]) -> Option<usize> {
+    if query.len() < HEADER_LEN {
+        return None;
+    }
+    let flags = u16::from_be_bytes([query[2], query[3]]);
+    let question_count = u16::from_be_bytes([query[4], query[5]]);
+    if flags & FLAG_QR != 0 {
+        // Not a query
+        return None;
+    }
+
+    // Only standard queries with one question are answered
+    let question_end = if flags & FLAG_OPCODE == 0 && question_count == 1 {
+        question_end(query)?
+    } else {
+        HEADER_LEN
+    };
+    let question = &query[HEADER_LEN..question_end];
+
+    let mut response_flags = FLAG_QR | FLAG_AA | (flags & (FLAG_OPCODE | FLAG_RD));
+    let mut answer_count = 0;
+    if question.is_empty() {
+        response_flags |= RCODE_NOT_IMPLEMENTED;
+    } else {
+        let record_type =
+            u16::from_be_bytes([question[question.len() - 4], question[question.len() - 3]]);
+        let class =
+            u16::from_be_bytes([question[question.len() - 2], question[question.len() - 1]]);
+        if (record_type == TYPE_A || record_type == TYPE_ANY) && class == CLASS_IN {
+            answer_count = 1;
+        }
+    }
+
+    let len = question_end + answer_count * ANSWER_LEN;
+    if response.len() < len {
+        return None;
+    }
+
+    // Header
+    response[0..2].copy_from_slice(&query[0..2]);
+    response[2..4].copy_from_slice(&response_flags.to_be_bytes());
+    response[4..6].copy_from_slice(&(!question.is_empty() as u16).to_be_bytes());
+    response[6..8].copy_from_slice(&(answer_count as u16).to_be_bytes());
+    response[8..12].fill(0);
+    // Question
+    response[HEADER_LEN..question_end].copy_from_slice(question);
+    // Answer
+    if answer_count == 1 {
+        let answer = &mut response[question_end..len];
+        answer[0..2].copy_from_slice(&NAME_POINTER.to_be_bytes());
+        answer[2..4].copy_from_slice(&TYPE_A.to_be_bytes());
+        answer[4..6].copy_from_slice(&CLASS_IN.to_be_bytes());
+        answer[6..10].copy_from_slice(&ANSWER_TTL.to_be_bytes());
+        answer[10..12].copy_from_slice(&4u16.to_be_bytes());
+        answer[12..16].copy_from_slice(&address.octets());
+    }
+
+    Some(len)
+}
+
+/// Find the end of the first question of the query.
+/// The name is made of labels, compression is not allowed in a question.
+fn question_end(query: &[u8]) -> Option<usize> {
+    let mut i = HEADER_LEN;
+    loop {
+        let label_len = *query.get(i)? as usize;
+        if label_len == 0 {
+            break;
+        }
+        if label_len > 63 {
+            return None;
+        }
+        i += 1 + label_len;
+    }
+    // Null label, type and class
+    let end = i + 1 + 4;
+    (end <= query.len()).then_some(end)
+}
+
+/// DNS server answering every query with the same address.
+pub struct CaptiveDns {
+    socket: UdpSocket,
+    address: Ipv4Addr,
+}
+
+impl CaptiveDns {
+    /// Bind the server to `local_addr`, answering with `address`.
+    pub fn bind<A: ToSocketAddrs>(local_addr: A, address: Ipv4Addr) -> io::Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind(local_addr)?,
+            address,
+        })
+    }
+
+    /// Address the server is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Wait for a query and answer it.
+    /// Malformed queries are ignored.
+    pub fn handle_query(&self) -> io::Result<()> {
+        let mut query = [0u8; MAX_MESSAGE_LEN];
+        let mut response = [0u8; MAX_MESSAGE_LEN];
+        let (len, source) = self.socket.recv_from(&mut query)?;
+        if let Some(response_len) = build_response(&query[..len], self.address, &mut response) {
+            self.socket.send_to(&response[..response_len], source)?;
+        }
+        Ok(())
+    }
+
+    /// Answer the queries forever, returning only if the socket fails.
+    pub fn run(&self) -> io::Error {
+        loop {
+            if let Err(e) = self.handle_query() {
+                // A client may have gone away, only the socket errors are fatal
+                if e.kind() != io::ErrorKind::ConnectionReset {
+                    return e;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    const PORTAL_ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 71, 1);
+
+    /// Query of `name` with the given record type.
+    fn query(id: u16, name: &str, record_type: u16) -> Vec<u8> {
+        let mut query = id.to_be_bytes().to_vec();
+        // Recursion desired, one question
+        query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            query.push(label.len() as u8);
+            query.extend_from_slice(label.as_bytes());
+        }
+        query.push(0);
+        query.extend_from_slice(&record_type.to_be_bytes());
+        query.extend_from_slice(&CLASS_IN.to_be_bytes());
+        query
+    }
+
+    #[test]
+    fn answers_over_udp() {
+        let server = CaptiveDns::bind("127.0.0.1:0", PORTAL_ADDRESS).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || server.handle_query());
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let query = query(0x1234, "connectivitycheck.gstatic.com", TYPE_A);
+        client.send_to(&query, server_addr).unwrap();
+
+        let mut response = [0u8; MAX_MESSAGE_LEN];
+        let (len, _) = client.recv_from(&mut response).unwrap();
+        handle.join().unwrap().unwrap();
+        let response = &response[..len];
+
+        assert_eq!(response[0..2], [0x12, 0x34]);
+        let flags = u16::from_be_bytes([response[2], response[3]]);
+        assert_eq!(flags, FLAG_QR | FLAG_AA | FLAG_RD);
+        // One question, one answer
+        assert_eq!(response[4..8], [0, 1, 0, 1]);
+        assert_eq!(response[HEADER_LEN..query.len()], query[HEADER_LEN..]);
+
+        let answer = &response[query.len()..];
+        assert_eq!(answer.len(), ANSWER_LEN);
+        assert_eq!(answer[0..2], NAME_POINTER.to_be_bytes());
+        assert_eq!(answer[2..4], TYPE_A.to_be_bytes());
+        assert_eq!(answer[4..6], CLASS_IN.to_be_bytes());
+        assert_eq!(answer[6..10], ANSWER_TTL.to_be_bytes());
+        assert_eq!(answer[10..12], [0, 4]);
+        assert_eq!(answer[12..16], PORTAL_ADDRESS.octets());
+    }
+
+    #[test]
+    fn no_answer_to_other_types() {
+        // AAAA
+        let query = query(1, "example.com", 28);
+        let mut response = [0u8; MAX_MESSAGE_LEN];
+        let len = build_response(&query, PORTAL_ADDRESS, &mut response).unwrap();
+
+        assert_eq!(len, query.len());
+        assert_eq!(response[6..8], [0, 0]);
+        assert_eq!(response[3] & 0x0F, 0);
+    }
+
+    #[test]
+    fn ignores_malformed_queries() {
+        let query = query(1, "example.com", TYPE_A);
+        let mut response = [0u8; MAX_MESSAGE_LEN];
+
+        assert!(build_response(&query[..5], PORTAL_ADDRESS, &mut response).is_none());
+        assert!(build_response(&query[..15], PORTAL_ADDRESS, &mut response).is_none());
+        // A response
+        let mut not_a_query = query.clone();
+        not_a_query[2] |= 0x80;
+        assert!(build_response(&not_a_query, PORTAL_ADDRESS, &mut response).is_none());
+    }
+}
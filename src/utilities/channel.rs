@@ -1,6 +1,30 @@
+use messages::Frame;
+
+use crate::definitions::{get_message_channel, Message};
+
 pub fn set_channel(channel: u8) {
     unsafe {
         let second = esp_idf_hal::sys::wifi_second_chan_t_WIFI_SECOND_CHAN_NONE;
         esp_idf_hal::sys::esp_wifi_set_channel(channel, second);
     }
 }
+
+/// Get the current primary channel of the radio.
+pub fn get_channel() -> u8 {
+    let mut primary = 0;
+    let mut second = esp_idf_hal::sys::wifi_second_chan_t_WIFI_SECOND_CHAN_NONE;
+    unsafe {
+        esp_idf_hal::sys::esp_wifi_get_channel(&mut primary, &mut second);
+    }
+    primary
+}
+
+/// Get the Wi-Fi channel announced by the master in the received data, if any.
+pub fn master_channel(data: &[u8]) -> Option<u8> {
+    let mut data = data.to_vec();
+    let frames = Frame::deserialize_many(&mut data).ok()?;
+    frames
+        .iter()
+        .filter_map(|frame| Message::try_from(frame).ok())
+        .find_map(|message| get_message_channel(&message).ok())
+}
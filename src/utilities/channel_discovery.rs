@@ -0,0 +1,196 @@
+//! Model of the master discovery performed by the slaves.
+//!
+//! The slaves do not know on which Wi-Fi channel the master is, because the
+//! master follows the channel of the access point it is connected to. This
+//! module contains the state machine deciding when a slave has to search for
+//! the master and on which channel it has to tune. It does not touch the radio,
+//! so it can be run on the host.
+
+/// Channels scanned while searching for the master, in order.
+/// Channels 1, 6 and 11 are the most common channels.
+pub const SCAN_CHANNELS: [u8; 3] = [6, 11, 1];
+/// Number of consecutive failed sends after which the master is considered lost.
+pub const MAX_SEND_FAILURES: usize = 10;
+
+/// State of the discovery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiscoveryState {
+    /// The master has not been heard, the slave is scanning the channels.
+    Searching,
+    /// The master has been heard on the current channel.
+    Locked,
+}
+
+/// State machine that tracks the channel of the master.
+#[derive(Debug, Clone)]
+pub struct ChannelDiscovery {
+    state: DiscoveryState,
+    channel: u8,
+    scan_index: usize,
+    num_fail: usize,
+}
+
+impl ChannelDiscovery {
+    /// Create a new discovery, searching from the first channel of [`SCAN_CHANNELS`].
+    pub const fn new() -> Self {
+        Self {
+            state: DiscoveryState::Searching,
+            channel: SCAN_CHANNELS[0],
+            scan_index: 0,
+            num_fail: 0,
+        }
+    }
+
+    pub fn state(&self) -> DiscoveryState {
+        self.state
+    }
+
+    pub fn is_searching(&self) -> bool {
+        self.state == DiscoveryState::Searching
+    }
+
+    /// Channel the slave should currently be tuned on.
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+
+    /// Advance the scan and return the channel to tune on.
+    /// Must be called only while searching.
+    pub fn next_scan_channel(&mut self) -> u8 {
+        self.channel = SCAN_CHANNELS[self.scan_index];
+        self.scan_index = (self.scan_index + 1) % SCAN_CHANNELS.len();
+        self.channel
+    }
+
+    /// Update the state with the result of a send to the master.
+    ///
+    /// Returns `true` if the master has just been lost, i.e. the search must start.
+    pub fn on_send_status(&mut self, success: bool) -> bool {
+        if success {
+            self.num_fail = 0;
+            self.state = DiscoveryState::Locked;
+            return false;
+        }
+
+        self.num_fail = self.num_fail.saturating_add(1);
+        if self.num_fail > MAX_SEND_FAILURES && self.state == DiscoveryState::Locked {
+            self.state = DiscoveryState::Searching;
+            return true;
+        }
+        false
+    }
+
+    /// Update the state after a message from the master, carrying the channel
+    /// the master is on (a ping or a channel change announcement).
+    ///
+    /// Returns the channel to tune on, if it differs from the current one.
+    pub fn on_master_channel(&mut self, channel: u8) -> Option<u8> {
+        self.state = DiscoveryState::Locked;
+        self.num_fail = 0;
+
+        // Channel 0 means that the master does not know its channel yet
+        if channel == 0 || channel == self.channel {
+            return None;
+        }
+
+        self.channel = channel;
+        Some(channel)
+    }
+}
+
+impl Default for ChannelDiscovery {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scans_the_channels_in_order() {
+        let mut discovery = ChannelDiscovery::new();
+        assert!(discovery.is_searching());
+        assert_eq!(discovery.channel(), SCAN_CHANNELS[0]);
+
+        let scanned: Vec<u8> = (0..SCAN_CHANNELS.len() * 2)
+            .map(|_| discovery.next_scan_channel())
+            .collect();
+        assert_eq!(scanned, [6, 11, 1, 6, 11, 1]);
+        assert_eq!(discovery.channel(), 1);
+    }
+
+    #[test]
+    fn master_lost_after_the_failure_threshold() {
+        let mut discovery = ChannelDiscovery::new();
+        assert!(!discovery.on_send_status(true));
+        assert_eq!(discovery.state(), DiscoveryState::Locked);
+
+        for _ in 0..MAX_SEND_FAILURES {
+            assert!(!discovery.on_send_status(false));
+        }
+        assert_eq!(discovery.state(), DiscoveryState::Locked);
+        assert!(discovery.on_send_status(false));
+        assert!(discovery.is_searching());
+        // Lost only once
+        assert!(!discovery.on_send_status(false));
+    }
+
+    #[test]
+    fn success_resets_the_failures() {
+        let mut discovery = ChannelDiscovery::new();
+        discovery.on_send_status(true);
+        for _ in 0..MAX_SEND_FAILURES {
+            discovery.on_send_status(false);
+        }
+        assert!(!discovery.on_send_status(true));
+        for _ in 0..MAX_SEND_FAILURES {
+            assert!(!discovery.on_send_status(false));
+        }
+        assert_eq!(discovery.state(), DiscoveryState::Locked);
+    }
+
+    #[test]
+    fn failures_while_searching_do_not_restart_the_search() {
+        let mut discovery = ChannelDiscovery::new();
+        for _ in 0..MAX_SEND_FAILURES * 2 {
+            assert!(!discovery.on_send_status(false));
+        }
+        assert!(discovery.is_searching());
+    }
+
+    #[test]
+    fn follows_the_channel_of_the_master() {
+        let mut discovery = ChannelDiscovery::new();
+        assert_eq!(discovery.on_master_channel(11), Some(11));
+        assert_eq!(discovery.state(), DiscoveryState::Locked);
+        assert_eq!(discovery.channel(), 11);
+
+        // Same channel, or unknown to the master
+        assert_eq!(discovery.on_master_channel(11), None);
+        assert_eq!(discovery.on_master_channel(0), None);
+        assert_eq!(discovery.channel(), 11);
+
+        // Channel change announced by the master
+        assert_eq!(discovery.on_master_channel(3), Some(3));
+        assert_eq!(discovery.channel(), 3);
+    }
+
+    #[test]
+    fn master_heard_while_searching() {
+        let mut discovery = ChannelDiscovery::new();
+        discovery.on_send_status(true);
+        for _ in 0..=MAX_SEND_FAILURES {
+            discovery.on_send_status(false);
+        }
+        assert!(discovery.is_searching());
+        assert_eq!(discovery.next_scan_channel(), 6);
+        assert_eq!(discovery.on_master_channel(6), None);
+        assert_eq!(discovery.state(), DiscoveryState::Locked);
+        // The failures were reset
+        for _ in 0..MAX_SEND_FAILURES {
+            assert!(!discovery.on_send_status(false));
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use core::ffi::{c_void, CStr};
+
+use esp_idf_hal::sys::{
+    esp, esp_partition_erase_range, esp_partition_find_first, esp_partition_read,
+    esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY, esp_partition_t,
+    esp_partition_type_t_ESP_PARTITION_TYPE_DATA, esp_partition_write, EspError,
+};
+use log::{info, warn};
+use messages::Frame;
+
+use super::frame_store::{FrameStore, StoreUsage};
+use super::ring_log::{Flash, RingLog, SECTOR_SIZE};
+use super::sd::SDError;
+
+/// Label of the data partition, in `partitions.csv`
+// Safety: NUL terminated, without interior NUL
+const PARTITION_LABEL: &CStr = unsafe { CStr::from_bytes_with_nul_unchecked(b"storage\0") };
+/// Max number of frames returned by a read
+const READ_BATCH_LEN: usize = 32;
+
+/// Data partition of the internal flash.
+pub struct PartitionFlash {
+    partition: *const esp_partition_t,
+}
+
+impl PartitionFlash {
+    /// Find the data partition, `None` if the partition table has none.
+    pub fn find() -> Option<Self> {
+        let partition = unsafe {
+            esp_partition_find_first(
+                esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+                esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
+                PARTITION_LABEL.as_ptr(),
+            )
+        };
+        (!partition.is_null()).then_some(Self { partition })
+    }
+}
+
+impl Flash for PartitionFlash {
+    type Error = EspError;
+
+    fn size(&self) -> u32 {
+        let size = unsafe { (*self.partition).size };
+        size - size % SECTOR_SIZE
+    }
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), EspError> {
+        esp!(unsafe {
+            esp_partition_read(
+                self.partition,
+                offset as usize,
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len(),
+            )
+        })
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), EspError> {
+        esp!(unsafe {
+            esp_partition_write(
+                self.partition,
+                offset as usize,
+                data.as_ptr() as *const c_void,
+                data.len(),
+            )
+        })
+    }
+
+    fn erase_sector(&mut self, offset: u32) -> Result<(), EspError> {
+        esp!(unsafe {
+            esp_partition_erase_range(self.partition, offset as usize, SECTOR_SIZE as usize)
+        })
+    }
+}
+
+/// Storage of the frames in the internal flash, for the boards without an SD
+/// card. When it is full the oldest frames are dropped.
+pub struct FlashStore {
+    log: RingLog<PartitionFlash>,
+}
+
+impl FlashStore {
+    pub fn new() -> Result<Self, SDError> {
+        let Some(flash) = PartitionFlash::find() else {
+            return Err(SDError::Other("No storage partition".to_string()));
+        };
+        info!("Storing the frames in the internal flash");
+
+        Ok(Self {
+            log: RingLog::new(flash)?,
+        })
+    }
+
+    /// Number of frames dropped because the storage was full.
+    pub fn dropped(&self) -> u64 {
+        self.log.dropped()
+    }
+}
+
+impl FrameStore for FlashStore {
+    fn write(&mut self, frame: &Frame) -> Result<(), SDError> {
+        self.log.append(&frame.serialize())?;
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<Vec<Frame>, SDError> {
+        let mut frames = Vec::new();
+        for mut record in self.log.read(READ_BATCH_LEN)? {
+            match Frame::deserialize_many(&mut record) {
+                Ok(record_frames) => frames.extend(record_frames),
+                // Dropped with the batch
+                Err(e) => warn!("Skipping corrupted frame in the flash: {:?}", e),
+            }
+        }
+        Ok(frames)
+    }
+
+    fn commit(&mut self) -> Result<(), SDError> {
+        self.log.commit()?;
+        Ok(())
+    }
+
+    fn rollback(&mut self) {
+        self.log.rollback();
+    }
+
+    fn usage(&mut self) -> Result<StoreUsage, SDError> {
+        Ok(StoreUsage {
+            capacity: self.log.capacity(),
+            used: self.log.backlog(),
+            backlog: self.log.backlog(),
+        })
+    }
+}
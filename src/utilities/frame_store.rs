@@ -0,0 +1,46 @@
+use messages::Frame;
+
+use super::sd::SDError;
+
+/// Space used by a storage, in bytes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StoreUsage {
+    pub capacity: u64,
+    pub used: u64,
+    /// Frames not yet delivered
+    pub backlog: u64,
+}
+
+/// Storage of the frames waiting to be sent to the server.
+///
+/// The frames are read in batches, a batch is removed from the storage only
+/// once it has been delivered.
+pub trait FrameStore {
+    /// Store a frame.
+    /// The batch returned by the last read is rolled back.
+    fn write(&mut self, frame: &Frame) -> Result<(), SDError>;
+
+    /// Read a batch of frames, from the oldest.
+    fn read(&mut self) -> Result<Vec<Frame>, SDError>;
+
+    /// Remove the batch returned by the last read, once it has been delivered.
+    fn commit(&mut self) -> Result<(), SDError>;
+
+    /// Keep the batch returned by the last read, it will be read again.
+    fn rollback(&mut self);
+
+    /// Space used by the storage.
+    fn usage(&mut self) -> Result<StoreUsage, SDError>;
+
+    /// Check if the storage is still there, to detect the removal of a card
+    /// without a card-detect switch.
+    fn is_present(&mut self) -> bool {
+        true
+    }
+
+    /// Write the frames buffered in memory before the storage is removed.
+    /// Returns the frames that could not be written.
+    fn unmount(self: Box<Self>) -> Vec<Frame> {
+        Vec::new()
+    }
+}
@@ -0,0 +1,476 @@
+//! Client of the InfluxDB v2 HTTP write API.
+//!
+//! The points are sent in line protocol with `POST /api/v2/write`, on a
+//! keep-alive HTTP/1.1 connection. The client is generic over the transport,
+//! so the same code runs over TLS on the board and over a plain TCP socket on
+//! the host.
+//!
+//! The `Date` header of the responses gives the time of the server, a
+//! fallback for the clock when NTP is blocked.
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+use super::timestamp::days_from_civil;
+
+/// Max length of the response headers.
+const MAX_HEADER_LEN: usize = 4096;
+/// Max length of the response body, the error messages are truncated.
+const MAX_BODY_LEN: usize = 1024;
+
+/// Error of a write.
+#[derive(Debug)]
+pub enum InfluxError {
+    /// The connection failed, it must be opened again
+    Io(io::Error),
+    /// The response is not valid HTTP, the connection must be opened again
+    InvalidResponse,
+    /// The server refused the points
+    Status { code: u16, message: String },
+}
+
+impl InfluxError {
+    /// Check if the connection can't be used anymore.
+    pub fn is_connection_error(&self) -> bool {
+        !matches!(self, InfluxError::Status { .. })
+    }
+}
+
+impl fmt::Display for InfluxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InfluxError::Io(e) => write!(f, "I/O error: {}", e),
+            InfluxError::InvalidResponse => write!(f, "invalid HTTP response"),
+            InfluxError::Status { code, message } => write!(f, "HTTP {}: {}", code, message),
+        }
+    }
+}
+
+impl std::error::Error for InfluxError {}
+
+impl From<io::Error> for InfluxError {
+    fn from(error: io::Error) -> Self {
+        InfluxError::Io(error)
+    }
+}
+
+/// Destination of the points.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InfluxConfig {
+    /// Host name, sent in the `Host` header
+    pub host: String,
+    pub org: String,
+    pub bucket: String,
+    /// API token, with write permission on the bucket
+    pub token: String,
+}
+
+/// InfluxDB v2 client on an open connection.
+pub struct InfluxClient<T: Read + Write> {
+    stream: BufReader<T>,
+    config: InfluxConfig,
+    /// Time of the server in ms since the UNIX epoch, from the last response
+    server_date: Option<u64>,
+}
+
+impl<T: Read + Write> InfluxClient<T> {
+    pub fn new(stream: T, config: InfluxConfig) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+            config,
+            server_date: None,
+        }
+    }
+
+    /// Time of the server in ms since the UNIX epoch, from the `Date` header
+    /// of the last response.
+    pub fn server_date(&self) -> Option<u64> {
+        self.server_date
+    }
+
+    /// Check that the server is up, with `GET /ping`.
+    pub fn ping(&mut self) -> Result<(), InfluxError> {
+        let request = format!(
+            "GET /ping HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Connection: keep-alive\r\n\r\n",
+            self.config.host,
+        );
+        let stream = self.stream.get_mut();
+        stream.write_all(request.as_bytes())?;
+        stream.flush()?;
+
+        self.read_response()
+    }
+
+    /// Write the points, one per line in line protocol, with timestamps in ms.
+    pub fn write(&mut self, lines: &str) -> Result<(), InfluxError> {
+        let request = format!(
+            "POST /api/v2/write?org={}&bucket={}&precision=ms HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Authorization: Token {}\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             Content-Length: {}\r\n\
+             Connection: keep-alive\r\n\r\n",
+            encode_query(&self.config.org),
+            encode_query(&self.config.bucket),
+            self.config.host,
+            self.config.token,
+            lines.len(),
+        );
+        let stream = self.stream.get_mut();
+        stream.write_all(request.as_bytes())?;
+        stream.write_all(lines.as_bytes())?;
+        stream.flush()?;
+
+        self.read_response()
+    }
+
+    /// Release the connection.
+    pub fn release(self) -> T {
+        self.stream.into_inner()
+    }
+
+    /// Read the response, the body is read entirely so that the connection
+    /// can be used for the next write.
+    fn read_response(&mut self) -> Result<(), InfluxError> {
+        let status_line = self.read_line()?;
+        let mut parts = status_line.split_whitespace();
+        let code = match (parts.next(), parts.next()) {
+            (Some(version), Some(code)) if version.starts_with("HTTP/1.") => code
+                .parse::<u16>()
+                .map_err(|_| InfluxError::InvalidResponse)?,
+            _ => return Err(InfluxError::InvalidResponse),
+        };
+
+        let mut content_length = 0;
+        let mut header_len = status_line.len();
+        loop {
+            let line = self.read_line()?;
+            header_len += line.len();
+            if header_len > MAX_HEADER_LEN {
+                return Err(InfluxError::InvalidResponse);
+            }
+            if line.is_empty() {
+                break;
+            }
+            let Some((name, value)) = line.split_once(':') else {
+                return Err(InfluxError::InvalidResponse);
+            };
+            if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| InfluxError::InvalidResponse)?;
+            } else if name.eq_ignore_ascii_case("Date") {
+                self.server_date = parse_http_date(value.trim());
+            } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+                // Not used by InfluxDB for the write API
+                return Err(InfluxError::InvalidResponse);
+            }
+        }
+
+        // Read the whole body, keeping only its beginning
+        let mut body = Vec::new();
+        (&mut self.stream)
+            .take(content_length as u64)
+            .read_to_end(&mut body)?;
+        if body.len() < content_length {
+            return Err(InfluxError::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+        body.truncate(MAX_BODY_LEN);
+
+        if (200..300).contains(&code) {
+            Ok(())
+        } else {
+            Err(InfluxError::Status {
+                code,
+                message: String::from_utf8_lossy(&body).trim().to_string(),
+            })
+        }
+    }
+
+    /// Read a line of the response headers, without the line ending.
+    fn read_line(&mut self) -> Result<String, InfluxError> {
+        let mut line = String::new();
+        let len = (&mut self.stream)
+            .take(MAX_HEADER_LEN as u64)
+            .read_line(&mut line)?;
+        if len == 0 {
+            return Err(InfluxError::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+        if !line.ends_with('\n') {
+            return Err(InfluxError::InvalidResponse);
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+/// Parse an HTTP date (`Sun, 06 Nov 1994 08:49:37 GMT`) to ms since the
+/// UNIX epoch.
+pub fn parse_http_date(value: &str) -> Option<u64> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let mut parts = value.split_whitespace();
+    let (_, day, month, year, time, zone) = (
+        parts.next()?,
+        parts.next()?,
+        parts.next()?,
+        parts.next()?,
+        parts.next()?,
+        parts.next()?,
+    );
+    if zone != "GMT" || parts.next().is_some() {
+        return None;
+    }
+    let day: u64 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|name| *name == month)? as u64 + 1;
+    let year: u64 = year.parse().ok()?;
+    let mut time = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next()??);
+    if year < 1970 || !(1..=31).contains(&day) || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    Some(((days * 24 + hours) * 60 + minutes) * 60 * 1000 + seconds * 1000)
+}
+
+/// Percent-encode a query parameter.
+fn encode_query(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread::{self, JoinHandle};
+
+    use super::*;
+
+    /// Request received by the stand-in server.
+    struct Request {
+        head: String,
+        body: String,
+    }
+
+    /// Start a server that accepts a single connection and answers its
+    /// requests with `responses`, in order. Returns the requests once the
+    /// responses have been sent.
+    fn stand_in(responses: &[&'static str]) -> (TcpStream, JoinHandle<Vec<Request>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let responses = responses.to_vec();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut requests = Vec::new();
+            for response in responses {
+                let mut head = String::new();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("Content-Length: ") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                    head.push_str(&line);
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                requests.push(Request {
+                    head,
+                    body: String::from_utf8(body).unwrap(),
+                });
+                writer.write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        });
+        (TcpStream::connect(addr).unwrap(), handle)
+    }
+
+    fn config() -> InfluxConfig {
+        InfluxConfig {
+            host: "influx.local".to_string(),
+            org: "home org".to_string(),
+            bucket: "sensors/raw&1".to_string(),
+            token: "secret-token".to_string(),
+        }
+    }
+
+    #[test]
+    fn writes_points() {
+        let (stream, server) = stand_in(&["HTTP/1.1 204 No Content\r\n\r\n"]);
+        let mut client = InfluxClient::new(stream, config());
+
+        client
+            .write("temperature value=21.5 1700000000000\n")
+            .unwrap();
+        drop(client);
+
+        let requests = server.join().unwrap();
+        let head = &requests[0].head;
+        assert!(head.starts_with(
+            "POST /api/v2/write?org=home%20org&bucket=sensors%2Fraw%261&precision=ms HTTP/1.1\r\n"
+        ));
+        assert!(head.contains("Host: influx.local\r\n"));
+        assert!(head.contains("Authorization: Token secret-token\r\n"));
+        assert!(head.contains("Connection: keep-alive\r\n"));
+        assert_eq!(requests[0].body, "temperature value=21.5 1700000000000\n");
+    }
+
+    #[test]
+    fn classifies_the_errors() {
+        let (stream, server) = stand_in(&[
+            "HTTP/1.1 400 Bad Request\r\nContent-Length: 29\r\n\r\n{\"message\":\"invalid point\"}\r\n",
+            "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 30\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n",
+            "garbage\r\n",
+        ]);
+        let mut client = InfluxClient::new(stream, config());
+
+        for expected in [400, 401, 429, 503] {
+            let error = client.write("x value=1\n").unwrap_err();
+            assert!(
+                matches!(error, InfluxError::Status { code, .. } if code == expected),
+                "{}",
+                error
+            );
+            assert!(!error.is_connection_error());
+            if expected == 400 {
+                assert_eq!(
+                    error.to_string(),
+                    "HTTP 400: {\"message\":\"invalid point\"}"
+                );
+            }
+        }
+        let error = client.write("x value=1\n").unwrap_err();
+        assert!(matches!(error, InfluxError::InvalidResponse));
+        assert!(error.is_connection_error());
+
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn reuses_the_connection() {
+        // The body of the error must be read for the next response to be
+        // parsed from its start
+        let (stream, server) = stand_in(&[
+            "HTTP/1.1 204 No Content\r\n\r\n",
+            "HTTP/1.1 400 Bad Request\r\nContent-Length: 5\r\n\r\nerror",
+            "HTTP/1.1 204 No Content\r\n\r\n",
+        ]);
+        let mut client = InfluxClient::new(stream, config());
+
+        client.write("a value=1\n").unwrap();
+        assert!(client.write("b value=2\n").is_err());
+        client.write("c value=3\n").unwrap();
+        drop(client);
+
+        let bodies: Vec<_> = server
+            .join()
+            .unwrap()
+            .into_iter()
+            .map(|request| request.body)
+            .collect();
+        assert_eq!(bodies, ["a value=1\n", "b value=2\n", "c value=3\n"]);
+    }
+
+    #[test]
+    fn closed_connection() {
+        let (stream, server) = stand_in(&[]);
+        server.join().unwrap();
+        let mut client = InfluxClient::new(stream, config());
+
+        let error = client.write("x value=1\n").unwrap_err();
+        assert!(error.is_connection_error());
+    }
+
+    #[test]
+    fn ping_records_the_server_date() {
+        let (stream, server) =
+            stand_in(&["HTTP/1.1 204 No Content\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\r\n"]);
+        let mut client = InfluxClient::new(stream, config());
+
+        assert_eq!(client.server_date(), None);
+        client.ping().unwrap();
+        assert_eq!(client.server_date(), Some(784_111_777_000));
+        drop(client);
+
+        let requests = server.join().unwrap();
+        assert!(requests[0]
+            .head
+            .starts_with("GET /ping HTTP/1.1\r\nHost: influx.local\r\n"));
+    }
+
+    #[test]
+    fn parses_http_dates() {
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(784_111_777_000)
+        );
+        assert_eq!(
+            parse_http_date("Mon, 19 Oct 2026 12:00:00 GMT"),
+            Some(1_792_411_200_000)
+        );
+        // Leap days, and the day after
+        assert_eq!(
+            parse_http_date("Tue, 29 Feb 2000 00:00:00 GMT"),
+            Some(951_782_400_000)
+        );
+        assert_eq!(
+            parse_http_date("Tue, 29 Feb 2028 23:59:59 GMT"),
+            Some(1_835_481_599_000)
+        );
+        assert_eq!(
+            parse_http_date("Wed, 01 Mar 2028 00:00:00 GMT"),
+            Some(1_835_481_600_000)
+        );
+    }
+
+    #[test]
+    fn rejects_other_zones() {
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 UTC"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 +0100"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37"), None);
+    }
+
+    #[test]
+    fn rejects_malformed_dates() {
+        for date in [
+            "",
+            "garbage",
+            // RFC 850 and asctime formats, obsolete
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+            "Sun, 06 Foo 1994 08:49:37 GMT",
+            "Sun, 32 Nov 1994 08:49:37 GMT",
+            "Sun, 00 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1969 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 06 Nov 1994 08:60:00 GMT",
+            "Sun, 06 Nov 1994 08:49 GMT",
+            "Sun, 06 Nov 1994 08:49:xx GMT",
+            "Sun, 06 Nov 1994 08:49:37 GMT extra",
+        ] {
+            assert_eq!(parse_http_date(date), None, "{}", date);
+        }
+    }
+}
@@ -0,0 +1,229 @@
+//! Latest values of the fields decoded from the slaves, exposed in the
+//! Prometheus text exposition format.
+//!
+//! A single gauge carries all the fields, labelled by the device, the message,
+//! the field and its unit, as the names of the fields are not valid metric
+//! names:
+//!
+//! ```text
+//! smarthome_field{device="0",message="Temperature",field="Temperature",unit="°C"} 21.5
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Name of the gauge of the fields decoded from the slaves.
+pub const FIELD_METRIC: &str = "smarthome_field";
+
+/// Series of a field, identified by the device, the message and the field.
+type SeriesKey = (u8, String, String);
+
+/// Latest value and unit of each field.
+#[derive(Debug)]
+pub struct LatestValues {
+    series: BTreeMap<SeriesKey, (Option<String>, f64)>,
+    max_series: usize,
+}
+
+impl LatestValues {
+    /// Keep at most `max_series` fields, the new fields are ignored once
+    /// full, a misbehaving slave must not exhaust the memory.
+    pub fn new(max_series: usize) -> Self {
+        Self {
+            series: BTreeMap::new(),
+            max_series,
+        }
+    }
+
+    /// Update the value of a field. Returns `false` if the field is new and
+    /// there is no room for it.
+    pub fn update(
+        &mut self,
+        device_id: u8,
+        message: &str,
+        field: &str,
+        unit: Option<&str>,
+        value: f64,
+    ) -> bool {
+        let key = (device_id, message.to_string(), field.to_string());
+        if let Some(entry) = self.series.get_mut(&key) {
+            *entry = (unit.map(str::to_string), value);
+            return true;
+        }
+        if self.series.len() >= self.max_series {
+            return false;
+        }
+        self.series.insert(key, (unit.map(str::to_string), value));
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.series.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.series.is_empty()
+    }
+
+    /// Write the gauge of the fields, nothing if no field has been received.
+    pub fn write(&self, out: &mut String) {
+        if self.series.is_empty() {
+            return;
+        }
+        write_header(
+            out,
+            FIELD_METRIC,
+            "gauge",
+            "Latest value of the fields received from the slaves",
+        );
+        for ((device_id, message, field), (unit, value)) in &self.series {
+            let _ = writeln!(
+                out,
+                "{}{{device=\"{}\",message=\"{}\",field=\"{}\",unit=\"{}\"}} {}",
+                FIELD_METRIC,
+                device_id,
+                escape_label(message),
+                escape_label(field),
+                escape_label(unit.as_deref().unwrap_or_default()),
+                format_value(*value),
+            );
+        }
+    }
+}
+
+/// Write the `HELP` and `TYPE` lines of a metric.
+pub fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, escape_help(help));
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Write a metric without labels, with its header.
+pub fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: f64) {
+    write_header(out, name, kind, help);
+    let _ = writeln!(out, "{} {}", name, format_value(value));
+}
+
+/// Value in the exposition format, which spells the infinities and NaN.
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Escape the backslashes, the double quotes and the new lines of a label
+/// value.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Escape the backslashes and the new lines of a help text.
+fn escape_help(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str =
+        "# HELP smarthome_field Latest value of the fields received from the slaves\n\
+                          # TYPE smarthome_field gauge\n";
+
+    #[test]
+    fn writes_the_latest_values() {
+        let mut values = LatestValues::new(8);
+        assert!(values.update(1, "Temperature", "Temperature", Some("°C"), 21.5));
+        assert!(values.update(0, "Fire Alarm", "Fire Alarm", None, 1.0));
+        // Replaces the value, and the unit
+        assert!(values.update(1, "Temperature", "Temperature", Some("K"), 295.15));
+        assert_eq!(values.len(), 2);
+
+        let mut out = String::new();
+        values.write(&mut out);
+        assert_eq!(
+            out,
+            format!(
+                "{}{}{}",
+                HEADER,
+                "smarthome_field{device=\"0\",message=\"Fire Alarm\",field=\"Fire Alarm\",unit=\"\"} 1\n",
+                "smarthome_field{device=\"1\",message=\"Temperature\",field=\"Temperature\",unit=\"K\"} 295.15\n",
+            )
+        );
+    }
+
+    #[test]
+    fn nothing_before_the_first_value() {
+        let values = LatestValues::new(8);
+        assert!(values.is_empty());
+
+        let mut out = String::new();
+        values.write(&mut out);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn escapes_the_labels() {
+        let mut values = LatestValues::new(8);
+        values.update(2, "Back\\slash", "\"Quoted\"", Some("new\nline"), 0.5);
+
+        let mut out = String::new();
+        values.write(&mut out);
+        assert_eq!(
+            out,
+            format!(
+                "{}{}",
+                HEADER,
+                "smarthome_field{device=\"2\",message=\"Back\\\\slash\",field=\"\\\"Quoted\\\"\",unit=\"new\\nline\"} 0.5\n",
+            )
+        );
+    }
+
+    #[test]
+    fn caps_the_series() {
+        let mut values = LatestValues::new(2);
+        assert!(values.update(0, "A", "x", None, 1.0));
+        assert!(values.update(0, "A", "y", None, 2.0));
+        assert!(!values.update(0, "A", "z", None, 3.0));
+        assert_eq!(values.len(), 2);
+        // The known series are still updated
+        assert!(values.update(0, "A", "x", None, 4.0));
+
+        let mut out = String::new();
+        values.write(&mut out);
+        assert!(out.contains("field=\"x\",unit=\"\"} 4\n"));
+        assert!(!out.contains("field=\"z\""));
+    }
+
+    #[test]
+    fn writes_the_metrics() {
+        let mut out = String::new();
+        write_metric(
+            &mut out,
+            "smarthome_hub_uptime_seconds",
+            "gauge",
+            "Seconds since the\nboot \\",
+            12.0,
+        );
+        assert_eq!(
+            out,
+            "# HELP smarthome_hub_uptime_seconds Seconds since the\\nboot \\\\\n\
+             # TYPE smarthome_hub_uptime_seconds gauge\n\
+             smarthome_hub_uptime_seconds 12\n"
+        );
+    }
+
+    #[test]
+    fn formats_the_special_values() {
+        assert_eq!(format_value(f64::NAN), "NaN");
+        assert_eq!(format_value(f64::INFINITY), "+Inf");
+        assert_eq!(format_value(f64::NEG_INFINITY), "-Inf");
+        assert_eq!(format_value(-0.25), "-0.25");
+    }
+}
@@ -1,3 +1,13 @@
+pub mod backoff;
+pub mod bounded_queue;
+pub mod captive_dns;
 pub mod channel;
+pub mod channel_discovery;
+pub mod flash_store;
+pub mod frame_store;
+pub mod influx;
 pub mod init;
+pub mod metrics;
+pub mod ring_log;
 pub mod sd;
+pub mod timestamp;
@@ -0,0 +1,669 @@
+//! Ring log of records on a NOR flash partition.
+//!
+//! The partition is split in sectors written in order, wrapping around at the
+//! end, so all the sectors are erased the same number of times. Each sector
+//! starts with a header holding a sequence number, followed by the records:
+//!
+//! ```text
+//! | length: u16 | state: u8 | checksum: u8 | data | padding to 4 bytes |
+//! ```
+//!
+//! Erased flash reads as `0xFF`, so a length of `0xFFFF` marks the free space
+//! of a sector. The records are consumed by clearing their state byte, which
+//! NOR flash allows without erasing, so the read position survives a restart.
+//! When the log is full the oldest sector is erased, dropping its records.
+//!
+//! The log is generic over the flash, so it can be run on the host.
+
+use std::fmt::Debug;
+
+/// Size of the erasable sectors.
+pub const SECTOR_SIZE: u32 = 4096;
+/// Magic number of the sector header ("RLOG").
+const SECTOR_MAGIC: u32 = 0x474F_4C52;
+const SECTOR_HEADER_LEN: u32 = 8;
+const RECORD_HEADER_LEN: u32 = 4;
+/// Length of the free space.
+const FREE: u16 = 0xFFFF;
+/// State of a record not yet consumed.
+const LIVE: u8 = 0xFF;
+const CONSUMED: u8 = 0x00;
+/// Max length of the data of a record.
+pub const MAX_RECORD_LEN: usize = (SECTOR_SIZE - SECTOR_HEADER_LEN - RECORD_HEADER_LEN) as usize;
+
+/// NOR flash: the bits can be cleared by a write, set only by an erase.
+pub trait Flash {
+    type Error: Debug;
+
+    /// Size of the flash, a multiple of [`SECTOR_SIZE`].
+    fn size(&self) -> u32;
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error>;
+    /// Erase the sector starting at the offset.
+    fn erase_sector(&mut self, offset: u32) -> Result<(), Self::Error>;
+}
+
+/// Error of the ring log.
+#[derive(Debug)]
+pub enum RingLogError<E> {
+    Flash(E),
+    /// The flash has less than two sectors
+    TooSmall,
+    /// The record does not fit in a sector
+    TooLarge,
+}
+
+impl<E> From<E> for RingLogError<E> {
+    fn from(error: E) -> Self {
+        RingLogError::Flash(error)
+    }
+}
+
+/// Position in the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Position {
+    sector: u32,
+    offset: u32,
+}
+
+/// Record not yet consumed.
+struct LiveRecord {
+    position: Position,
+    data: Vec<u8>,
+}
+
+/// Records returned by the last read, waiting to be consumed.
+#[derive(Debug)]
+struct InFlight {
+    records: Vec<Position>,
+    /// Position after the last record
+    end: Position,
+}
+
+/// Ring log of records on a flash.
+#[derive(Debug)]
+pub struct RingLog<F: Flash> {
+    flash: F,
+    num_sectors: u32,
+    /// Position of the next write
+    head: Position,
+    /// Sequence number of the sector of the head
+    head_seq: u32,
+    /// Position of the oldest record not consumed
+    tail: Position,
+    in_flight: Option<InFlight>,
+    dropped: u64,
+}
+
+impl<F: Flash> RingLog<F> {
+    /// Open the log, finding the write and read positions left by the
+    /// previous run.
+    pub fn new(mut flash: F) -> Result<Self, RingLogError<F::Error>> {
+        let num_sectors = flash.size() / SECTOR_SIZE;
+        if num_sectors < 2 {
+            return Err(RingLogError::TooSmall);
+        }
+
+        // The sector written last has the highest sequence number
+        let mut newest = None;
+        for sector in 0..num_sectors {
+            if let Some(seq) = read_sector_seq(&mut flash, sector)? {
+                if newest.map_or(true, |(_, newest_seq)| seq > newest_seq) {
+                    newest = Some((sector, seq));
+                }
+            }
+        }
+
+        let mut log = Self {
+            flash,
+            num_sectors,
+            head: Position {
+                sector: 0,
+                offset: SECTOR_SIZE,
+            },
+            head_seq: 0,
+            tail: Position {
+                sector: 0,
+                offset: SECTOR_SIZE,
+            },
+            in_flight: None,
+            dropped: 0,
+        };
+        let Some((sector, seq)) = newest else {
+            // Empty log, the first write formats the first sector
+            log.head.sector = num_sectors - 1;
+            log.tail = log.head;
+            return Ok(log);
+        };
+        log.head_seq = seq;
+
+        // Find the free space of the newest sector, a corrupted record (a
+        // write interrupted by a reset) closes the sector
+        log.head = Position {
+            sector,
+            offset: SECTOR_HEADER_LEN,
+        };
+        while log.head.offset + RECORD_HEADER_LEN <= SECTOR_SIZE {
+            match log.read_record(log.head)? {
+                Record::Valid { len, .. } => log.head.offset += record_len(len),
+                Record::Free => break,
+                Record::Corrupted => {
+                    log.head.offset = SECTOR_SIZE;
+                    break;
+                }
+            }
+        }
+
+        // The oldest sector follows the newest one
+        log.tail = log.head;
+        for i in 1..=num_sectors {
+            let sector = (sector + i) % num_sectors;
+            if read_sector_seq(&mut log.flash, sector)?.is_some() {
+                log.tail = Position {
+                    sector,
+                    offset: SECTOR_HEADER_LEN,
+                };
+                break;
+            }
+        }
+        // Skip the consumed records
+        log.tail = match log.next_live(log.tail)? {
+            Some(record) => record.position,
+            None => log.head,
+        };
+
+        Ok(log)
+    }
+
+    /// Check if there are records not yet consumed.
+    pub fn is_empty(&self) -> bool {
+        self.tail == self.head
+    }
+
+    /// Number of records dropped because the log was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Size of the flash used by the log.
+    pub fn capacity(&self) -> u64 {
+        (self.num_sectors * SECTOR_SIZE) as u64
+    }
+
+    /// Space taken by the records not yet consumed, with the headers and
+    /// the consumed records between them.
+    pub fn backlog(&self) -> u64 {
+        if self.is_empty() {
+            return 0;
+        }
+        let sectors = (self.head.sector + self.num_sectors - self.tail.sector) % self.num_sectors;
+        (sectors * SECTOR_SIZE + self.head.offset - self.tail.offset) as u64
+    }
+
+    /// Add a record at the end of the log, dropping the oldest sector if the
+    /// log is full.
+    pub fn append(&mut self, data: &[u8]) -> Result<(), RingLogError<F::Error>> {
+        if data.len() > MAX_RECORD_LEN {
+            return Err(RingLogError::TooLarge);
+        }
+        let len = data.len() as u16;
+        if self.head.offset + record_len(len) > SECTOR_SIZE {
+            self.next_sector()?;
+        }
+
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + data.len());
+        record.extend_from_slice(&len.to_le_bytes());
+        record.push(LIVE);
+        record.push(checksum(data));
+        record.extend_from_slice(data);
+        self.flash.write(self.offset(self.head), &record)?;
+        self.head.offset += record_len(len);
+
+        Ok(())
+    }
+
+    /// Read at most `max_records` records from the oldest one.
+    /// The records are removed from the log only by [`RingLog::commit`],
+    /// otherwise the next read returns them again.
+    pub fn read(&mut self, max_records: usize) -> Result<Vec<Vec<u8>>, RingLogError<F::Error>> {
+        self.in_flight = None;
+
+        let mut records = Vec::new();
+        let mut positions = Vec::new();
+        let mut position = self.tail;
+        while records.len() < max_records {
+            let Some(record) = self.next_live(position)? else {
+                position = self.head;
+                break;
+            };
+            position = Position {
+                sector: record.position.sector,
+                offset: record.position.offset + record_len(record.data.len() as u16),
+            };
+            positions.push(record.position);
+            records.push(record.data);
+        }
+
+        self.in_flight = Some(InFlight {
+            records: positions,
+            end: position,
+        });
+        Ok(records)
+    }
+
+    /// Consume the records returned by the last read.
+    pub fn commit(&mut self) -> Result<(), RingLogError<F::Error>> {
+        let Some(in_flight) = self.in_flight.take() else {
+            return Ok(());
+        };
+        for position in in_flight.records {
+            let offset = self.offset(position) + 2;
+            self.flash.write(offset, &[CONSUMED])?;
+        }
+        self.tail = in_flight.end;
+        Ok(())
+    }
+
+    /// Keep the records returned by the last read, they will be read again.
+    pub fn rollback(&mut self) {
+        self.in_flight = None;
+    }
+
+    /// Release the flash.
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    /// Move the head to the start of the next sector, erasing it.
+    fn next_sector(&mut self) -> Result<(), RingLogError<F::Error>> {
+        let sector = (self.head.sector + 1) % self.num_sectors;
+
+        // The log is full, drop the records of the oldest sector
+        if !self.is_empty() && self.tail.sector == sector {
+            let mut position = self.tail;
+            while let Some(record) = self.next_live(position)? {
+                if record.position.sector != sector {
+                    break;
+                }
+                self.dropped += 1;
+                position = Position {
+                    sector,
+                    offset: record.position.offset + record_len(record.data.len() as u16),
+                };
+            }
+            self.tail = Position {
+                sector: (sector + 1) % self.num_sectors,
+                offset: SECTOR_HEADER_LEN,
+            };
+        }
+        // The records of the last read could be in the erased sector
+        self.in_flight = None;
+
+        let was_empty = self.is_empty();
+        self.flash.erase_sector(sector * SECTOR_SIZE)?;
+        self.head_seq = self.head_seq.wrapping_add(1);
+        let mut header = [0u8; SECTOR_HEADER_LEN as usize];
+        header[..4].copy_from_slice(&SECTOR_MAGIC.to_le_bytes());
+        header[4..].copy_from_slice(&self.head_seq.to_le_bytes());
+        self.flash.write(sector * SECTOR_SIZE, &header)?;
+
+        self.head = Position {
+            sector,
+            offset: SECTOR_HEADER_LEN,
+        };
+        if was_empty {
+            self.tail = self.head;
+        }
+        Ok(())
+    }
+
+    /// Find the first record not consumed, from the position.
+    fn next_live(
+        &mut self,
+        mut position: Position,
+    ) -> Result<Option<LiveRecord>, RingLogError<F::Error>> {
+        loop {
+            if position == self.head {
+                return Ok(None);
+            }
+            if position.offset + RECORD_HEADER_LEN > SECTOR_SIZE {
+                if position.sector == self.head.sector {
+                    return Ok(None);
+                }
+                position = self.next_sector_start(position.sector)?;
+                continue;
+            }
+
+            match self.read_record(position)? {
+                Record::Valid { len, state, data } => {
+                    if state == LIVE {
+                        return Ok(Some(LiveRecord { position, data }));
+                    }
+                    position.offset += record_len(len);
+                }
+                // End of the sector
+                Record::Free | Record::Corrupted => {
+                    if position.sector == self.head.sector {
+                        return Ok(None);
+                    }
+                    position = self.next_sector_start(position.sector)?;
+                }
+            }
+        }
+    }
+
+    /// First record of the sector after the given one, skipping the sectors
+    /// that are not formatted.
+    fn next_sector_start(&mut self, mut sector: u32) -> Result<Position, RingLogError<F::Error>> {
+        loop {
+            sector = (sector + 1) % self.num_sectors;
+            if sector == self.head.sector || read_sector_seq(&mut self.flash, sector)?.is_some() {
+                return Ok(Position {
+                    sector,
+                    offset: SECTOR_HEADER_LEN,
+                });
+            }
+        }
+    }
+
+    fn read_record(&mut self, position: Position) -> Result<Record, RingLogError<F::Error>> {
+        let mut header = [0u8; RECORD_HEADER_LEN as usize];
+        self.flash.read(self.offset(position), &mut header)?;
+        let len = u16::from_le_bytes([header[0], header[1]]);
+        if len == FREE {
+            return Ok(Record::Free);
+        }
+        if len as usize > MAX_RECORD_LEN || position.offset + record_len(len) > SECTOR_SIZE {
+            return Ok(Record::Corrupted);
+        }
+
+        let mut data = vec![0u8; len as usize];
+        self.flash
+            .read(self.offset(position) + RECORD_HEADER_LEN, &mut data)?;
+        if checksum(&data) != header[3] || !matches!(header[2], LIVE | CONSUMED) {
+            return Ok(Record::Corrupted);
+        }
+        Ok(Record::Valid {
+            len,
+            state: header[2],
+            data,
+        })
+    }
+
+    fn offset(&self, position: Position) -> u32 {
+        position.sector * SECTOR_SIZE + position.offset
+    }
+}
+
+/// Record read from the flash.
+enum Record {
+    Valid { len: u16, state: u8, data: Vec<u8> },
+    Free,
+    Corrupted,
+}
+
+/// Sequence number of the sector, `None` if it is not formatted.
+fn read_sector_seq<F: Flash>(flash: &mut F, sector: u32) -> Result<Option<u32>, F::Error> {
+    let mut header = [0u8; SECTOR_HEADER_LEN as usize];
+    flash.read(sector * SECTOR_SIZE, &mut header)?;
+    let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let seq = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    Ok((magic == SECTOR_MAGIC).then_some(seq))
+}
+
+/// Length of the record in the flash, aligned to 4 bytes.
+fn record_len(len: u16) -> u32 {
+    (RECORD_HEADER_LEN + len as u32 + 3) & !3
+}
+
+/// CRC-8 (polynomial 0x07) of the data.
+fn checksum(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Flash in memory, counting the erases of each sector.
+    struct RamFlash {
+        data: Vec<u8>,
+        erases: Vec<u32>,
+    }
+
+    impl RamFlash {
+        fn new(num_sectors: u32) -> Self {
+            Self {
+                data: vec![0xFF; (num_sectors * SECTOR_SIZE) as usize],
+                erases: vec![0; num_sectors as usize],
+            }
+        }
+    }
+
+    impl Flash for RamFlash {
+        type Error = ();
+
+        fn size(&self) -> u32 {
+            self.data.len() as u32
+        }
+
+        fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), ()> {
+            let offset = offset as usize;
+            buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), ()> {
+            // Writes only clear bits
+            for (byte, value) in self.data[offset as usize..].iter_mut().zip(data) {
+                *byte &= value;
+            }
+            Ok(())
+        }
+
+        fn erase_sector(&mut self, offset: u32) -> Result<(), ()> {
+            assert_eq!(offset % SECTOR_SIZE, 0);
+            self.erases[(offset / SECTOR_SIZE) as usize] += 1;
+            self.data[offset as usize..(offset + SECTOR_SIZE) as usize].fill(0xFF);
+            Ok(())
+        }
+    }
+
+    /// Record `i`, of varying length.
+    fn record(i: u32) -> Vec<u8> {
+        let mut data = i.to_le_bytes().to_vec();
+        data.resize(20 + (i % 7) as usize, i as u8);
+        data
+    }
+
+    fn records(range: std::ops::Range<u32>) -> Vec<Vec<u8>> {
+        range.map(record).collect()
+    }
+
+    /// Offset of the record `i` in a log holding the records from 0, all in
+    /// the first sector.
+    fn record_offset(i: u32) -> usize {
+        (SECTOR_HEADER_LEN
+            + (0..i)
+                .map(|i| record_len(record(i).len() as u16))
+                .sum::<u32>()) as usize
+    }
+
+    #[test]
+    fn remounts_after_a_restart() {
+        let mut log = RingLog::new(RamFlash::new(4)).unwrap();
+        assert!(log.is_empty());
+        for i in 0..10 {
+            log.append(&record(i)).unwrap();
+        }
+        assert_eq!(log.read(4).unwrap(), records(0..4));
+        log.commit().unwrap();
+        // Not committed, read again after the restart
+        assert_eq!(log.read(3).unwrap(), records(4..7));
+
+        let mut log = RingLog::new(log.release()).unwrap();
+        assert_eq!(log.read(usize::MAX).unwrap(), records(4..10));
+        log.commit().unwrap();
+        assert!(log.is_empty());
+
+        let mut log = RingLog::new(log.release()).unwrap();
+        assert!(log.is_empty());
+        assert!(log.read(10).unwrap().is_empty());
+        log.append(&record(10)).unwrap();
+        assert_eq!(log.read(10).unwrap(), records(10..11));
+    }
+
+    #[test]
+    fn rollback_keeps_the_records() {
+        let mut log = RingLog::new(RamFlash::new(2)).unwrap();
+        for i in 0..5 {
+            log.append(&record(i)).unwrap();
+        }
+
+        assert_eq!(log.read(3).unwrap(), records(0..3));
+        log.rollback();
+        // Nothing in flight anymore
+        log.commit().unwrap();
+        assert_eq!(log.read(3).unwrap(), records(0..3));
+        log.commit().unwrap();
+        assert_eq!(log.read(3).unwrap(), records(3..5));
+        log.commit().unwrap();
+        assert!(log.is_empty());
+    }
+
+    #[test]
+    fn wrap_around_drops_the_oldest_sector() {
+        let mut log = RingLog::new(RamFlash::new(4)).unwrap();
+        let count = 2000;
+        for i in 0..count {
+            log.append(&record(i)).unwrap();
+        }
+        let dropped = log.dropped() as u32;
+        assert!(dropped > 0);
+
+        let flash = log.release();
+        // Every sector is erased the same number of times, give or take one
+        let max_erases = flash.erases.iter().max().unwrap();
+        let min_erases = flash.erases.iter().min().unwrap();
+        assert!(max_erases - min_erases <= 1, "{:?}", flash.erases);
+
+        let mut log = RingLog::new(flash).unwrap();
+        assert_eq!(log.read(usize::MAX).unwrap(), records(dropped..count));
+    }
+
+    #[test]
+    fn reader_keeps_up_across_wraps() {
+        let mut log = RingLog::new(RamFlash::new(3)).unwrap();
+        let mut next = 0;
+        for i in 0..5000 {
+            log.append(&record(i)).unwrap();
+            if i % 3 == 0 {
+                for data in log.read(5).unwrap() {
+                    assert_eq!(data, record(next));
+                    next += 1;
+                }
+                log.commit().unwrap();
+            }
+            if i % 997 == 0 {
+                log = RingLog::new(log.release()).unwrap();
+            }
+        }
+        for data in log.read(usize::MAX).unwrap() {
+            assert_eq!(data, record(next));
+            next += 1;
+        }
+        assert_eq!(next, 5000);
+        assert_eq!(log.dropped(), 0);
+    }
+
+    #[test]
+    fn torn_record_is_skipped() {
+        let mut log = RingLog::new(RamFlash::new(2)).unwrap();
+        for i in 0..3 {
+            log.append(&record(i)).unwrap();
+        }
+        let mut flash = log.release();
+        // Reset while writing the fourth record: header written, data not
+        let offset = record_offset(3);
+        flash.data[offset..offset + 5].copy_from_slice(&[20, 0, LIVE, 0x12, 0]);
+
+        let mut log = RingLog::new(flash).unwrap();
+        log.append(&record(3)).unwrap();
+        assert_eq!(log.read(10).unwrap(), records(0..4));
+    }
+
+    #[test]
+    fn corrupted_record_closes_its_sector() {
+        let mut log = RingLog::new(RamFlash::new(2)).unwrap();
+        for i in 0..4 {
+            log.append(&record(i)).unwrap();
+        }
+        let mut flash = log.release();
+        // A bit flipped in the data of the second record, the ones after it
+        // in the sector can not be found anymore
+        flash.data[record_offset(1) + RECORD_HEADER_LEN as usize] ^= 0x01;
+
+        let mut log = RingLog::new(flash).unwrap();
+        assert_eq!(log.read(10).unwrap(), records(0..1));
+        // The next record goes to the next sector
+        log.append(&record(4)).unwrap();
+        assert_eq!(log.read(10).unwrap(), vec![record(0), record(4)]);
+    }
+
+    #[test]
+    fn record_sizes() {
+        assert!(matches!(
+            RingLog::new(RamFlash::new(1)),
+            Err(RingLogError::TooSmall)
+        ));
+
+        let mut log = RingLog::new(RamFlash::new(2)).unwrap();
+        assert!(matches!(
+            log.append(&[0; MAX_RECORD_LEN + 1]),
+            Err(RingLogError::TooLarge)
+        ));
+        // A sector each
+        log.append(&[1; MAX_RECORD_LEN]).unwrap();
+        log.append(&[2; MAX_RECORD_LEN]).unwrap();
+        assert_eq!(
+            log.read(5).unwrap(),
+            [[1; MAX_RECORD_LEN], [2; MAX_RECORD_LEN]]
+        );
+        log.append(&[3; MAX_RECORD_LEN]).unwrap();
+        assert_eq!(log.dropped(), 1);
+    }
+
+    #[test]
+    fn backlog_follows_appends_and_commits() {
+        let mut log = RingLog::new(RamFlash::new(4)).unwrap();
+        assert_eq!(log.capacity(), 4 * SECTOR_SIZE as u64);
+        assert_eq!(log.backlog(), 0);
+        log.append(&[1; 12]).unwrap();
+        assert_eq!(log.backlog(), 16);
+        for i in 0..1000 {
+            log.append(&record(i)).unwrap();
+        }
+        assert!(log.backlog() > 2 * SECTOR_SIZE as u64);
+        assert!(log.backlog() <= log.capacity());
+
+        let mut log = RingLog::new(log.release()).unwrap();
+        let backlog = log.backlog();
+        log.read(10).unwrap();
+        log.commit().unwrap();
+        assert!(log.backlog() < backlog);
+        log.read(usize::MAX).unwrap();
+        log.commit().unwrap();
+        assert_eq!(log.backlog(), 0);
+    }
+}
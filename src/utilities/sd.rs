@@ -4,12 +4,15 @@ use embedded_sdmmc::{
     BlockSpi, Controller, Directory, Error, File, SdMmcError, SdMmcSpi, TimeSource, Timestamp,
     Volume,
 };
-use esp_idf_hal::sys::{suseconds_t, time_t, timeval};
-use log::info;
+use esp_idf_hal::sys::{suseconds_t, time_t, timeval, EspError};
+use log::{info, warn};
 use messages::Frame;
 use std::fmt::Debug;
 use std::time::SystemTime;
 
+use super::frame_store::{FrameStore, StoreUsage};
+use super::ring_log::RingLogError;
+
 const FAT_SECTOR_SIZE: usize = 512;
 /// File name for the data file
 const FILE_NAME: &str = "DATA.txt";
@@ -24,8 +27,24 @@ where
     volume: Volume,
     directory: Directory,
     file: Option<File>,
+    /// Frames not yet written to the file, a sector is written at a time
     in_buffer: Vec<u8>,
+    /// Beginning of a frame read from the file, completed by the next read
     out_buffer: Vec<u8>,
+    /// Position in the file of the data not yet delivered
+    cursor: u32,
+    /// Batch returned by the last read, waiting for the delivery
+    in_flight: Option<InFlight>,
+}
+
+/// Batch of frames read from the SD card and not yet delivered.
+struct InFlight {
+    /// Position in the file after the batch
+    end: u32,
+    /// Number of bytes of the write buffer in the batch
+    in_buffer_len: usize,
+    /// Bytes after the last complete frame of the batch
+    remainder: Vec<u8>,
 }
 
 impl<'a, DR, CS> SD<'a, DR, CS>
@@ -59,13 +78,18 @@ where
             file: Some(file),
             in_buffer: Vec::new(),
             out_buffer: Vec::new(),
+            cursor: 0,
+            in_flight: None,
         };
 
         Ok(sd)
     }
 
-    /// Write a frame to the current file
+    /// Write a frame to the current file.
+    /// The batch returned by the last read is rolled back, it will be read
+    /// again.
     pub fn write(&mut self, frame: &Frame) -> Result<(), SDError> {
+        self.in_flight = None;
         let mut frame_vec = frame.serialize();
         let frame_data: heapless::Vec<u8, FAT_SECTOR_SIZE>;
 
@@ -92,28 +116,72 @@ where
         Ok(())
     }
 
-    /// Read frames from sd card, will read a block of 512 bytes at a time
+    /// Read a batch of frames from the SD card, a block of 512 bytes at a time.
+    /// The batch is removed from the card only by [`SD::commit`], once it has
+    /// been delivered, otherwise the next read returns it again.
     pub fn read(&mut self) -> Result<Vec<Frame>, SDError> {
-        if self.file.as_ref().unwrap().eof() {
-            self.file.as_mut().unwrap().seek_from_start(0)?;
-        }
-
-        let mut vec = Vec::new();
-        let length = self.file.as_ref().unwrap().length();
-        if length != 0 {
+        self.in_flight = None;
+
+        let file = self.file.as_mut().unwrap();
+        let length = file.length();
+        let mut vec = self.out_buffer.clone();
+        let mut end = self.cursor;
+        if self.cursor < length {
+            file.seek_from_start(self.cursor)?;
             let mut buffer = [0u8; FAT_SECTOR_SIZE];
-            // read the first block of the file
-            let bytes_read =
-                self.controller
-                    .read(&self.volume, self.file.as_mut().unwrap(), &mut buffer)?;
+            let bytes_read = self.controller.read(&self.volume, file, &mut buffer)?;
 
             vec.extend_from_slice(&buffer[..bytes_read]);
-            vec.append(&mut self.out_buffer);
+            end += bytes_read as u32;
+        }
+
+        // Once the whole file has been read, add the frames not yet written
+        let mut in_buffer_len = 0;
+        if end >= length {
+            vec.extend_from_slice(&self.in_buffer);
+            in_buffer_len = self.in_buffer.len();
         }
 
-        // If eof all data has been read, so we can delete the file and create a new one
-        if self.file.as_ref().unwrap().eof() && self.file.as_ref().unwrap().length() != 0 {
-            info!("All data in the SD card has been read");
+        // Deserialize the frames
+        let frames = match Frame::deserialize_many(&mut vec) {
+            Ok(frames) => frames,
+            Err(e) => {
+                // Skip the corrupted data, or it would be read again forever
+                warn!("Skipping corrupted data in the SD card: {:?}", e);
+                self.in_flight = Some(InFlight {
+                    end,
+                    in_buffer_len,
+                    remainder: Vec::new(),
+                });
+                self.commit()?;
+                return Err(e.into());
+            }
+        };
+
+        self.in_flight = Some(InFlight {
+            end,
+            in_buffer_len,
+            remainder: vec,
+        });
+
+        Ok(frames)
+    }
+
+    /// Remove the batch returned by the last read from the card, once it has
+    /// been delivered.
+    pub fn commit(&mut self) -> Result<(), SDError> {
+        let Some(in_flight) = self.in_flight.take() else {
+            return Ok(());
+        };
+        self.cursor = in_flight.end;
+        self.in_buffer.drain(..in_flight.in_buffer_len);
+        // save the remaining bytes
+        self.out_buffer = in_flight.remainder;
+
+        // If all data has been delivered, we can delete the file and create a new one
+        let length = self.file.as_ref().unwrap().length();
+        if self.cursor >= length && length != 0 {
+            info!("All data in the SD card has been delivered");
             self.controller
                 .close_file(&self.volume, self.file.take().unwrap())?;
             self.controller
@@ -124,23 +192,114 @@ where
                 FILE_NAME,
                 embedded_sdmmc::Mode::ReadWriteCreateOrAppend,
             )?);
+            self.cursor = 0;
         }
 
+        Ok(())
+    }
+
+    /// Keep the batch returned by the last read in the card, it will be read
+    /// again.
+    pub fn rollback(&mut self) {
+        self.in_flight = None;
+    }
+
+    /// Capacity of the card, size of the data file and of the part not yet
+    /// delivered. The card is dedicated to the hub, only the data file is
+    /// counted as used.
+    pub fn usage(&mut self) -> Result<StoreUsage, SDError> {
+        let capacity = self.controller.device().card_size_bytes()?;
+        let length = self.file.as_ref().unwrap().length();
+        let buffered = (self.in_buffer.len() + self.out_buffer.len()) as u64;
+
+        Ok(StoreUsage {
+            capacity,
+            used: length as u64 + self.in_buffer.len() as u64,
+            backlog: length.saturating_sub(self.cursor) as u64 + buffered,
+        })
+    }
+
+    /// Check if the card still answers, to detect its removal without a
+    /// card-detect switch.
+    pub fn is_present(&mut self) -> bool {
+        self.controller.device().card_size_bytes().is_ok()
+    }
+
+    /// Write the frames buffered in memory to the card, and the length of the
+    /// file to its directory entry.
+    pub fn flush(&mut self) -> Result<(), SDError> {
+        self.in_flight = None;
         if !self.in_buffer.is_empty() {
-            //println!("Read {} bytes from in_buffer", self.in_buffer.len());
-            vec.append(&mut self.in_buffer);
+            let file = self.file.as_mut().unwrap();
+            if !file.eof() {
+                file.seek_from_end(0)?;
+            }
+            self.controller
+                .write(&mut self.volume, file, &self.in_buffer)?;
+            self.in_buffer.clear();
         }
 
-        // Deserialize the frames
-        //println!("Deserializing from {:?}", vec);
-        let frames = Frame::deserialize_many(&mut vec)?;
-        //println!("Remaining buffer is {:?}", vec);
-        //println!("Deserialized {:?}", frames);
+        // The directory entry is updated when the file is closed
+        self.controller
+            .close_file(&self.volume, self.file.take().unwrap())?;
+        self.file = Some(self.controller.open_file_in_dir(
+            &mut self.volume,
+            &self.directory,
+            FILE_NAME,
+            embedded_sdmmc::Mode::ReadWriteCreateOrAppend,
+        )?);
 
-        // save the remaining bytes
-        self.out_buffer = vec;
+        Ok(())
+    }
 
-        Ok(frames)
+    /// Flush the card before it is removed. Returns the frames buffered in
+    /// memory if they could not be written.
+    pub fn unmount(mut self) -> Vec<Frame> {
+        match self.flush() {
+            Ok(()) => {
+                info!("SD card unmounted");
+                Vec::new()
+            }
+            Err(e) => {
+                warn!("Failed to flush the SD card: {:?}", e);
+                Frame::deserialize_many(&mut self.in_buffer).unwrap_or_default()
+            }
+        }
+    }
+}
+
+impl<DR, CS> FrameStore for SD<'_, DR, CS>
+where
+    CS: embedded_hal_0_2::digital::v2::OutputPin,
+    DR: embedded_hal_0_2::blocking::spi::Transfer<u8>,
+    DR::Error: Debug,
+{
+    fn write(&mut self, frame: &Frame) -> Result<(), SDError> {
+        SD::write(self, frame)
+    }
+
+    fn read(&mut self) -> Result<Vec<Frame>, SDError> {
+        SD::read(self)
+    }
+
+    fn commit(&mut self) -> Result<(), SDError> {
+        SD::commit(self)
+    }
+
+    fn rollback(&mut self) {
+        SD::rollback(self)
+    }
+
+    fn usage(&mut self) -> Result<StoreUsage, SDError> {
+        SD::usage(self)
+    }
+
+    fn is_present(&mut self) -> bool {
+        SD::is_present(self)
+    }
+
+    fn unmount(self: Box<Self>) -> Vec<Frame> {
+        SD::unmount(*self)
     }
 }
 
@@ -167,6 +326,11 @@ impl CurrentTime {
             },
             std::ptr::null(),
         );
+        self.mark_set();
+    }
+
+    /// Mark the clock as set by another source, such as SNTP.
+    pub fn mark_set(&self) {
         IS_TIME_SET.store(true, std::sync::atomic::Ordering::Relaxed)
     }
 
@@ -212,6 +376,8 @@ pub enum SDError {
     Error2(SdMmcError),
     MessageError(messages::errors::Error),
     FileError(FileError),
+    /// Error of the internal flash storage
+    Flash(RingLogError<EspError>),
     Other(String),
 }
 
@@ -233,6 +399,12 @@ impl From<messages::errors::Error> for SDError {
     }
 }
 
+impl From<RingLogError<EspError>> for SDError {
+    fn from(error: RingLogError<EspError>) -> Self {
+        SDError::Flash(error)
+    }
+}
+
 impl From<FileError> for SDError {
     fn from(error: FileError) -> Self {
         SDError::FileError(error)
@@ -246,6 +418,7 @@ impl Debug for SDError {
             SDError::Error2(error) => write!(f, "SDError::Error2({:?})", error),
             SDError::MessageError(error) => write!(f, "SDError::MessageError({:?})", error),
             SDError::FileError(error) => write!(f, "SDError::FileError({:?})", error),
+            SDError::Flash(error) => write!(f, "SDError::Flash({:?})", error),
             SDError::Other(error) => write!(f, "SDError::Other({:?})", error),
         }
     }
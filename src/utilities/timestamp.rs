@@ -0,0 +1,157 @@
+//! Timestamps of the frames recorded before the clock is set.
+//!
+//! Until SNTP or another source sets the clock, the clock counts from 1970 at
+//! the boot. The frames are then stamped with the ID of the boot and the time
+//! since the boot, flagged by the highest bit:
+//!
+//! ```text
+//! | 1 | boot ID: 23 bits | milliseconds since the boot: 40 bits |
+//! ```
+//!
+//! Once the clock is set, the wall-clock time of the boot is known and the
+//! frames of the same boot are rewritten to the wall-clock time. The frames of
+//! a previous boot can not be rewritten, the clock was never set during it.
+//!
+//! The conversions between the days since the epoch and the civil dates are
+//! used by the sources of the time (HTTP dates and RTC).
+
+/// Number of bits of the boot ID, the boot IDs wrap around.
+pub const BOOT_ID_BITS: u32 = 23;
+const UPTIME_BITS: u32 = 40;
+/// Flag of the timestamps relative to a boot.
+const RELATIVE_FLAG: u64 = 1 << 63;
+const UPTIME_MASK: u64 = (1 << UPTIME_BITS) - 1;
+const BOOT_ID_MASK: u64 = (1 << BOOT_ID_BITS) - 1;
+
+/// Timestamp relative to a boot.
+pub fn relative(boot_id: u32, uptime_ms: u64) -> u64 {
+    RELATIVE_FLAG | (boot_id as u64 & BOOT_ID_MASK) << UPTIME_BITS | uptime_ms & UPTIME_MASK
+}
+
+/// Check if the timestamp is relative to a boot.
+pub fn is_relative(timestamp: u64) -> bool {
+    timestamp & RELATIVE_FLAG != 0
+}
+
+/// Wall-clock time of a timestamp, given the ID of the current boot and the
+/// wall-clock time of that boot. `None` if the timestamp is relative to
+/// another boot.
+pub fn to_wall_clock(timestamp: u64, boot_id: u32, boot_time_ms: u64) -> Option<u64> {
+    if !is_relative(timestamp) {
+        return Some(timestamp);
+    }
+    let timestamp_boot_id = (timestamp >> UPTIME_BITS) & BOOT_ID_MASK;
+    if timestamp_boot_id != boot_id as u64 & BOOT_ID_MASK {
+        return None;
+    }
+    Some(boot_time_ms + (timestamp & UPTIME_MASK))
+}
+
+/// Days since the UNIX epoch of a civil date, from 1970.
+pub fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    // The years start in March, so the leap day is the last one
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Civil date (year, month, day) of the days since the UNIX epoch.
+pub fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let (year, month) = if month < 10 {
+        (era * 400 + year_of_era, month + 3)
+    } else {
+        (era * 400 + year_of_era + 1, month - 9)
+    };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2023-11-14 22:13:20 UTC
+    const BOOT_TIME: u64 = 1_700_000_000_000;
+
+    #[test]
+    fn relative_timestamps() {
+        let timestamp = relative(42, 1234);
+        assert!(is_relative(timestamp));
+        assert_eq!(timestamp >> UPTIME_BITS, 1 << BOOT_ID_BITS | 42);
+        assert_eq!(timestamp & UPTIME_MASK, 1234);
+
+        // The wall-clock times and the clock counting from 1970 at the boot
+        assert!(!is_relative(BOOT_TIME));
+        assert!(!is_relative(5_000));
+    }
+
+    #[test]
+    fn rewritten_for_the_same_boot() {
+        let timestamp = relative(42, 1234);
+        assert_eq!(
+            to_wall_clock(timestamp, 42, BOOT_TIME),
+            Some(BOOT_TIME + 1234)
+        );
+        // The wall-clock times are kept
+        assert_eq!(to_wall_clock(BOOT_TIME, 42, 0), Some(BOOT_TIME));
+    }
+
+    #[test]
+    fn not_rewritten_for_another_boot() {
+        let timestamp = relative(42, 1234);
+        assert_eq!(to_wall_clock(timestamp, 41, BOOT_TIME), None);
+        assert_eq!(to_wall_clock(timestamp, 43, BOOT_TIME), None);
+    }
+
+    #[test]
+    fn boot_ids_wrap_around() {
+        let wrapped = (1 << BOOT_ID_BITS) + 5;
+        assert_eq!(relative(wrapped, 10), relative(5, 10));
+        assert_eq!(
+            to_wall_clock(relative(5, 10), wrapped, BOOT_TIME),
+            Some(BOOT_TIME + 10)
+        );
+        assert_eq!(to_wall_clock(relative(wrapped, 10), 6, BOOT_TIME), None);
+
+        let last = (1 << BOOT_ID_BITS) - 1;
+        assert_eq!(
+            to_wall_clock(relative(last, 10), last, BOOT_TIME),
+            Some(BOOT_TIME + 10)
+        );
+        assert_eq!(to_wall_clock(relative(last, 10), 0, BOOT_TIME), None);
+    }
+
+    #[test]
+    fn uptime_does_not_overflow_into_the_boot_id() {
+        // 40 bits of milliseconds are about 34 years
+        let timestamp = relative(7, UPTIME_MASK + 1 + 99);
+        assert_eq!(to_wall_clock(timestamp, 7, BOOT_TIME), Some(BOOT_TIME + 99));
+    }
+
+    #[test]
+    fn civil_dates_round_trip() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(days_from_civil(2000, 2, 29), 11_016);
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(11_017), (2000, 3, 1));
+        for days in (0..200_000).step_by(7) {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+}